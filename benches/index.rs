@@ -0,0 +1,51 @@
+//! Measures how long replaying the log and looking keys up takes with many short keys.
+//!
+//! Compare the default index hasher against the `fxhash` feature with:
+//!
+//! ```text
+//! cargo bench --bench index
+//! cargo bench --bench index --features fxhash
+//! ```
+
+use std::time::{Duration, Instant};
+
+use kvs::{KvStore, Result};
+use tempfile::TempDir;
+
+const KEYS: usize = 200_000;
+const ROUNDS: u32 = 5;
+
+fn main() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let keys: Vec<String> = (0..KEYS).map(|key_id| format!("k{}", key_id)).collect();
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key in &keys {
+        store.set(key.as_str(), "v")?;
+    }
+    drop(store);
+
+    let (mut open, mut get) = (Duration::ZERO, Duration::ZERO);
+    for _ in 0..ROUNDS {
+        let start = Instant::now();
+        let store = KvStore::open(temp_dir.path())?;
+        open += start.elapsed();
+
+        let start = Instant::now();
+        for key in &keys {
+            assert!(store.contains_key(key.as_str()));
+        }
+        get += start.elapsed();
+    }
+
+    let hasher = if cfg!(feature = "fxhash") {
+        "fxhash"
+    } else {
+        "siphash"
+    };
+    println!("hasher: {}", hasher);
+    println!("open:   {:?} per replay of {} keys", open / ROUNDS, KEYS);
+    println!("lookup: {:?} per {} lookups", get / ROUNDS, KEYS);
+
+    Ok(())
+}
@@ -46,6 +46,38 @@ pub fn get_cli_args<'src>() -> ArgMatches<'src> {
                 ),
         )
         .subcommand(SubCommand::with_name("clean").about("Removes all stale data kept in log"))
+        .subcommand(
+            SubCommand::with_name("stats")
+                .about("Prints the state of the store")
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .takes_value(true)
+                        .value_name("FORMAT")
+                        .possible_values(&["text", "json"])
+                        .default_value("text"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("repair")
+                .about("Truncates unreadable records and drops keys that can't be read back")
+                .arg(
+                    Arg::with_name("compact")
+                        .long("compact")
+                        .help("Removes stale data from the log once repaired"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("dump")
+                .about("Prints the records stored in the log as JSON lines")
+                .arg(
+                    Arg::with_name("generation")
+                        .long("generation")
+                        .takes_value(true)
+                        .value_name("N")
+                        .help("Only prints the records of generation <N>"),
+                ),
+        )
         .get_matches();
 
     matches
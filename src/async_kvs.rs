@@ -0,0 +1,103 @@
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use tokio::task;
+
+use crate::{KvStore, Result};
+
+/// An `async` handle to a [`KvStore`] for use within a tokio runtime.
+///
+/// Every operation runs the blocking [`KvStore`] call on tokio's blocking thread pool,
+/// so disk I/O never stalls the reactor. The store itself sits behind a single mutex:
+/// concurrent operations on clones of the same handle are serialized, one at a time.
+///
+/// # Examples
+///
+/// ```rust no_run
+/// # use kvs::{AsyncKvStore, Result};
+/// # async fn run() -> Result<()> {
+/// let store = AsyncKvStore::open("./data").await?;
+///
+/// store.set("key", "value").await?;
+/// assert_eq!(store.get("key").await?, Some("value".to_string()));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct AsyncKvStore {
+    store: Arc<Mutex<KvStore>>,
+}
+
+impl AsyncKvStore {
+    /// Wraps an already opened [`KvStore`].
+    pub fn new(store: KvStore) -> Self {
+        Self {
+            store: Arc::new(Mutex::new(store)),
+        }
+    }
+
+    /// Opens a [`KvStore`] within provided `path`.
+    ///
+    /// # Errors
+    ///
+    /// See [`KvStore::open`].
+    pub async fn open<P: Into<PathBuf>>(path: P) -> Result<Self> {
+        let path = path.into();
+        let store = task::spawn_blocking(move || KvStore::open(path))
+            .await
+            .map_err(io::Error::other)??;
+
+        Ok(Self::new(store))
+    }
+
+    /// Sets the given `key` to provided `value`.
+    ///
+    /// # Errors
+    ///
+    /// See [`KvStore::set`].
+    pub async fn set<K, V>(&self, key: K, value: V) -> Result<()>
+    where
+        K: Into<String>,
+        V: Into<String>,
+    {
+        let (key, value) = (key.into(), value.into());
+        self.run(move |store| store.set(key, value)).await
+    }
+
+    /// Fetches the stored `value` of a given `key`.
+    ///
+    /// # Errors
+    ///
+    /// See [`KvStore::get`].
+    pub async fn get<K: Into<String>>(&self, key: K) -> Result<Option<String>> {
+        let key = key.into();
+        self.run(move |store| store.get(key)).await
+    }
+
+    /// Removes a given key returning `true` if the key was saved, `false` otherwise.
+    ///
+    /// # Errors
+    ///
+    /// See [`KvStore::remove`].
+    pub async fn remove<K: Into<String>>(&self, key: K) -> Result<bool> {
+        let key = key.into();
+        self.run(move |store| store.remove(key)).await
+    }
+
+    /// Runs `op` on the locked store using tokio's blocking thread pool.
+    async fn run<T, F>(&self, op: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut KvStore) -> Result<T> + Send + 'static,
+    {
+        let store = Arc::clone(&self.store);
+
+        task::spawn_blocking(move || {
+            let mut store = store.lock().unwrap_or_else(PoisonError::into_inner);
+            op(&mut store)
+        })
+        .await
+        .map_err(io::Error::other)?
+    }
+}
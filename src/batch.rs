@@ -0,0 +1,62 @@
+/// A group of writes applied to a [`KvStore`](crate::KvStore) together.
+///
+/// See [`KvStore::write_batch`](crate::KvStore::write_batch).
+///
+/// # Examples
+///
+/// ```rust no_run
+/// # use kvs::{Result, KvStore, WriteBatch};
+/// # fn main() -> Result<()> {
+/// use std::env::current_dir;
+/// let mut store = KvStore::open(current_dir()?)?;
+///
+/// let mut batch = WriteBatch::new();
+/// batch.set("key1", "value1").remove("key2");
+/// store.write_batch(batch)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct WriteBatch {
+    pub(crate) ops: Vec<BatchOp>,
+}
+
+/// A single write of a [`WriteBatch`].
+#[derive(Debug, Clone)]
+pub(crate) enum BatchOp {
+    Set(String, String),
+    Remove(String),
+}
+
+impl WriteBatch {
+    /// Creates an empty batch.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds setting the given `key` to provided `value` to the batch.
+    pub fn set<K, V>(&mut self, key: K, value: V) -> &mut Self
+    where
+        K: Into<String>,
+        V: Into<String>,
+    {
+        self.ops.push(BatchOp::Set(key.into(), value.into()));
+        self
+    }
+
+    /// Adds removing a given `key` to the batch.
+    pub fn remove<K: Into<String>>(&mut self, key: K) -> &mut Self {
+        self.ops.push(BatchOp::Remove(key.into()));
+        self
+    }
+
+    /// Returns the number of writes in the batch.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Checks whether the batch has no writes.
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
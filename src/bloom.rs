@@ -0,0 +1,67 @@
+use serde::{Deserialize, Serialize};
+
+/// A Bloom filter over the live keys of a [`KvStore`](crate::KvStore).
+///
+/// It answers whether a key may be present with no false negatives, but with false
+/// positives at about the rate it was sized for. Keys are hashed independently of the
+/// process, so a serialized filter can be shipped elsewhere, such as to a remote peer
+/// that answers lookups for definitely absent keys without asking the store.
+///
+/// See [`KvStoreBuilder::bloom_filter`](crate::KvStoreBuilder::bloom_filter).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    hashes: u32,
+}
+
+impl BloomFilter {
+    /// Creates an empty filter sized for `keys` keys at the given false positive rate.
+    pub(crate) fn new(keys: usize, false_positive_rate: f64) -> Self {
+        let keys = keys.max(1) as f64;
+        let rate = false_positive_rate.clamp(f64::MIN_POSITIVE, 0.5);
+        let ln2 = std::f64::consts::LN_2;
+
+        let bits = (-keys * rate.ln() / (ln2 * ln2)).ceil().max(64.0);
+        let hashes = (bits / keys * ln2).round().max(1.0);
+
+        Self {
+            bits: vec![0; (bits as usize).div_ceil(64)],
+            hashes: hashes as u32,
+        }
+    }
+
+    /// Adds a key to the filter.
+    pub(crate) fn insert(&mut self, key: &str) {
+        for bit in self.bit_positions(key) {
+            self.bits[bit / 64] |= 1 << (bit % 64);
+        }
+    }
+
+    /// Checks whether `key` may be in the filter, returning `false` only if it
+    /// definitely isn't.
+    pub fn may_contain(&self, key: &str) -> bool {
+        // Only a filter deserialized from malformed input can be empty.
+        if self.bits.is_empty() {
+            return true;
+        }
+
+        self.bit_positions(key)
+            .all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    /// Derives the positions of a key's bits from two FNV-1a hashes of it.
+    fn bit_positions(&self, key: &str) -> impl Iterator<Item = usize> {
+        let len = self.bits.len() as u64 * 64;
+        let h1 = fnv1a(key.as_bytes(), 0xcbf2_9ce4_8422_2325);
+        let h2 = fnv1a(key.as_bytes(), 0x8422_2325_cbf2_9ce4) | 1;
+
+        (0..u64::from(self.hashes))
+            .map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % len) as usize)
+    }
+}
+
+fn fnv1a(bytes: &[u8], basis: u64) -> u64 {
+    bytes.iter().fold(basis, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
@@ -0,0 +1,398 @@
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Sender;
+use std::time::Duration;
+
+use crate::{
+    slow_op::SlowOpHook,
+    utils::{has_marker, LogNaming},
+    Commit, KvStore, KvsError, OpKind, RepairReport, Result, StoreDir,
+};
+
+/// Determines how thoroughly [`KvStore`] checks the log when opening.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OpenMode {
+    /// Only replays the log to rebuild the index.
+    ///
+    /// A corrupted value is detected when it is first read.
+    #[default]
+    Fast,
+    /// Replays the log and then reads back every live value, failing on the first
+    /// corrupted one. See [`KvStore::verify`].
+    Verified,
+}
+
+/// Determines when [`KvStore`] syncs writes to disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FlushPolicy {
+    /// Writes are buffered and only reach the disk on [`KvStore::flush`],
+    /// [`KvStore::sync`] or when the store is closed.
+    #[default]
+    Manual,
+    /// Every write is flushed and synced to disk before it returns.
+    Sync,
+}
+
+/// Determines when [`KvStore`] compacts its log on its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CompactionMode {
+    /// Compacts once stale records take up more than 1 MiB.
+    #[default]
+    Fixed,
+    /// Compacts once stale records take up more than half as much space as the live
+    /// records did after the last compaction, and at least 64 KiB.
+    ///
+    /// This bounds how often every live record gets rewritten, whatever the size of
+    /// the store, while still compacting small stores often.
+    Adaptive,
+    /// Never compacts on its own, keeping every record ever written in the log, such
+    /// as for an audit trail.
+    ///
+    /// Reads still return the latest values, but stale records are only removed by an
+    /// explicit [`KvStore::clean_stale_data`] or [`Compactor`](crate::Compactor).
+    Disabled,
+}
+
+/// Determines what [`KvStore::set`] does when a write would exceed
+/// [`KvStoreBuilder::max_live_bytes`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EvictionPolicy {
+    /// Rejects the write with [`KvsError::QuotaExceeded`].
+    #[default]
+    Reject,
+    /// Removes the least recently used keys until the write fits, like a bounded cache.
+    ///
+    /// Keys count as used when they are set or read by key, through [`KvStore::get`] and
+    /// the other point reads such as [`KvStore::get_into`], which makes reads update the
+    /// order of keys as well. Scans, such as [`KvStore::for_each`], leave it unchanged.
+    /// When opening, keys are ordered by when they were last written. A write larger
+    /// than the limit on its own is still rejected.
+    LruEvict,
+}
+
+/// Options a [`KvStore`] is opened with.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Config {
+    pub(crate) naming: LogNaming,
+    pub(crate) open_mode: OpenMode,
+    pub(crate) flush_policy: FlushPolicy,
+    pub(crate) max_unflushed_writes: Option<usize>,
+    pub(crate) compaction_mode: CompactionMode,
+    pub(crate) retain_generations: usize,
+    pub(crate) max_live_bytes: Option<u64>,
+    pub(crate) eviction: EvictionPolicy,
+    pub(crate) max_open_readers: Option<usize>,
+    pub(crate) dedup_identical: bool,
+    pub(crate) compact_in_key_order: bool,
+    pub(crate) version_history: usize,
+    /// Number of keys and false positive rate the Bloom filter is sized for.
+    pub(crate) bloom_filter: Option<(usize, f64)>,
+    pub(crate) scratch_dir: Option<PathBuf>,
+    pub(crate) on_commit: Option<Sender<Commit>>,
+    pub(crate) slow_op_threshold: Option<Duration>,
+    pub(crate) on_slow_op: Option<SlowOpHook>,
+    #[cfg(feature = "compression")]
+    pub(crate) compression_threshold: Option<usize>,
+}
+
+/// A builder used to open a [`KvStore`] with non-default options.
+///
+/// # Examples
+///
+/// ```rust no_run
+/// # use kvs::{Result, KvStore};
+/// # fn main() -> Result<()> {
+/// use std::env::current_dir;
+/// // Stores logfiles as `kvs-1.data`, `kvs-2.data`, ...
+/// let mut store = KvStore::builder()
+///     .prefix("kvs-")
+///     .extension("data")
+///     .open(current_dir()?)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct KvStoreBuilder {
+    config: Config,
+}
+
+impl KvStoreBuilder {
+    /// Creates a builder with default options.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the prefix logfile names start with, before the generation number.
+    ///
+    /// Defaults to no prefix.
+    pub fn prefix<S: Into<String>>(mut self, prefix: S) -> Self {
+        self.config.naming.prefix = prefix.into();
+        self
+    }
+
+    /// Sets the extension of logfiles, with or without the leading dot.
+    ///
+    /// Defaults to `log`. An empty extension names logfiles after their generation only.
+    pub fn extension<S: Into<String>>(mut self, extension: S) -> Self {
+        self.config.naming.extension = extension.into().trim_start_matches('.').to_owned();
+        self
+    }
+
+    /// Sets how thoroughly the log is checked when opening.
+    ///
+    /// Defaults to [`OpenMode::Fast`].
+    pub fn open_mode(mut self, mode: OpenMode) -> Self {
+        self.config.open_mode = mode;
+        self
+    }
+
+    /// Sets when writes are synced to disk.
+    ///
+    /// Defaults to [`FlushPolicy::Manual`].
+    pub fn flush_policy(mut self, policy: FlushPolicy) -> Self {
+        self.config.flush_policy = policy;
+        self
+    }
+
+    /// Flushes the log on its own once `writes` writes were buffered since it was last
+    /// flushed, bounding how many of them a crash of the process can lose.
+    ///
+    /// Flushed writes reach the operating system, but are only synced to disk as the
+    /// [`FlushPolicy`] determines. The writes of a [`WriteBatch`](crate::WriteBatch) count
+    /// individually, but are only flushed once the whole batch was written.
+    ///
+    /// By default, writes are only flushed once the write buffer fills up.
+    pub fn max_unflushed_writes(mut self, writes: usize) -> Self {
+        self.config.max_unflushed_writes = Some(writes.max(1));
+        self
+    }
+
+    /// Sets when the log is compacted on its own.
+    ///
+    /// Defaults to [`CompactionMode::Fixed`].
+    pub fn compaction_mode(mut self, mode: CompactionMode) -> Self {
+        self.config.compaction_mode = mode;
+        self
+    }
+
+    /// Keeps the `count` most recent generations replaced by compaction on disk,
+    /// such as for comparing them with the compacted log afterwards.
+    ///
+    /// Retained generations are no longer part of the store: they are neither read
+    /// nor listed by [`KvStore::generations`], and are removed once newer ones are
+    /// retained in their place. Defaults to `0`, removing every replaced generation.
+    pub fn retain_generations(mut self, count: usize) -> Self {
+        self.config.retain_generations = count;
+        self
+    }
+
+    /// Limits the bytes taken up by live records, so that the data held by the store
+    /// stays bounded.
+    ///
+    /// A set that would push the live records over `limit` is handled according to
+    /// the [`eviction`](Self::eviction) policy, which by default rejects it with
+    /// [`KvsError::QuotaExceeded`] without writing anything. Removals always succeed
+    /// and make room again. Records count with their headers, and stale records don't
+    /// count at all, so the log itself may grow larger until compacted. By default,
+    /// there is no limit.
+    pub fn max_live_bytes(mut self, limit: u64) -> Self {
+        self.config.max_live_bytes = Some(limit);
+        self
+    }
+
+    /// Sets what a set that would exceed [`max_live_bytes`](Self::max_live_bytes) does.
+    ///
+    /// Defaults to [`EvictionPolicy::Reject`].
+    pub fn eviction(mut self, policy: EvictionPolicy) -> Self {
+        self.config.eviction = policy;
+        self
+    }
+
+    /// Sets how many logfiles are kept open for reading at once.
+    ///
+    /// Once that many are open, reading from another generation closes the least
+    /// recently read logfile, which is reopened when it is next read from. This bounds
+    /// the file handles used by a store with very many generations. Defaults to `1024`,
+    /// and a limit of `0` is treated as `1`.
+    pub fn max_open_readers(mut self, count: usize) -> Self {
+        self.config.max_open_readers = Some(count);
+        self
+    }
+
+    /// Makes [`KvStore::set`], [`KvStore::set_counted`] and [`KvStore::set_tracked`]
+    /// skip writing a value identical to the key's current one.
+    ///
+    /// This costs a read of the current value on every set, but avoids growing the log
+    /// for writers that repeatedly set the same value. Defaults to `false`.
+    pub fn dedup_identical(mut self, enabled: bool) -> Self {
+        self.config.dedup_identical = enabled;
+        self
+    }
+
+    /// Makes compaction write live records in the byte order of their keys, so that
+    /// reading keys in sorted order reads the compacted logfile front to back.
+    ///
+    /// The keys are sorted before copying starts, which costs `O(n log n)` time for `n`
+    /// live keys on top of the copying itself. Records written after compaction are
+    /// still appended in the order of the writes. Defaults to `false`, copying records
+    /// in no particular order.
+    pub fn compact_in_key_order(mut self, enabled: bool) -> Self {
+        self.config.compact_in_key_order = enabled;
+        self
+    }
+
+    /// Keeps up to `versions` of the latest values of every key, including the current
+    /// one, so that older ones can be read with [`KvStore::get_version`].
+    ///
+    /// Compaction then copies the older versions of live keys along with their latest
+    /// one, and only counts versions past the window as stale. Removing a key drops all
+    /// of its versions. Older versions are found by reading the whole log when opening.
+    /// [`KvStore::compact_into`] still only copies the latest versions. Defaults to `1`,
+    /// keeping only the latest version.
+    pub fn version_history(mut self, versions: usize) -> Self {
+        self.config.version_history = versions;
+        self
+    }
+
+    /// Maintains a [`BloomFilter`](crate::BloomFilter) over the live keys, sized for
+    /// `keys` keys at the given false positive rate, which [`KvStore::get`] consults
+    /// before the index.
+    ///
+    /// The filter is rebuilt when opening and compacting, and grows to fit the live
+    /// keys at that point. Removed keys stay in the filter until then, and so do keys
+    /// added beyond its size, at the cost of more false positives. By default, no filter
+    /// is maintained. See [`KvStore::bloom_filter`].
+    pub fn bloom_filter(mut self, keys: usize, false_positive_rate: f64) -> Self {
+        self.config.bloom_filter = Some((keys, false_positive_rate));
+        self
+    }
+
+    /// Sets the directory compaction writes its temporary logfile to, such as one on
+    /// faster storage than the store itself.
+    ///
+    /// The compacted logfile is moved into the store directory once complete. If the
+    /// directories are on different filesystems, it is copied next to its destination
+    /// first, so that it still replaces the stale generations atomically. Defaults to
+    /// the store directory.
+    pub fn scratch_dir<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.config.scratch_dir = Some(dir.into());
+        self
+    }
+
+    /// Sends every write committed to the log to `sender`, such as for shipping it to
+    /// a replica.
+    ///
+    /// A [`Commit`] is sent once a `set` or `remove` was written to the log and, under
+    /// [`FlushPolicy::Sync`], synced to disk. Writes keep succeeding after the receiver
+    /// is dropped. By default, commits aren't sent anywhere.
+    pub fn on_commit(mut self, sender: Sender<Commit>) -> Self {
+        self.config.on_commit = Some(sender);
+        self
+    }
+
+    /// Sets how long an operation must take to be reported to
+    /// [`on_slow_op`](Self::on_slow_op).
+    ///
+    /// Defaults to 100 milliseconds. A zero threshold reports every operation.
+    pub fn slow_op_threshold(mut self, threshold: Duration) -> Self {
+        self.config.slow_op_threshold = Some(threshold);
+        self
+    }
+
+    /// Calls `callback` with the kind and the duration of every operation that took at
+    /// least the [`slow_op_threshold`](Self::slow_op_threshold), such as for tracing
+    /// tail latency.
+    ///
+    /// Only the operations listed by [`OpKind`] are timed, from the moment they are
+    /// called until they return, whether they succeed or not. The callback runs on the
+    /// thread of the operation, before it returns. By default, operations aren't timed.
+    pub fn on_slow_op<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(OpKind, Duration) + Send + Sync + 'static,
+    {
+        self.config.on_slow_op = Some(SlowOpHook::new(callback));
+        self
+    }
+
+    /// Compresses values longer than `threshold` bytes when writing them to the log.
+    ///
+    /// Shorter values are stored uncompressed to avoid the overhead. By default, values are
+    /// never compressed. Logfiles written by a build with the `compression` feature use a
+    /// newer format version, which builds without the feature refuse to open.
+    #[cfg(feature = "compression")]
+    pub fn compress_values_above(mut self, threshold: usize) -> Self {
+        self.config.compression_threshold = Some(threshold);
+        self
+    }
+
+    /// Opens a [`KvStore`] within provided `path` using the configured options.
+    ///
+    /// # Errors
+    ///
+    /// See [`KvStore::open`].
+    pub fn open<P: AsRef<Path>>(self, path: P) -> Result<KvStore> {
+        KvStore::open_with(path, self.config)
+    }
+
+    /// Opens the [`KvStore`] within provided `path` using the configured options,
+    /// which must already hold one.
+    ///
+    /// # Errors
+    ///
+    /// See [`KvStore::open_existing`].
+    pub fn open_existing<P: AsRef<Path>>(self, path: P) -> Result<KvStore> {
+        if !has_marker(&StoreDir::new(&path), &self.config.naming) {
+            return Err(KvsError::StoreNotFound(path.as_ref().to_path_buf()));
+        }
+
+        KvStore::open_with(path, self.config)
+    }
+
+    /// Repairs and opens the [`KvStore`] within provided `path` using the configured
+    /// options, which must already hold one.
+    ///
+    /// # Errors
+    ///
+    /// See [`KvStore::repair`].
+    pub fn repair<P: AsRef<Path>>(self, path: P) -> Result<(KvStore, RepairReport)> {
+        if !has_marker(&StoreDir::new(&path), &self.config.naming) {
+            return Err(KvsError::StoreNotFound(path.as_ref().to_path_buf()));
+        }
+
+        KvStore::repair_with(path, self.config)
+    }
+
+    /// Opens a [`KvStore`] within the directory a pre-opened handle `dir` refers to using
+    /// the configured options, creating a store there if it holds none.
+    ///
+    /// Every file of the store is opened relative to the handle rather than by looking up
+    /// a path, so the store keeps using the same directory even if it is moved or its path
+    /// is replaced in the meantime. Only a [`scratch_dir`](Self::scratch_dir) is still
+    /// accessed by path. `path` is where `dir` was opened from, which the store reports as
+    /// its [`path`](KvStore::path) and in errors, but never accesses.
+    ///
+    /// Directory handles are only supported on Unix platforms, with the `dir-handle`
+    /// feature enabled.
+    ///
+    /// # Errors
+    ///
+    /// This function returns [`KvsError::NotADirectory`] if `dir` isn't a directory, and
+    /// an I/O error of kind [`io::ErrorKind::Unsupported`](std::io::ErrorKind::Unsupported)
+    /// if directory handles aren't supported. Otherwise, see [`KvStore::open`].
+    pub fn open_dir<P: AsRef<Path>>(self, dir: File, path: P) -> Result<KvStore> {
+        KvStore::open_dir_with(dir, path.as_ref(), self.config)
+    }
+
+    /// Opens a [`KvStore`] in a new temporary directory using the configured options.
+    ///
+    /// The store still writes to a log on disk like any other, but the directory and
+    /// everything in it is deleted once the store is dropped, without flushing it first.
+    ///
+    /// # Errors
+    ///
+    /// This function propagates any I/O error that could arise while creating the
+    /// temporary directory. Otherwise, see [`KvStore::open`].
+    pub fn ephemeral(self) -> Result<KvStore> {
+        KvStore::ephemeral_with(self.config)
+    }
+}
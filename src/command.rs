@@ -1,6 +1,9 @@
+use std::borrow::Cow;
 use std::ops::Range;
 
-use serde::{Deserialize, Serialize};
+use serde::{de::IgnoredAny, Deserialize, Serialize};
+
+use crate::{KvsError, Result};
 
 /// Represents [`KvStore`] commands that are persisted to disk.
 #[derive(Debug, Serialize, Deserialize)]
@@ -20,21 +23,58 @@ impl Command {
     }
 }
 
-/// An in-memory representation that stores the generation
+/// A `Command` as seen by log replay, which only needs to know the key of each command.
+///
+/// Values are skipped without being checked to be strings.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub(crate) enum ReplayCommand {
+    Set(String, IgnoredAny),
+    Remove(String),
+}
+
+/// A borrowed view of the value of a serialized `Command::Set`.
+///
+/// Deserializing this from a byte slice skips the key and only allocates
+/// when the value contains escape sequences.
+#[derive(Debug, Deserialize)]
+pub(crate) struct SetValue<'a>(IgnoredAny, #[serde(borrow)] pub(crate) Cow<'a, str>);
+
+impl<'a> SetValue<'a> {
+    /// Parses the value of a serialized `Command::Set`.
+    ///
+    /// A serialized `Command::Remove` fails with [`KvsError::UnexpectedCommand`].
+    pub(crate) fn parse(record: &'a [u8]) -> Result<Cow<'a, str>> {
+        match serde_json::from_slice::<Self>(record) {
+            Ok(set) => Ok(set.1),
+            Err(err) => match serde_json::from_slice::<Command>(record) {
+                Ok(command) => Err(KvsError::UnexpectedCommand {
+                    expected: "set",
+                    got: command.kind(),
+                }),
+                Err(_) => Err(err.into()),
+            },
+        }
+    }
+}
+
+/// An in-memory representation that stores the generation, sequence number
 /// and in-file position of a `Command`.
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub(crate) struct CommandPointer {
     gen: usize,
+    seq: u64,
     start: usize,
     length: usize,
 }
 
 impl CommandPointer {
-    pub(crate) fn new(gen: usize, range: Range<usize>) -> Self {
+    pub(crate) fn new(gen: usize, seq: u64, range: Range<usize>) -> Self {
         Self {
             start: range.start,
             length: range.len(),
             gen,
+            seq,
         }
     }
 
@@ -48,4 +88,8 @@ impl CommandPointer {
     pub(crate) fn gen(&self) -> usize {
         self.gen
     }
+
+    pub(crate) fn seq(&self) -> u64 {
+        self.seq
+    }
 }
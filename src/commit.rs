@@ -0,0 +1,25 @@
+/// A write to a [`KvStore`](crate::KvStore), as carried by a [`Commit`] and applied to
+/// another store by [`KvStore::apply_command`](crate::KvStore::apply_command).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mutation {
+    /// Sets a key to a value.
+    Set(String, String),
+    /// Removes a key.
+    Remove(String),
+}
+
+/// A write committed to the log of a [`KvStore`](crate::KvStore), as sent to the
+/// channel registered with [`KvStoreBuilder::on_commit`](crate::KvStoreBuilder::on_commit).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Commit {
+    /// The write that was committed.
+    pub mutation: Mutation,
+    /// Generation of the logfile the write was appended to.
+    pub generation: usize,
+    /// Byte offset of the write within its logfile.
+    ///
+    /// Compaction may move the write elsewhere later.
+    pub offset: usize,
+    /// Sequence number of the write.
+    pub seq: u64,
+}
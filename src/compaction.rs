@@ -0,0 +1,168 @@
+use std::fs::File;
+use std::io::BufWriter;
+
+use crate::{CommandPointer, Counted, KvStore, Result, StoreDir};
+
+/// An estimate of what compacting a [`KvStore`] would achieve.
+///
+/// See [`KvStore::compaction_estimate`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompactionEstimate {
+    /// Bytes of stale records compaction would remove.
+    pub reclaimable_bytes: u64,
+    /// Bytes of live records compaction would rewrite.
+    pub live_bytes: usize,
+    /// Number of generations compaction would replace.
+    pub generations: usize,
+}
+
+/// The outcome of a single [`Compactor::step`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompactStep {
+    /// Some live records were copied, but others are still left.
+    InProgress {
+        /// Bytes of live records copied by this step.
+        copied_bytes: usize,
+        /// Bytes of live records left to copy.
+        remaining_bytes: usize,
+    },
+    /// Compaction is finished and stale generations were removed.
+    Done {
+        /// Bytes of stale records removed by compaction.
+        reclaimed_bytes: u64,
+    },
+}
+
+/// How far a compaction got.
+///
+/// See [`Compactor::progress`] and [`KvStore::compact_with_progress`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CompactProgress {
+    /// Bytes of live records copied so far.
+    pub bytes_rewritten: usize,
+    /// Number of live keys copied so far.
+    pub keys_done: usize,
+    /// Number of live keys to copy in total.
+    pub keys_total: usize,
+}
+
+/// Compacts a [`KvStore`] incrementally, a bounded number of bytes at a time.
+///
+/// Live records are copied into a temporary logfile, which replaces the stale
+/// generations only once every record was copied. Until then, the store on disk is
+/// left untouched, so a compaction can be paused or abandoned at any point.
+/// Dropping an unfinished compactor removes the temporary logfile.
+///
+/// See [`KvStore::compactor`].
+///
+/// # Examples
+///
+/// ```rust no_run
+/// # use kvs::{Result, KvStore, CompactStep};
+/// # fn main() -> Result<()> {
+/// use std::env::current_dir;
+/// let mut store = KvStore::open(current_dir()?)?;
+/// let mut compactor = store.compactor(64 * 1024)?;
+///
+/// while let CompactStep::InProgress { .. } = compactor.step()? {
+///     // Do some other work in between.
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct Compactor<'a> {
+    store: &'a mut KvStore,
+    state: Option<CompactionState>,
+    step_bytes: usize,
+    progress: CompactProgress,
+    reclaimed_bytes: u64,
+}
+
+/// Progress of an unfinished compaction.
+#[derive(Debug)]
+pub(crate) struct CompactionState {
+    /// Generation the compacted logfile will become.
+    pub(crate) clean_gen: usize,
+    /// Directory and name of the temporary logfile records are copied to.
+    pub(crate) temp_dir: StoreDir,
+    pub(crate) temp_name: String,
+    pub(crate) writer: BufWriter<Counted<File>>,
+    pub(crate) writer_pos: usize,
+    /// Live records that are left to copy.
+    pub(crate) pending: Vec<(String, CommandPointer)>,
+    pub(crate) remaining_bytes: usize,
+    /// Copied records, pointing into the compacted logfile.
+    pub(crate) relocated: Vec<(String, CommandPointer)>,
+}
+
+impl<'a> Compactor<'a> {
+    pub(crate) fn new(store: &'a mut KvStore, state: CompactionState, step_bytes: usize) -> Self {
+        let progress = CompactProgress {
+            keys_total: state.pending.len(),
+            ..CompactProgress::default()
+        };
+
+        Self {
+            store,
+            state: Some(state),
+            step_bytes,
+            progress,
+            reclaimed_bytes: 0,
+        }
+    }
+
+    /// Copies live records until at least the configured number of bytes was copied,
+    /// or all of them are.
+    ///
+    /// Once every live record is copied, the compacted logfile replaces the stale
+    /// generations and [`CompactStep::Done`] is returned, including on every later call.
+    ///
+    /// # Errors
+    ///
+    /// This function propagates any I/O error that could arise while copying records
+    /// or replacing the stale generations.
+    pub fn step(&mut self) -> Result<CompactStep> {
+        let state = match &mut self.state {
+            Some(state) => state,
+            None => {
+                return Ok(CompactStep::Done {
+                    reclaimed_bytes: self.reclaimed_bytes,
+                })
+            }
+        };
+
+        let copied_bytes = self.store.compaction_step(state, self.step_bytes)?;
+        self.progress.bytes_rewritten += copied_bytes;
+        self.progress.keys_done = self.progress.keys_total - state.pending.len();
+
+        if !state.pending.is_empty() {
+            return Ok(CompactStep::InProgress {
+                copied_bytes,
+                remaining_bytes: state.remaining_bytes,
+            });
+        }
+
+        if let Some(state) = self.state.take() {
+            self.reclaimed_bytes = self.store.finish_compaction(state)?;
+        }
+
+        Ok(CompactStep::Done {
+            reclaimed_bytes: self.reclaimed_bytes,
+        })
+    }
+
+    /// Gets how many live records were copied so far, out of how many.
+    pub fn progress(&self) -> CompactProgress {
+        self.progress
+    }
+}
+
+impl Drop for Compactor<'_> {
+    fn drop(&mut self) {
+        if let Some(state) = self.state.take() {
+            drop(state.writer);
+            let _ = state.temp_dir.remove_file(&state.temp_name);
+        }
+    }
+}
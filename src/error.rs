@@ -1,4 +1,5 @@
 use std::io;
+use std::path::PathBuf;
 
 use thiserror::Error;
 
@@ -19,7 +20,7 @@ pub enum KvsError {
     Serde(#[from] serde_json::Error),
 
     /// Missing Logfile
-    #[error("tried to access missing logfile: {0}.log")]
+    #[error("missing logfile of generation {0}")]
     MissingLogfile(usize),
 
     /// Unexpected Command
@@ -30,4 +31,178 @@ pub enum KvsError {
         /// Actual command kind
         got: &'static str,
     },
+
+    /// Corrupted Record
+    #[error("corrupted record in generation {gen} at offset {offset}")]
+    Corruption {
+        /// Generation of the logfile holding the record
+        gen: usize,
+        /// Position of the record within the logfile
+        offset: usize,
+    },
+
+    /// Existing Store
+    #[error("a store already exists at {0}")]
+    StoreExists(PathBuf),
+
+    /// Unsupported Format
+    #[error("unsupported logfile format version (expected: {expected}, found: {found})")]
+    UnsupportedFormat {
+        /// Format version found in the logfile
+        found: u16,
+        /// Format version supported by this build
+        expected: u16,
+    },
+
+    /// Not An Integer
+    #[error("value of key {0} is not an integer")]
+    NotAnInteger(String),
+
+    /// Integer Overflow
+    #[error("value of key {0} would overflow")]
+    IntegerOverflow(String),
+
+    /// Read-Only Filesystem
+    #[error("store directory {0} is not writable")]
+    ReadOnlyFilesystem(PathBuf),
+
+    /// Unavailable Sequence
+    #[error("cannot roll back to sequence {0}, later writes are not at the end of the log")]
+    SequenceUnavailable(u64),
+
+    /// Missing Store
+    #[error("no store found at {0}")]
+    StoreNotFound(PathBuf),
+
+    /// Not A Directory
+    #[error("store path {0} is not a directory")]
+    NotADirectory(PathBuf),
+
+    /// Quota Exceeded
+    #[error("write would grow live data to {live_bytes} bytes (limit: {limit})")]
+    QuotaExceeded {
+        /// Bytes of live records the write would have led to
+        live_bytes: u64,
+        /// Configured limit of live bytes
+        limit: u64,
+    },
+
+    /// Unsupported Codec
+    #[error("logfile records are serialized with codec {0}, which this build does not support")]
+    UnsupportedCodec(u8),
+
+    /// Replay Failure
+    #[error("failed to deserialize the record in generation {gen} at offset {offset}")]
+    ReplayFailed {
+        /// Generation of the logfile holding the record
+        gen: usize,
+        /// Position of the record within the logfile
+        offset: usize,
+        /// Error deserializing the record
+        #[source]
+        source: serde_json::Error,
+    },
+
+    /// Ambiguous Generation
+    #[error("several logfiles claim generation {0}")]
+    AmbiguousGeneration(usize),
+
+    /// Not An Object
+    #[error("value of key {0} is not a JSON object")]
+    NotAnObject(String),
+}
+
+/// The kind of a [`KvsError`], without the data it carries.
+///
+/// Unlike [`KvsError`], which wraps errors that can't be compared, kinds implement
+/// [`PartialEq`], so they can be asserted on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorKind {
+    /// See [`KvsError::Io`].
+    Io,
+    /// See [`KvsError::Serde`].
+    Serde,
+    /// See [`KvsError::MissingLogfile`].
+    MissingLogfile,
+    /// See [`KvsError::UnexpectedCommand`].
+    UnexpectedCommand,
+    /// See [`KvsError::Corruption`].
+    Corruption,
+    /// See [`KvsError::StoreExists`].
+    StoreExists,
+    /// See [`KvsError::UnsupportedFormat`].
+    UnsupportedFormat,
+    /// See [`KvsError::NotAnInteger`].
+    NotAnInteger,
+    /// See [`KvsError::IntegerOverflow`].
+    IntegerOverflow,
+    /// See [`KvsError::ReadOnlyFilesystem`].
+    ReadOnlyFilesystem,
+    /// See [`KvsError::SequenceUnavailable`].
+    SequenceUnavailable,
+    /// See [`KvsError::StoreNotFound`].
+    StoreNotFound,
+    /// See [`KvsError::NotADirectory`].
+    NotADirectory,
+    /// See [`KvsError::QuotaExceeded`].
+    QuotaExceeded,
+    /// See [`KvsError::UnsupportedCodec`].
+    UnsupportedCodec,
+    /// See [`KvsError::ReplayFailed`].
+    ReplayFailed,
+    /// See [`KvsError::AmbiguousGeneration`].
+    AmbiguousGeneration,
+    /// See [`KvsError::NotAnObject`].
+    NotAnObject,
+}
+
+impl KvsError {
+    /// Gets the kind of this error.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Self::Io(_) => ErrorKind::Io,
+            Self::Serde(_) => ErrorKind::Serde,
+            Self::MissingLogfile(_) => ErrorKind::MissingLogfile,
+            Self::UnexpectedCommand { .. } => ErrorKind::UnexpectedCommand,
+            Self::Corruption { .. } => ErrorKind::Corruption,
+            Self::StoreExists(_) => ErrorKind::StoreExists,
+            Self::UnsupportedFormat { .. } => ErrorKind::UnsupportedFormat,
+            Self::NotAnInteger(_) => ErrorKind::NotAnInteger,
+            Self::IntegerOverflow(_) => ErrorKind::IntegerOverflow,
+            Self::ReadOnlyFilesystem(_) => ErrorKind::ReadOnlyFilesystem,
+            Self::SequenceUnavailable(_) => ErrorKind::SequenceUnavailable,
+            Self::StoreNotFound(_) => ErrorKind::StoreNotFound,
+            Self::NotADirectory(_) => ErrorKind::NotADirectory,
+            Self::QuotaExceeded { .. } => ErrorKind::QuotaExceeded,
+            Self::UnsupportedCodec(_) => ErrorKind::UnsupportedCodec,
+            Self::ReplayFailed { .. } => ErrorKind::ReplayFailed,
+            Self::AmbiguousGeneration(_) => ErrorKind::AmbiguousGeneration,
+            Self::NotAnObject(_) => ErrorKind::NotAnObject,
+        }
+    }
+
+    /// Checks whether this error reports something that doesn't exist.
+    ///
+    /// This is the case of I/O errors of kind [`io::ErrorKind::NotFound`], as well as
+    /// of [`KvsError::StoreNotFound`].
+    pub fn is_not_found(&self) -> bool {
+        match self {
+            Self::Io(err) => err.kind() == io::ErrorKind::NotFound,
+            Self::StoreNotFound(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Checks whether this error reports missing permissions.
+    ///
+    /// This is the case of I/O errors of kind [`io::ErrorKind::PermissionDenied`], as
+    /// well as of [`KvsError::ReadOnlyFilesystem`], which opening a store reports when
+    /// the store directory can't be written to.
+    pub fn is_permission_denied(&self) -> bool {
+        match self {
+            Self::Io(err) => err.kind() == io::ErrorKind::PermissionDenied,
+            Self::ReadOnlyFilesystem(_) => true,
+            _ => false,
+        }
+    }
 }
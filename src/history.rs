@@ -0,0 +1,89 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::{utils::IndexHasher, CommandPointer};
+
+/// Older versions of the live keys of a store, kept under
+/// [`KvStoreBuilder::version_history`](crate::KvStoreBuilder::version_history).
+///
+/// The latest version of every key is in the index. Up to `retained` older ones are
+/// kept here, newest first, and are left in place by compaction.
+#[derive(Debug)]
+pub(crate) struct History {
+    retained: usize,
+    older: HashMap<String, VecDeque<CommandPointer>, IndexHasher>,
+}
+
+impl History {
+    /// Creates an empty history keeping up to `retained` older versions of every key,
+    /// which must be at least one.
+    pub(crate) fn new(retained: usize) -> Self {
+        Self {
+            retained,
+            older: HashMap::default(),
+        }
+    }
+
+    /// Keeps the version of `key` that was just overwritten, returning a version that
+    /// no longer fits and has become stale.
+    pub(crate) fn push(
+        &mut self,
+        key: &str,
+        overwritten: CommandPointer,
+    ) -> Option<CommandPointer> {
+        let versions = match self.older.get_mut(key) {
+            Some(versions) => versions,
+            None => self.older.entry(key.to_owned()).or_default(),
+        };
+        versions.push_front(overwritten);
+
+        if versions.len() > self.retained {
+            versions.pop_back()
+        } else {
+            None
+        }
+    }
+
+    /// Forgets the older versions of a removed key, returning them as they are stale.
+    pub(crate) fn remove(&mut self, key: &str) -> VecDeque<CommandPointer> {
+        self.older.remove(key).unwrap_or_default()
+    }
+
+    /// Gets the version of `key` that is `n_ago` versions older than the latest one.
+    pub(crate) fn get(&self, key: &str, n_ago: usize) -> Option<CommandPointer> {
+        let versions = self.older.get(key)?;
+        versions.get(n_ago.checked_sub(1)?).copied()
+    }
+
+    /// Gets the number of bytes of records holding older versions.
+    pub(crate) fn bytes(&self) -> u64 {
+        self.older
+            .values()
+            .flatten()
+            .map(|cmd_ptr| cmd_ptr.len() as u64)
+            .sum()
+    }
+
+    /// Lists the older versions of `key`, newest first.
+    pub(crate) fn versions(&self, key: &str) -> impl Iterator<Item = CommandPointer> + '_ {
+        self.older.get(key).into_iter().flatten().copied()
+    }
+
+    /// Lists the older versions of every key.
+    pub(crate) fn all_versions(&self) -> impl Iterator<Item = CommandPointer> + '_ {
+        self.older.values().flatten().copied()
+    }
+
+    /// Gets the version [`push`](Self::push) would make stale if `key` was overwritten.
+    pub(crate) fn evicted_by_push(&self, key: &str) -> Option<CommandPointer> {
+        let versions = self.older.get(key)?;
+        if versions.len() < self.retained {
+            return None;
+        }
+        versions.back().copied()
+    }
+
+    /// Forgets every older version.
+    pub(crate) fn clear(&mut self) {
+        self.older.clear();
+    }
+}
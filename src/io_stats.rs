@@ -0,0 +1,112 @@
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Cumulative I/O performed by a [`KvStore`](crate::KvStore) since it was opened.
+///
+/// Counts bytes actually read from and written to logfiles, below any buffering,
+/// and includes the I/O done by compaction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct IoStats {
+    /// Bytes read from logfiles.
+    pub bytes_read: u64,
+    /// Bytes written to logfiles, including records rewritten by compaction.
+    pub bytes_written: u64,
+    /// Bytes of keys and values set, regardless of how they are stored.
+    pub logical_bytes_written: u64,
+    /// Number of times a logfile was synced to disk.
+    pub syncs: u64,
+}
+
+impl IoStats {
+    /// Gets the ratio of bytes written to logfiles to the bytes of keys and values set.
+    ///
+    /// This grows with record overhead and with every compaction, which rewrites live
+    /// records. Returns [`None`] if nothing was set yet.
+    pub fn write_amplification(&self) -> Option<f64> {
+        if self.logical_bytes_written == 0 {
+            return None;
+        }
+
+        Some(self.bytes_written as f64 / self.logical_bytes_written as f64)
+    }
+}
+
+/// Shared counters updated by every [`Counted`] file of a store.
+#[derive(Debug, Default)]
+pub(crate) struct IoCounters {
+    read: AtomicU64,
+    written: AtomicU64,
+    logical_written: AtomicU64,
+    syncs: AtomicU64,
+}
+
+impl IoCounters {
+    pub(crate) fn stats(&self) -> IoStats {
+        IoStats {
+            bytes_read: self.read.load(Ordering::Relaxed),
+            bytes_written: self.written.load(Ordering::Relaxed),
+            logical_bytes_written: self.logical_written.load(Ordering::Relaxed),
+            syncs: self.syncs.load(Ordering::Relaxed),
+        }
+    }
+
+    pub(crate) fn record_read(&self, len: usize) {
+        self.read.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_logical_write(&self, len: usize) {
+        self.logical_written
+            .fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_sync(&self) {
+        self.syncs.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// An I/O adapter counting the bytes passing through it, otherwise behaving like `T`.
+#[derive(Debug)]
+pub(crate) struct Counted<T> {
+    inner: T,
+    counters: Arc<IoCounters>,
+}
+
+impl<T> Counted<T> {
+    pub(crate) fn new(inner: T, counters: &Arc<IoCounters>) -> Self {
+        Self {
+            inner,
+            counters: Arc::clone(counters),
+        }
+    }
+
+    pub(crate) fn get_ref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T: Read> Read for Counted<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.counters.read.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+}
+
+impl<T: Write> Write for Counted<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.counters.written.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T: Seek> Seek for Counted<T> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
@@ -1,17 +1,43 @@
+use std::borrow::Cow;
 use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::fs::{self, File};
-use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::io::{self, BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write};
 use std::mem;
 use std::path::{Path, PathBuf};
+use std::sync::mpsc::Receiver;
+use std::sync::Arc;
+
+use serde::{de::DeserializeOwned, Serialize};
+use tempfile::TempDir;
 
 use crate::{
     command::Command,
-    get_generation_list, logfile_path,
-    utils::{get_logfile, replay},
-    CommandPointer, KvsError, Result,
+    compaction::CompactionState,
+    get_generation_list,
+    history::History,
+    log_reader::LogReader,
+    readers::{Readers, DEFAULT_MAX_OPEN_READERS},
+    recency::Recency,
+    slow_op::{OpTimer, DEFAULT_SLOW_OP_THRESHOLD},
+    utils::{
+        dump_records, encode_payload, get_logfile, is_torn, key_records, logfile_writer, move_file,
+        prune_generations, read_manifest, read_sync_marker, record_sequences, replay,
+        temp_logfile_name, write_manifest, write_marker, write_payload, write_record,
+        write_sync_marker, FileHeader, Index, IndexHasher, Manifest, RecordHeader, Recovery,
+        SyncMarker, FORMAT_VERSION,
+    },
+    value_reader::{JsonStrReader, ValueReader},
+    BatchOp, BloomFilter, CommandPointer, Commit, CompactProgress, CompactStep, CompactionEstimate,
+    CompactionMode, Compactor, Config, Counted, Event, EvictionPolicy, FlushPolicy, IoCounters,
+    IoStats, KvStoreBuilder, KvsError, LogRecord, Mutation, OpKind, OpenMode, PrefixedStore,
+    ReadSnapshot, RecordKind, RepairReport, Result, SetValue, Stats, StoreDir, Transaction,
+    TruncatedLog, Watchers, WriteBatch, WriteReceipt,
 };
 
 const SIZE_THRESHOLD: usize = 1024 * 1024;
+/// Least amount of stale bytes that triggers compaction under [`CompactionMode::Adaptive`].
+const MIN_ADAPTIVE_THRESHOLD: u64 = 64 * 1024;
 
 /// The [`KvStore`] stores string key-value pairs.
 ///
@@ -37,12 +63,43 @@ const SIZE_THRESHOLD: usize = 1024 * 1024;
 /// # }
 #[derive(Debug)]
 pub struct KvStore {
-    path: PathBuf,
-    index: HashMap<String, CommandPointer>,
-    readers: HashMap<usize, BufReader<File>>,
-    writer: BufWriter<File>,
+    dir: StoreDir,
+    index: Index,
+    /// Filter over the live keys, if enabled, which may hold removed keys until rebuilt.
+    bloom: Option<BloomFilter>,
+    /// Order in which live keys were last used, tracked under [`EvictionPolicy::LruEvict`].
+    recency: Option<Recency>,
+    /// Older versions of live keys, kept under [`KvStoreBuilder::version_history`].
+    history: Option<History>,
+    /// Commits of the batch being written, which are published once it is synced.
+    batched_commits: Option<Vec<Commit>>,
+    /// Every generation on disk with a reader of its logfile, kept up to date as
+    /// generations are created and removed, so they are known without a directory scan.
+    readers: Readers,
+    /// Stale generations left on disk under [`KvStoreBuilder::retain_generations`],
+    /// oldest first.
+    retained_gens: Vec<usize>,
+    writer: BufWriter<Counted<File>>,
+    writer_pos: usize,
+    writer_version: u16,
+    /// Whether anything was written to the current logfile since it was last synced.
+    dirty: bool,
+    /// Number of writes appended since the log was last flushed.
+    unflushed_writes: usize,
     curr_gen: usize,
-    stale_bytes: usize,
+    last_seq: u64,
+    stale_bytes: u64,
+    /// Bytes of records stored in all generations, which `stale_bytes` never exceeds.
+    log_bytes: u64,
+    /// Amount of stale bytes above which compaction runs on its own.
+    compaction_threshold: u64,
+    watchers: Watchers,
+    config: Config,
+    read_buf: Vec<u8>,
+    io_counters: Arc<IoCounters>,
+    /// Temporary directory of an [`ephemeral`](KvStoreBuilder::ephemeral) store, which is
+    /// deleted once the logfiles above are closed.
+    temp_dir: Option<TempDir>,
 }
 
 impl KvStore {
@@ -52,15 +109,231 @@ impl KvStore {
     ///
     /// # Errors
     ///
-    /// This function propagates I/O and deserialization errors that could arise during log replay.
+    /// This function propagates I/O and deserialization errors that could arise during log replay,
+    /// and returns [`KvsError::MissingLogfile`] if a logfile between the oldest and the newest
+    /// generation is missing. If the store directory can't be written to, for example because
+    /// it's on a read-only filesystem, [`KvsError::ReadOnlyFilesystem`] is returned, and if
+    /// `path` is an existing file rather than a directory, [`KvsError::NotADirectory`].
     pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
-        fs::create_dir_all(&path)?;
+        Self::builder().open(path)
+    }
+
+    /// Opens the [`KvStore`] within provided `path`, which must already hold one.
+    ///
+    /// Every store directory holds a `KVSTORE` marker file, which [`open`](Self::open)
+    /// writes when creating a store, so that a store can be told apart from an unrelated
+    /// or empty directory even before anything was written to it.
+    ///
+    /// # Errors
+    ///
+    /// This function returns [`KvsError::StoreNotFound`] if `path` holds no marker file.
+    /// Otherwise, see [`open`](Self::open).
+    pub fn open_existing<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::builder().open_existing(path)
+    }
+
+    /// Opens a [`KvStore`] within the directory a pre-opened handle `dir` refers to,
+    /// which was opened from `path`.
+    ///
+    /// # Errors
+    ///
+    /// See [`KvStoreBuilder::open_dir`].
+    pub fn open_dir<P: AsRef<Path>>(dir: File, path: P) -> Result<Self> {
+        Self::builder().open_dir(dir, path)
+    }
+
+    /// Repairs the [`KvStore`] within provided `path`, which must already hold one,
+    /// and opens it.
+    ///
+    /// Unlike [`open`](Self::open), which only drops a torn record written after the
+    /// last [`sync`](Self::sync), this truncates every logfile at its first unreadable
+    /// record, losing whatever follows it. The index is rebuilt from the records that
+    /// are left, and keys whose value still can't be read back are then removed.
+    /// What was fixed is listed in the returned [`RepairReport`].
+    ///
+    /// # Errors
+    ///
+    /// This function returns [`KvsError::StoreNotFound`] if `path` holds no marker file.
+    /// A logfile with an unreadable file header or a missing generation can't be
+    /// repaired, and fails like it does in [`open`](Self::open).
+    pub fn repair<P: AsRef<Path>>(path: P) -> Result<(Self, RepairReport)> {
+        Self::builder().repair(path)
+    }
+
+    /// Opens a [`KvStore`] in a new temporary directory, which is deleted along with
+    /// everything written to the store once it is dropped.
+    ///
+    /// # Errors
+    ///
+    /// See [`KvStoreBuilder::ephemeral`].
+    pub fn ephemeral() -> Result<Self> {
+        Self::builder().ephemeral()
+    }
+
+    /// Creates a [`KvStoreBuilder`] to open a store with non-default options.
+    pub fn builder() -> KvStoreBuilder {
+        KvStoreBuilder::new()
+    }
+
+    /// Moves the store directory at `src` to `dst`, replacing any directory already there,
+    /// such as for swapping in a store that was built elsewhere.
+    ///
+    /// Every [`KvStore`] opened within either directory must be closed beforehand, as
+    /// their open logfiles would keep pointing to the moved or replaced files. If `dst`
+    /// doesn't exist or is empty, it is replaced atomically by a single rename.
+    /// Otherwise, it is first renamed aside to a sibling with `.replaced` appended to
+    /// its name, which is removed once `src` took its place, so a crash in between may
+    /// leave `dst` missing with the old store still saved aside.
+    ///
+    /// # Errors
+    ///
+    /// This function returns [`KvsError::NotADirectory`] if `src` or an existing `dst`
+    /// is not a directory, and propagates any I/O error that could arise while renaming
+    /// the directories. Directories can't be renamed across filesystems, which fails
+    /// without changing either of them.
+    pub fn replace_dir(src: &Path, dst: &Path) -> Result<()> {
+        if !src.is_dir() {
+            return Err(KvsError::NotADirectory(src.to_path_buf()));
+        }
+        if dst.exists() && !dst.is_dir() {
+            return Err(KvsError::NotADirectory(dst.to_path_buf()));
+        }
+
+        let dst_is_empty = !dst.exists() || fs::read_dir(dst)?.next().is_none();
+        if dst_is_empty {
+            fs::rename(src, dst)?;
+        } else {
+            let mut replaced = dst.as_os_str().to_owned();
+            replaced.push(".replaced");
+            let replaced = PathBuf::from(replaced);
+
+            fs::rename(dst, &replaced)?;
+            if let Err(err) = fs::rename(src, dst) {
+                // Puts the old store back, since nothing took its place.
+                fs::rename(&replaced, dst)?;
+                return Err(err.into());
+            }
+            fs::remove_dir_all(&replaced)?;
+        }
+
+        let parent = |path: &Path| match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        StoreDir::new(parent(dst)).sync()?;
+        if parent(src) != parent(dst) {
+            StoreDir::new(parent(src)).sync()?;
+        }
+
+        Ok(())
+    }
+
+    /// Opens a [`KvStore`] within provided `path` using given `config`.
+    pub(crate) fn open_with<P: AsRef<Path>>(path: P, config: Config) -> Result<Self> {
+        Self::open_truncating(Self::create_dir(path)?, config, None)
+    }
+
+    /// Opens a [`KvStore`] in a fresh temporary directory using given `config`.
+    /// The directory is removed on drop.
+    pub(crate) fn ephemeral_with(config: Config) -> Result<Self> {
+        let temp_dir = tempfile::Builder::new().prefix("kvs-").tempdir()?;
+        let mut store = Self::open_with(temp_dir.path(), config)?;
+        store.temp_dir = Some(temp_dir);
+
+        Ok(store)
+    }
+
+    /// Opens a [`KvStore`] within the directory `dir` refers to using given `config`.
+    /// The directory is accessed through the handle, which is kept open by the store.
+    pub(crate) fn open_dir_with(dir: File, path: &Path, config: Config) -> Result<Self> {
+        if !dir.metadata()?.is_dir() {
+            return Err(KvsError::NotADirectory(path.to_path_buf()));
+        }
+
+        Self::open_truncating(StoreDir::from_handle(dir, path)?, config, None)
+    }
+
+    /// Repairs and opens the [`KvStore`] within provided `path` using given `config`.
+    ///
+    /// See [`repair`](Self::repair).
+    pub(crate) fn repair_with<P: AsRef<Path>>(
+        path: P,
+        config: Config,
+    ) -> Result<(Self, RepairReport)> {
+        let mut report = RepairReport::default();
+        let dir = Self::create_dir(path)?;
+        let mut store = Self::open_truncating(dir, config, Some(&mut report.truncated))?;
+
+        let cmd_ptrs: Vec<(String, CommandPointer)> = store
+            .index
+            .iter()
+            .map(|(key, cmd_ptr)| (key.clone(), *cmd_ptr))
+            .collect();
+
+        for (key, cmd_ptr) in cmd_ptrs {
+            match store.read_value(cmd_ptr) {
+                Ok(_) => {}
+                Err(err) if is_torn(&err) => report.dropped_keys.push(key),
+                Err(err) => return Err(err),
+            }
+        }
+
+        report.dropped_keys.sort_unstable();
+        for key in &report.dropped_keys {
+            store.append_remove(key.clone())?;
+        }
+        store.sync()?;
+
+        Ok((store, report))
+    }
+
+    /// Creates the store directory at `path`, unless it exists.
+    fn create_dir<P: AsRef<Path>>(path: P) -> Result<StoreDir> {
+        let path = path.as_ref();
+        if path.exists() && !path.is_dir() {
+            return Err(KvsError::NotADirectory(path.to_path_buf()));
+        }
+
+        fs::create_dir_all(path).map_err(|err| read_only_error(err.into(), path))?;
+
+        Ok(StoreDir::new(path))
+    }
+
+    /// Opens a [`KvStore`] within the store directory `dir` using given `config`.
+    ///
+    /// If `truncated` is given, the store is being repaired, and every logfile is cut
+    /// short at its first unreadable record rather than only a torn record past the
+    /// last sync. Truncated logfiles are then listed in `truncated`.
+    fn open_truncating(
+        dir: StoreDir,
+        config: Config,
+        mut truncated: Option<&mut Vec<TruncatedLog>>,
+    ) -> Result<Self> {
+        let naming = &config.naming;
+        write_marker(&dir, naming).map_err(|err| read_only_error(err, dir.path()))?;
+        let mut prev_gens = get_generation_list(&dir, naming)?;
+
+        // Generations preceding the manifest's were retained or left over from an
+        // interrupted compaction, and are no longer part of the store.
+        let mut retained_gens = Vec::new();
+        if let Some(manifest) = read_manifest(&dir, naming)? {
+            let stale = prev_gens
+                .iter()
+                .take_while(|&&gen| gen < manifest.first_gen)
+                .count();
+            let stale_gens: Vec<usize> = prev_gens.drain(..stale).collect();
+            retained_gens =
+                prune_generations(&dir, naming, &stale_gens, config.retain_generations)?;
+        }
 
-        let prev_gens = get_generation_list(&path)?;
+        // Generations are only ever created one after another and compaction removes
+        // all generations preceding the compacted one, so a gap means a lost logfile.
+        if let Some(pair) = prev_gens.windows(2).find(|pair| pair[1] != pair[0] + 1) {
+            return Err(KvsError::MissingLogfile(pair[0] + 1));
+        }
 
         let curr_gen = if let Some(last_gen) = prev_gens.last().copied() {
-            let last_logfile_path = logfile_path(&path, last_gen);
-            if fs::metadata(last_logfile_path)?.len() <= SIZE_THRESHOLD as u64 {
+            if dir.file_len(&naming.file_name(last_gen))? <= SIZE_THRESHOLD as u64 {
                 last_gen
             } else {
                 last_gen.wrapping_add(1)
@@ -69,209 +342,2026 @@ impl KvStore {
             1
         };
 
+        let io_counters = Arc::new(IoCounters::default());
+
+        // Only the newest generation was being written to, so only it can end in a torn
+        // record, cut short by the end of the logfile, which is dropped if it was written
+        // after the last sync. Any other record that can't be read fails opening. Without a
+        // marker naming that generation, none of it is known to have been synced.
+        let last_gen = prev_gens.last().copied();
+        let synced_end = match read_sync_marker(&dir, naming)? {
+            Some(marker) if Some(marker.gen) == last_gen => marker.offset,
+            _ => FileHeader::LEN,
+        };
+
         let mut stale_bytes = 0;
-        let mut index = HashMap::new();
-        let mut readers = HashMap::with_capacity(prev_gens.len() + 1);
+        let mut log_bytes = 0;
+        let mut last_seq = 0;
+        let mut writer_version = FORMAT_VERSION;
+        let mut writer_pos = FileHeader::LEN;
+        let mut index = Index::default();
+        let max_open_readers = config.max_open_readers.unwrap_or(DEFAULT_MAX_OPEN_READERS);
+        let mut readers = Readers::new(
+            dir.clone(),
+            naming.clone(),
+            Arc::clone(&io_counters),
+            max_open_readers,
+        );
 
         for gen in prev_gens {
-            let mut reader = BufReader::new(File::open(logfile_path(&path, gen))?);
+            let reader = LogReader::new(dir.open_read(&naming.file_name(gen))?, &io_counters);
+
+            let recovery = match truncated {
+                Some(_) => Recovery::Truncate,
+                None if Some(gen) == last_gen => Recovery::TornTail { synced_end },
+                None => Recovery::Strict,
+            };
+            let replayed = replay(BufReader::new(reader.cursor(0)), &mut index, gen, recovery)?;
+            if replayed.torn {
+                let logfile = dir
+                    .open_write(&naming.file_name(gen))
+                    .map_err(|err| read_only_error(err.into(), dir.path()))?;
+                let len = logfile.metadata()?.len();
+                logfile.set_len(replayed.end as u64)?;
+                logfile.sync_data()?;
+
+                if let Some(truncated) = truncated.as_mut() {
+                    truncated.push(TruncatedLog {
+                        generation: gen,
+                        offset: replayed.end,
+                        dropped_bytes: len - replayed.end as u64,
+                    });
+                }
+            }
 
-            stale_bytes += replay(&mut reader, &mut index, gen)?;
+            stale_bytes += replayed.stale_bytes;
+            log_bytes += (replayed.end - FileHeader::LEN) as u64;
+            last_seq = last_seq.max(replayed.last_seq);
 
-            readers.insert(gen, reader);
+            if gen == curr_gen {
+                writer_version = replayed.version.unwrap_or(FORMAT_VERSION);
+                writer_pos = replayed.end;
+            }
+
+            readers.insert(gen, Arc::new(reader));
         }
 
-        let current_logfile = get_logfile(&path, curr_gen)?;
-        readers.insert(curr_gen, BufReader::new(current_logfile.try_clone()?));
+        let current_logfile =
+            get_logfile(&dir, naming, curr_gen).map_err(|err| read_only_error(err, dir.path()))?;
+        let writer = logfile_writer(Counted::new(current_logfile, &io_counters), writer_pos)?;
 
-        let writer = BufWriter::new(current_logfile);
+        readers.add(curr_gen);
 
-        Ok(Self {
-            path: PathBuf::from(path.as_ref()),
+        let mut store = Self {
+            dir,
             curr_gen,
+            last_seq,
             readers,
+            retained_gens,
+            writer_version,
             writer,
+            dirty: false,
+            unflushed_writes: 0,
+            writer_pos,
             index,
+            bloom: None,
+            recency: None,
+            history: None,
+            batched_commits: None,
             stale_bytes,
-        })
+            log_bytes,
+            compaction_threshold: 0,
+            watchers: Watchers::default(),
+            config,
+            read_buf: Vec::new(),
+            io_counters,
+            temp_dir: None,
+        };
+
+        store.debug_check_stale_bytes();
+        store.update_compaction_threshold();
+        store.rebuild_bloom_filter();
+        store.rebuild_recency();
+        store.rebuild_history()?;
+
+        if store.config.open_mode == OpenMode::Verified {
+            store.verify()?;
+        }
+
+        Ok(store)
     }
 
     /// Sets the given `key` to provided `value`.
     ///
-    /// If the key already exists, the previous value will be overwritten.
+    /// If the key already exists, the previous value will be overwritten. A store opened
+    /// with [`KvStoreBuilder::dedup_identical`] instead leaves the log untouched if the
+    /// previous value is identical, without notifying watchers either.
     ///
     /// # Errors
     ///
-    /// This function propagates serialization and I/O errors that could arise while
-    /// writing to the log.
+    /// This function returns [`KvsError::QuotaExceeded`] if the write would exceed
+    /// [`KvStoreBuilder::max_live_bytes`]. Otherwise it propagates serialization and
+    /// I/O errors that could arise while reading the previous value or writing to the log.
     pub fn set<K, V>(&mut self, key: K, value: V) -> Result<()>
     where
         K: Into<String>,
         V: Into<String>,
     {
-        let key = key.into();
-        let value = value.into();
-        let start = self.writer.stream_position()? as usize;
+        self.set_counted(key, value).map(|_| ())
+    }
 
-        let command = Command::Set(key, value);
-        serde_json::to_writer(&mut self.writer, &command)?;
+    /// Sets the given `key` to provided `value`, like [`set`](Self::set), and returns
+    /// the number of bytes appended to the log.
+    ///
+    /// The count is the length of the whole record, including its header and the
+    /// serialization overhead, and is `0` if nothing was written. It doesn't include
+    /// bytes rewritten by a compaction the write triggered.
+    ///
+    /// # Errors
+    ///
+    /// This function propagates serialization and I/O errors that could arise while
+    /// reading the previous value or writing to the log.
+    pub fn set_counted<K, V>(&mut self, key: K, value: V) -> Result<usize>
+    where
+        K: Into<String>,
+        V: Into<String>,
+    {
+        let written = match self.write_set(key.into(), value.into())? {
+            (cmd_ptr, true) => cmd_ptr.len(),
+            (_, false) => 0,
+        };
+
+        if self.compaction_due() {
+            self.clean_stale_data()?;
+        }
+
+        Ok(written)
+    }
 
-        let end = self.writer.stream_position()? as usize;
+    /// Serializes `value` to JSON and sets the given `key` to it.
+    ///
+    /// # Errors
+    ///
+    /// This function propagates serialization and I/O errors that could arise while
+    /// serializing the value or writing to the log.
+    pub fn set_json<K, T>(&mut self, key: K, value: &T) -> Result<()>
+    where
+        K: Into<String>,
+        T: Serialize + ?Sized,
+    {
+        let value = serde_json::to_string(value)?;
+        self.set(key, value)
+    }
 
-        let cmd_ptr = CommandPointer::new(self.curr_gen, start..end);
+    /// Sets the field at the dotted `field_path` of the JSON object stored at the given
+    /// `key` to `value`, writing the whole updated object back.
+    ///
+    /// Missing objects along the path are created, including the stored object itself
+    /// if the key doesn't exist. For example, setting `"address.city"` of a key that
+    /// doesn't exist sets it to `{"address":{"city":value}}`.
+    ///
+    /// # Errors
+    ///
+    /// This function returns [`KvsError::NotAnObject`] if the stored value or one of the
+    /// fields along the path holds anything but a JSON object, in which case nothing is
+    /// written. Otherwise it propagates serialization and I/O errors that could arise
+    /// while reading or writing the log.
+    pub fn set_field<K: Into<String>>(
+        &mut self,
+        key: K,
+        field_path: &str,
+        value: serde_json::Value,
+    ) -> Result<()> {
+        let key = key.into();
+        let not_an_object = || KvsError::NotAnObject(key.clone());
 
-        if let Command::Set(key, _) = command {
-            if let Some(old_cmd_ptr) = self.index.insert(key, cmd_ptr) {
-                self.stale_bytes += old_cmd_ptr.len();
+        let mut object = match self.index.get(&key).copied() {
+            Some(cmd_ptr) => {
+                serde_json::from_str(&self.read_value(cmd_ptr)?).map_err(|_| not_an_object())?
             }
-        }
+            None => serde_json::Value::Object(Default::default()),
+        };
 
-        if self.stale_bytes > SIZE_THRESHOLD {
-            self.clean_stale_data()?;
+        let mut fields = field_path.split('.');
+        let last = fields.next_back().unwrap_or_default();
+        let mut parent = object.as_object_mut().ok_or_else(not_an_object)?;
+        for field in fields {
+            parent = parent
+                .entry(field)
+                .or_insert_with(|| serde_json::Value::Object(Default::default()))
+                .as_object_mut()
+                .ok_or_else(not_an_object)?;
         }
+        parent.insert(last.to_owned(), value);
 
-        Ok(())
+        let object = serde_json::to_string(&object)?;
+        self.set(key, object)
     }
 
-    /// Fetches the stored `value` of a given `key`.
+    /// Sets the given `key` to provided `value`, like [`set`](Self::set), and returns
+    /// where the write ended up in the log.
     ///
-    /// Returns [`None`] if the key does not exist.
+    /// The write is reported as durable only if the [`FlushPolicy`] synced it to disk.
+    /// If [`KvStoreBuilder::dedup_identical`] skipped the write, the receipt points at
+    /// the record already holding the value.
     ///
     /// # Errors
     ///
-    /// This function propagates deserialization and I/O errors that could arise while
-    /// reading the log.
-    pub fn get<K: Into<String>>(&mut self, key: K) -> Result<Option<String>> {
-        if let Some(cmd_ptr) = self.index.get(&key.into()) {
-            let gen = cmd_ptr.gen();
-            let start = cmd_ptr.start();
-            let length = cmd_ptr.len();
-
-            let logfile = self
-                .readers
-                .get_mut(&gen)
-                .ok_or_else(|| KvsError::MissingLogfile(gen))?;
+    /// This function propagates serialization and I/O errors that could arise while
+    /// writing to the log.
+    pub fn set_tracked<K, V>(&mut self, key: K, value: V) -> Result<WriteReceipt>
+    where
+        K: Into<String>,
+        V: Into<String>,
+    {
+        let key = key.into();
+        let (mut cmd_ptr, _) = self.write_set(key.clone(), value.into())?;
 
-            logfile.seek(SeekFrom::Start(start as u64))?;
-            let reader = logfile.take(length as u64);
+        if self.compaction_due() {
+            self.clean_stale_data()?;
+            // Compaction moves the record to a new generation.
+            cmd_ptr = self.index[&key];
+        }
 
-            let command: Command = serde_json::from_reader(reader)?;
+        Ok(WriteReceipt {
+            generation: cmd_ptr.gen(),
+            offset: cmd_ptr.start(),
+            durable: self.config.flush_policy == FlushPolicy::Sync,
+        })
+    }
 
-            if let Command::Set(_, value) = command {
-                Ok(Some(value))
-            } else {
-                Err(KvsError::UnexpectedCommand {
-                    expected: "set",
-                    got: command.kind(),
-                })
-            }
-        } else {
-            Ok(None)
+    /// Sets the given `key` to provided `value` only if the key doesn't exist yet.
+    ///
+    /// Returns `true` if the value was set, or `false` if the key already existed,
+    /// in which case nothing is written to the log.
+    ///
+    /// # Errors
+    ///
+    /// This function propagates serialization and I/O errors that could arise while
+    /// writing to the log.
+    pub fn set_nx<K, V>(&mut self, key: K, value: V) -> Result<bool>
+    where
+        K: Into<String>,
+        V: Into<String>,
+    {
+        let key = key.into();
+        if self.index.contains_key(&key) {
+            return Ok(false);
         }
+
+        self.set(key, value)?;
+        Ok(true)
     }
 
-    /// Removes a given key returning `true` if the key was saved, `false` otherwise.
+    /// Sets the given `key` to provided `value` only if the key was last written by the
+    /// write with sequence number `expected_seq`, or doesn't exist if it is [`None`].
+    ///
+    /// Returns `true` if the value was set, or `false` if the key was written since,
+    /// in which case nothing is written to the log. Together with
+    /// [`sequence_of`](Self::sequence_of), this allows optimistic read-modify-write updates.
     ///
     /// # Errors
     ///
     /// This function propagates serialization and I/O errors that could arise while
     /// writing to the log.
-    pub fn remove<K: Into<String>>(&mut self, key: K) -> Result<bool> {
+    pub fn set_if_seq<K, V>(&mut self, key: K, value: V, expected_seq: Option<u64>) -> Result<bool>
+    where
+        K: Into<String>,
+        V: Into<String>,
+    {
         let key = key.into();
+        if self.sequence_of(&key) != expected_seq {
+            return Ok(false);
+        }
 
-        let command = Command::Remove(key);
+        self.set(key, value)?;
+        Ok(true)
+    }
 
-        serde_json::to_writer(&mut self.writer, &command)?;
+    /// Adds `by` to the integer stored at the given `key`, returning the new value.
+    ///
+    /// A key that doesn't exist is treated as holding `0`.
+    ///
+    /// # Errors
+    ///
+    /// This function returns [`KvsError::NotAnInteger`] if the stored value can't be
+    /// parsed as an `i64`, and [`KvsError::IntegerOverflow`] if the new value doesn't fit
+    /// in one, in which case nothing is written. Otherwise it propagates serialization
+    /// and I/O errors that could arise while reading or writing the log.
+    pub fn increment<K: Into<String>>(&mut self, key: K, by: i64) -> Result<i64> {
+        self.update_integer(key.into(), |value| value.checked_add(by))
+    }
 
-        if let Command::Remove(key) = command {
-            if let Some(old_cmd_ptr) = self.index.remove(&key) {
-                self.stale_bytes += old_cmd_ptr.len();
-                if self.stale_bytes > SIZE_THRESHOLD {
-                    self.clean_stale_data()?;
-                }
-                Ok(true)
-            } else {
-                Ok(false)
-            }
-        } else {
-            unreachable!()
-        }
+    /// Subtracts `by` from the integer stored at the given `key`, returning the new value.
+    ///
+    /// A key that doesn't exist is treated as holding `0`.
+    ///
+    /// # Errors
+    ///
+    /// See [`increment`](Self::increment).
+    pub fn decrement<K: Into<String>>(&mut self, key: K, by: i64) -> Result<i64> {
+        self.update_integer(key.into(), |value| value.checked_sub(by))
     }
 
-    /// Removes all stale data from the disk.
+    /// Sets all given key-value `pairs`, writing each key to the log only once.
+    ///
+    /// If a key appears more than once in `pairs`, only its last value is written.
     ///
     /// # Errors
-    /// This function propagates any I/O error that could arise while
-    /// writing to the disk. The process itself guarantees that no data
-    /// will be lost in case of a crash during cleanup.
-    pub fn clean_stale_data(&mut self) -> Result<usize> {
-        self.flush()?;
+    ///
+    /// This function propagates serialization and I/O errors that could arise while
+    /// writing to the log.
+    pub fn set_many(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        let mut last_seen = HashMap::with_capacity(pairs.len());
+        for (i, (key, _)) in pairs.iter().enumerate() {
+            last_seen.insert(key.clone(), i);
+        }
+
+        for (i, (key, value)) in pairs.into_iter().enumerate() {
+            if last_seen.get(&key) == Some(&i) {
+                self.write_set(key, value)?;
+            }
+        }
 
-        let stale = self.stale_bytes;
+        if self.compaction_due() {
+            self.clean_stale_data()?;
+        }
 
-        let clean_gen = self.curr_gen.wrapping_add(1);
-        let clean_file = get_logfile(&self.path, clean_gen)?;
-        let mut clean_writer = BufWriter::new(clean_file.try_clone()?);
+        Ok(())
+    }
 
-        let mut clean_start = 0;
+    /// Sets every live key-value pair of `other` in this store, returning the number of merged keys.
+    ///
+    /// Keys present in both stores are overwritten with the value from `other`.
+    /// `other` itself is only read from.
+    ///
+    /// # Errors
+    ///
+    /// This function propagates I/O and (de)serialization errors that could arise while
+    /// reading `other` or writing to this store's log.
+    pub fn merge_from(&mut self, other: &mut KvStore) -> Result<usize> {
+        let cmd_ptrs: Vec<(String, CommandPointer)> = other
+            .index
+            .iter()
+            .map(|(key, cmd_ptr)| (key.clone(), *cmd_ptr))
+            .collect();
 
-        for cmd_ptr in self.index.values_mut() {
-            let logfile = self
-                .readers
-                .get_mut(&cmd_ptr.gen())
-                .ok_or_else(|| KvsError::MissingLogfile(cmd_ptr.gen()))?;
+        for (key, cmd_ptr) in &cmd_ptrs {
+            let value = other.read_value(*cmd_ptr)?;
+            self.write_set(key.clone(), value)?;
+        }
 
-            logfile.seek(SeekFrom::Start(cmd_ptr.start() as u64))?;
+        if self.compaction_due() {
+            self.clean_stale_data()?;
+        }
 
-            let mut reader = logfile.take(cmd_ptr.len() as u64);
+        Ok(cmd_ptrs.len())
+    }
 
-            let length = io::copy(&mut reader, &mut clean_writer)? as usize;
-            *cmd_ptr = CommandPointer::new(clean_gen, clean_start..clean_start + length);
+    /// Fetches the stored `value` of a given `key`.
+    ///
+    /// Returns [`None`] if the key does not exist. The key is only borrowed, so looking
+    /// it up allocates nothing but the returned value.
+    ///
+    /// # Errors
+    ///
+    /// This function propagates deserialization and I/O errors that could arise while
+    /// reading the log.
+    pub fn get<K: AsRef<str>>(&mut self, key: K) -> Result<Option<String>> {
+        let _timer = self.time_op(OpKind::Get);
+        let key = key.as_ref();
 
-            clean_start += length;
+        if let Some(bloom) = &self.bloom {
+            if !bloom.may_contain(key) {
+                return Ok(None);
+            }
         }
-        clean_writer.flush()?;
 
-        let mut new_readers = HashMap::new();
-        new_readers.insert(clean_gen, BufReader::new(clean_file));
+        let cmd_ptr = match self.index.get(key).copied() {
+            Some(cmd_ptr) => cmd_ptr,
+            None => return Ok(None),
+        };
+        self.touch(key);
 
-        if clean_writer.get_ref().metadata()?.len() > SIZE_THRESHOLD as u64 {
-            let new_gen = self.curr_gen.wrapping_add(2);
-            let new_logfile = get_logfile(&self.path, self.curr_gen)?;
-            let new_writer = BufWriter::new(new_logfile.try_clone()?);
+        self.read_value(cmd_ptr).map(Some)
+    }
 
-            new_readers.insert(new_gen, BufReader::new(new_logfile));
+    /// Fetches the record of a given `key` exactly as it is stored in the log.
+    ///
+    /// A record is made of a 12 byte header, holding the length of the payload and the
+    /// sequence number of the write in little endian, followed by the payload, which is
+    /// the JSON serialized command unless compressed.
+    ///
+    /// Returns [`None`] if the key does not exist.
+    ///
+    /// # Errors
+    ///
+    /// This function propagates any I/O error that could arise while reading the log.
+    pub fn get_raw_record<K: AsRef<str>>(&mut self, key: K) -> Result<Option<Vec<u8>>> {
+        let cmd_ptr = match self.index.get(key.as_ref()).copied() {
+            Some(cmd_ptr) => cmd_ptr,
+            None => return Ok(None),
+        };
+        self.touch(key.as_ref());
 
-            self.curr_gen = new_gen;
-            self.writer = new_writer;
-        } else {
-            self.curr_gen = clean_gen;
-            self.writer = clean_writer;
+        if cmd_ptr.gen() == self.curr_gen {
+            self.flush()?;
         }
 
-        let stale_readers = mem::replace(&mut self.readers, new_readers);
-
-        stale_readers
-            .into_keys()
-            .try_for_each(|stale_gen| -> Result<()> {
-                let path = logfile_path(&self.path, stale_gen);
-                fs::remove_file(path)?;
-                Ok(())
-            })?;
+        let mut record = Vec::with_capacity(cmd_ptr.len());
+        copy_record(&mut self.readers, cmd_ptr, &mut record)?;
+        Ok(Some(record))
+    }
 
-        self.stale_bytes = 0;
+    /// Fetches the stored `value` of a given `key` and deserializes it from JSON.
+    ///
+    /// Returns [`None`] if the key does not exist.
+    ///
+    /// # Errors
+    ///
+    /// This function returns [`KvsError::Serde`] if the value can't be deserialized
+    /// into `T`, and propagates deserialization and I/O errors that could arise while
+    /// reading the log.
+    pub fn get_json<K, T>(&mut self, key: K) -> Result<Option<T>>
+    where
+        K: AsRef<str>,
+        T: DeserializeOwned,
+    {
+        match self.get(key)? {
+            Some(value) => Ok(Some(serde_json::from_str(&value)?)),
+            None => Ok(None),
+        }
+    }
 
-        Ok(stale)
+    /// Fetches the stored `value` of a given `key`, or an empty string if the key
+    /// does not exist.
+    ///
+    /// # Errors
+    ///
+    /// This function propagates deserialization and I/O errors that could arise while
+    /// reading the log.
+    pub fn get_or_default<K: AsRef<str>>(&mut self, key: K) -> Result<String> {
+        self.get(key).map(Option::unwrap_or_default)
     }
 
-    /// Flushes any pending write operation to disk.
+    /// Returns a reader over the stored `value` of a given `key`, which decodes the value
+    /// from the log as it is read instead of loading all of it into memory.
+    ///
+    /// Returns [`None`] if the key does not exist. Compressed values are still
+    /// decompressed into memory first.
     ///
     /// # Errors
     ///
-    /// This function propagates any I/O error that could arise while
-    /// flushing the buffer to the disk.
-    pub fn flush(&mut self) -> Result<()> {
-        self.writer.flush()?;
-        Ok(())
+    /// This function propagates deserialization and I/O errors that could arise while
+    /// reading the log. Reading from the returned reader fails with
+    /// [`io::ErrorKind::InvalidData`] if the value turns out to be malformed.
+    pub fn get_reader<K: AsRef<str>>(&mut self, key: K) -> Result<Option<impl Read + '_>> {
+        let cmd_ptr = match self.index.get(key.as_ref()).copied() {
+            Some(cmd_ptr) => cmd_ptr,
+            None => return Ok(None),
+        };
+        self.touch(key.as_ref());
+
+        let header = self.read_record_header(cmd_ptr)?;
+        if header.compressed {
+            let value = self.read_value(cmd_ptr)?;
+            return Ok(Some(ValueReader::Buffered(Cursor::new(value.into_bytes()))));
+        }
+
+        let payload_start = cmd_ptr.start() + RecordHeader::LEN;
+        let payload = self
+            .readers
+            .get(cmd_ptr.gen())?
+            .cursor(payload_start as u64);
+        let payload = BufReader::new(payload.take(header.payload_len as u64));
+        let reader = JsonStrReader::new_set_value(payload, cmd_ptr.gen(), cmd_ptr.start())?;
+
+        Ok(Some(ValueReader::Streamed(reader)))
+    }
+
+    /// Fetches the stored `value` of a given `key` together with its location on disk.
+    ///
+    /// Returns `(value, generation, offset)`, where `offset` is the position of the
+    /// record within the generation's logfile, or [`None`] if the key does not exist.
+    /// This is meant for diagnostics, e.g. observing where compaction moved a key.
+    ///
+    /// # Errors
+    ///
+    /// This function propagates deserialization and I/O errors that could arise while
+    /// reading the log.
+    pub fn get_with_location<K: AsRef<str>>(
+        &mut self,
+        key: K,
+    ) -> Result<Option<(String, usize, usize)>> {
+        match self.index.get(key.as_ref()).copied() {
+            Some(cmd_ptr) => {
+                self.touch(key.as_ref());
+                let value = self.read_value(cmd_ptr)?;
+                Ok(Some((value, cmd_ptr.gen(), cmd_ptr.start())))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Fetches the value a given `key` had `n_ago` writes before its current one, with
+    /// `0` fetching the current value like [`get`](Self::get).
+    ///
+    /// Returns [`None`] if the key does not exist or no such version is kept, see
+    /// [`KvStoreBuilder::version_history`].
+    ///
+    /// # Errors
+    ///
+    /// This function propagates deserialization and I/O errors that could arise while
+    /// reading the log.
+    pub fn get_version<K: AsRef<str>>(&mut self, key: K, n_ago: usize) -> Result<Option<String>> {
+        let key = key.as_ref();
+        let cmd_ptr = match n_ago {
+            0 => self.index.get(key).copied(),
+            _ => self
+                .history
+                .as_ref()
+                .and_then(|history| history.get(key, n_ago)),
+        };
+
+        match cmd_ptr {
+            Some(cmd_ptr) => {
+                self.touch(key);
+                self.read_value(cmd_ptr).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Fetches the stored `value` of a given `key` into `buf`.
+    ///
+    /// Returns `true` and replaces the contents of `buf` with the value if the key
+    /// exists, or returns `false` leaving `buf` untouched otherwise. Reusing the
+    /// same `buf` across calls avoids allocating a new [`String`] for every value.
+    ///
+    /// # Errors
+    ///
+    /// This function propagates deserialization and I/O errors that could arise while
+    /// reading the log.
+    pub fn get_into<K: AsRef<str>>(&mut self, key: K, buf: &mut String) -> Result<bool> {
+        let cmd_ptr = match self.index.get(key.as_ref()).copied() {
+            Some(cmd_ptr) => cmd_ptr,
+            None => return Ok(false),
+        };
+        self.touch(key.as_ref());
+
+        let record = self.read_record(cmd_ptr)?;
+        let value = SetValue::parse(record)?;
+
+        buf.clear();
+        buf.push_str(&value);
+        Ok(true)
+    }
+
+    /// Removes a given key returning `true` if the key was saved, `false` otherwise.
+    ///
+    /// # Errors
+    ///
+    /// This function propagates serialization and I/O errors that could arise while
+    /// writing to the log.
+    pub fn remove<K: Into<String>>(&mut self, key: K) -> Result<bool> {
+        self.remove_and_count(key.into())
+            .map(|(removed, _)| removed)
+    }
+
+    /// Removes a given key, like [`remove`](Self::remove), and returns the number of
+    /// bytes appended to the log.
+    ///
+    /// A removal is always logged, so the count is the length of the tombstone record
+    /// even if the key didn't exist. It doesn't include bytes rewritten by a compaction
+    /// the removal triggered.
+    ///
+    /// # Errors
+    ///
+    /// This function propagates serialization and I/O errors that could arise while
+    /// writing to the log.
+    pub fn remove_counted<K: Into<String>>(&mut self, key: K) -> Result<usize> {
+        self.remove_and_count(key.into())
+            .map(|(_, written)| written)
+    }
+
+    /// Removes a given key, returning whether it was saved and the number of bytes
+    /// appended to the log.
+    fn remove_and_count(&mut self, key: String) -> Result<(bool, usize)> {
+        let _timer = self.time_op(OpKind::Remove);
+        let start = self.writer_pos;
+        let removed = self.append_remove(key)?;
+        let written = self.writer_pos - start;
+
+        if removed && self.compaction_due() {
+            self.clean_stale_data()?;
+        }
+
+        Ok((removed, written))
+    }
+
+    /// Applies a write received from another store, such as through its
+    /// [`on_commit`](KvStoreBuilder::on_commit) channel, to keep this store in sync.
+    ///
+    /// The write is appended to this store's log like any `set` or `remove`, under a
+    /// sequence number of this store. Applying the same write twice leaves the store in
+    /// the same state, but the repeated record is immediately stale.
+    ///
+    /// # Errors
+    ///
+    /// This function propagates serialization and I/O errors that could arise while
+    /// writing to the log.
+    pub fn apply_command(&mut self, mutation: Mutation) -> Result<()> {
+        match mutation {
+            Mutation::Set(key, value) => self.set(key, value),
+            Mutation::Remove(key) => self.remove(key).map(drop),
+        }
+    }
+
+    /// Returns a view of this store that prepends `prefix` to every key.
+    ///
+    /// See [`PrefixedStore`] for how keys of the view are stored.
+    pub fn with_prefix(&mut self, prefix: &str) -> PrefixedStore<'_> {
+        PrefixedStore::new(self, prefix)
+    }
+
+    /// Applies all writes of a `batch` in order, checking whether compaction is due
+    /// only once at the end.
+    ///
+    /// As with [`remove_many`](Self::remove_many), nothing is written to the log for
+    /// removing keys that don't exist. Under [`FlushPolicy::Sync`], the log is synced
+    /// once after all writes rather than after each of them, and their commits are only
+    /// sent to [`on_commit`](KvStoreBuilder::on_commit) after that.
+    ///
+    /// # Errors
+    ///
+    /// This function propagates serialization and I/O errors that could arise while
+    /// writing to the log. Writes preceding the failed one stay applied, and are still
+    /// synced.
+    pub fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        self.batched_commits = Some(Vec::new());
+
+        let result = batch.ops.into_iter().try_for_each(|op| match op {
+            BatchOp::Set(key, value) => self.write_set(key, value).map(|_| ()),
+            BatchOp::Remove(key) if self.index.contains_key(&key) => {
+                self.append_remove(key).map(|_| ())
+            }
+            BatchOp::Remove(_) => Ok(()),
+        });
+
+        // Writes preceding a failed one stay applied, so they are synced either way.
+        let commits = self.batched_commits.take().unwrap_or_default();
+        self.apply_flush_policy()?;
+        for commit in commits {
+            self.send_commit(commit);
+        }
+        result?;
+
+        if self.compaction_due() {
+            self.clean_stale_data()?;
+        }
+
+        Ok(())
+    }
+
+    /// Begins a [`Transaction`] staging changes to this store until it is committed.
+    pub fn begin(&mut self) -> Transaction<'_> {
+        Transaction::new(self)
+    }
+
+    /// Removes all given `keys`, returning the number of keys that were present.
+    ///
+    /// Nothing is written to the log for keys that don't exist.
+    ///
+    /// # Errors
+    ///
+    /// This function propagates serialization and I/O errors that could arise while
+    /// writing to the log.
+    pub fn remove_many<I, K>(&mut self, keys: I) -> Result<usize>
+    where
+        I: IntoIterator<Item = K>,
+        K: Into<String>,
+    {
+        let mut removed = 0;
+
+        for key in keys {
+            let key = key.into();
+            if self.index.contains_key(&key) {
+                self.append_remove(key)?;
+                removed += 1;
+            }
+        }
+
+        if removed > 0 && self.compaction_due() {
+            self.clean_stale_data()?;
+        }
+
+        Ok(removed)
+    }
+
+    /// Removes all keys starting with a given `prefix`, returning the number of removed keys.
+    ///
+    /// # Errors
+    ///
+    /// This function propagates serialization and I/O errors that could arise while
+    /// writing to the log.
+    pub fn remove_prefix(&mut self, prefix: &str) -> Result<usize> {
+        let keys: Vec<String> = self
+            .index
+            .keys()
+            .filter(|key| key.starts_with(prefix))
+            .cloned()
+            .collect();
+
+        for key in &keys {
+            self.append_remove(key.clone())?;
+        }
+
+        if self.compaction_due() {
+            self.clean_stale_data()?;
+        }
+
+        Ok(keys.len())
+    }
+
+    /// Reads every live record from the log without deserializing it, bringing the
+    /// logfiles into the page cache of the operating system so that later reads don't
+    /// stall on the disk.
+    ///
+    /// Records are read in the order they're stored in, one generation after another,
+    /// so that the logfiles are read sequentially as much as possible.
+    ///
+    /// # Errors
+    ///
+    /// This function propagates any I/O error that could arise while reading the log.
+    pub fn prewarm(&mut self) -> Result<()> {
+        self.flush()?;
+
+        let mut cmd_ptrs: Vec<CommandPointer> = self.index.values().copied().collect();
+        if let Some(history) = &self.history {
+            cmd_ptrs.extend(history.all_versions());
+        }
+        cmd_ptrs.sort_unstable_by_key(|cmd_ptr| (cmd_ptr.gen(), cmd_ptr.start()));
+
+        for cmd_ptr in cmd_ptrs {
+            let logfile = self.readers.get(cmd_ptr.gen())?;
+            self.read_buf.resize(cmd_ptr.len(), 0);
+            logfile.read_exact_at(&mut self.read_buf, cmd_ptr.start() as u64)?;
+        }
+
+        Ok(())
+    }
+
+    /// Checks that the value of every key can be read back from the log.
+    ///
+    /// # Errors
+    ///
+    /// This function returns the first deserialization or I/O error that arises while
+    /// reading the values.
+    pub fn verify(&mut self) -> Result<()> {
+        let cmd_ptrs: Vec<CommandPointer> = self.index.values().copied().collect();
+
+        for cmd_ptr in cmd_ptrs {
+            self.read_value(cmd_ptr)?;
+        }
+
+        Ok(())
+    }
+
+    /// Calls `f` with every key and its value, reading one value at a time.
+    ///
+    /// Keys are visited in no particular order. Iteration stops at the first error
+    /// returned by `f`, which is then returned.
+    ///
+    /// # Errors
+    ///
+    /// This function propagates errors returned by `f`, and deserialization and
+    /// I/O errors that could arise while reading the log.
+    pub fn for_each<F>(&mut self, mut f: F) -> Result<()>
+    where
+        F: FnMut(&str, &str) -> Result<()>,
+    {
+        // Reading values doesn't need the index, which saves copying all of the keys.
+        let index = mem::take(&mut self.index);
+
+        let result = index.iter().try_for_each(|(key, cmd_ptr)| {
+            let value = self.read_value(*cmd_ptr)?;
+            f(key, &value)
+        });
+
+        self.index = index;
+        result
+    }
+
+    /// Checks whether any key is set to `value`.
+    ///
+    /// Values are read one at a time until one matches, so this reads every value
+    /// from disk if none does. It is meant as a diagnostic rather than a lookup.
+    ///
+    /// # Errors
+    ///
+    /// This function propagates deserialization and I/O errors that could arise while
+    /// reading the log.
+    pub fn contains_value(&mut self, value: &str) -> Result<bool> {
+        let index = mem::take(&mut self.index);
+
+        let result = index
+            .values()
+            .map(|cmd_ptr| self.read_value(*cmd_ptr).map(|stored| stored == value))
+            .find(|matched| !matches!(matched, Ok(false)))
+            .unwrap_or(Ok(false));
+
+        self.index = index;
+        result
+    }
+
+    /// Lists every key set to `value`, in no particular order.
+    ///
+    /// Like [`contains_value`](Self::contains_value), this reads every value from disk.
+    ///
+    /// # Errors
+    ///
+    /// This function propagates deserialization and I/O errors that could arise while
+    /// reading the log.
+    pub fn keys_with_value(&mut self, value: &str) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+
+        self.for_each(|key, stored| {
+            if stored == value {
+                keys.push(key.to_owned());
+            }
+            Ok(())
+        })?;
+
+        Ok(keys)
+    }
+
+    /// Rebuilds the index from scratch by replaying every generation of the log.
+    ///
+    /// This is a recovery tool for an index that went out of sync with the log.
+    /// The stale byte count is recomputed along the way.
+    ///
+    /// # Errors
+    ///
+    /// This function propagates deserialization and I/O errors that could arise while
+    /// replaying the log. The index is left unchanged on error.
+    pub fn rebuild_index(&mut self) -> Result<()> {
+        self.flush()?;
+
+        let gens = self.readers.gens();
+
+        let mut index = Index::with_capacity_and_hasher(self.index.len(), Default::default());
+        let (mut stale_bytes, mut log_bytes) = (0, 0);
+
+        for gen in gens {
+            let reader = self.readers.get(gen)?;
+            let replayed = replay(
+                BufReader::new(reader.cursor(0)),
+                &mut index,
+                gen,
+                Recovery::Strict,
+            )?;
+            stale_bytes += replayed.stale_bytes;
+            log_bytes += (replayed.end - FileHeader::LEN) as u64;
+            self.last_seq = self.last_seq.max(replayed.last_seq);
+        }
+
+        self.index = index;
+        self.stale_bytes = stale_bytes;
+        self.log_bytes = log_bytes;
+        self.debug_check_stale_bytes();
+        self.rebuild_bloom_filter();
+        self.rebuild_recency();
+        self.rebuild_history()?;
+
+        Ok(())
+    }
+
+    /// Rolls the store back to the state right after the write with sequence number `seq`,
+    /// discarding all later writes.
+    ///
+    /// Later writes are discarded by truncating the logfile of the current generation,
+    /// so all of them must be stored at its end. That is no longer the case once
+    /// compaction ran or a new generation was started after the write with `seq`,
+    /// as compaction copies records in no particular order. Sequence numbers of
+    /// discarded writes are not reused.
+    ///
+    /// # Errors
+    ///
+    /// This function returns [`KvsError::SequenceUnavailable`] if writes after `seq`
+    /// can't be discarded, leaving the store unchanged. Otherwise it propagates
+    /// deserialization and I/O errors that could arise while truncating and
+    /// replaying the log.
+    pub fn truncate_to(&mut self, seq: u64) -> Result<()> {
+        self.flush()?;
+
+        let gens = self.readers.gens();
+
+        let mut truncate_at = None;
+        for gen in gens {
+            let reader = self.readers.get(gen)?;
+
+            for (start, record_seq) in record_sequences(BufReader::new(reader.cursor(0)), gen)? {
+                match truncate_at {
+                    None if record_seq > seq && gen == self.curr_gen => truncate_at = Some(start),
+                    None if record_seq > seq => return Err(KvsError::SequenceUnavailable(seq)),
+                    Some(_) if record_seq <= seq => return Err(KvsError::SequenceUnavailable(seq)),
+                    _ => {}
+                }
+            }
+        }
+
+        if let Some(end) = truncate_at {
+            self.writer.get_ref().get_ref().set_len(end as u64)?;
+            self.writer.seek(SeekFrom::Start(end as u64))?;
+
+            // Records written past `end` later on are no longer synced.
+            let naming = &self.config.naming;
+            if let Some(marker) = read_sync_marker(&self.dir, naming)? {
+                if marker.gen == self.curr_gen && marker.offset > end {
+                    let marker = SyncMarker {
+                        offset: end,
+                        ..marker
+                    };
+                    write_sync_marker(&self.dir, naming, marker)?;
+                }
+            }
+            self.writer_pos = end;
+            self.dirty = true;
+
+            self.rebuild_index()?;
+        }
+
+        Ok(())
+    }
+
+    /// Sets the given `key` to provided `value` in the logfile of generation `gen`,
+    /// which becomes the current generation.
+    ///
+    /// Generations between the current one and `gen` are created empty.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `gen` is older than the current generation, as later generations
+    /// would override the write on replay.
+    #[cfg(feature = "test-util")]
+    #[doc(hidden)]
+    pub fn set_in_generation<K, V>(&mut self, key: K, value: V, gen: usize) -> Result<()>
+    where
+        K: Into<String>,
+        V: Into<String>,
+    {
+        assert!(gen >= self.curr_gen, "generation {} is not current", gen);
+
+        if gen > self.curr_gen {
+            self.flush()?;
+
+            for new_gen in self.curr_gen + 1..=gen {
+                let logfile = get_logfile(&self.dir, &self.config.naming, new_gen)?;
+                self.readers.add(new_gen);
+
+                if new_gen == gen {
+                    self.writer = logfile_writer(self.counted(logfile), FileHeader::LEN)?;
+                }
+            }
+
+            self.curr_gen = gen;
+            self.writer_pos = FileHeader::LEN;
+            self.writer_version = FORMAT_VERSION;
+            self.dirty = false;
+        }
+
+        self.append_set(key.into(), value.into())?;
+        Ok(())
+    }
+
+    /// Points every key of the index at the record of another key.
+    #[cfg(feature = "test-util")]
+    #[doc(hidden)]
+    pub fn scramble_index(&mut self) {
+        let mut cmd_ptrs: Vec<CommandPointer> = self.index.values().copied().collect();
+        cmd_ptrs.rotate_left(1);
+
+        for (cmd_ptr, scrambled) in self.index.values_mut().zip(cmd_ptrs) {
+            *cmd_ptr = scrambled;
+        }
+    }
+
+    /// Gets the sequence number of the write that last set a given `key`.
+    ///
+    /// Every `set` and `remove` is assigned a sequence number greater than all previous
+    /// ones, so the sequence of a key changes whenever it is overwritten. Sequence numbers
+    /// are stored in the log and persist across reopening and compaction.
+    ///
+    /// Returns [`None`] if the key does not exist.
+    pub fn sequence_of(&self, key: &str) -> Option<u64> {
+        self.index.get(key).map(CommandPointer::seq)
+    }
+
+    /// Checks whether a given `key` exists, without touching the disk.
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.index.contains_key(key)
+    }
+
+    /// Checks whether any key starts with a given `prefix`, without touching the disk.
+    ///
+    /// This scans the keys of the index until the first match, so it takes time
+    /// linear in the number of keys in the worst case. An ordered index would make
+    /// this logarithmic.
+    pub fn contains_prefix(&self, prefix: &str) -> bool {
+        self.index.keys().any(|key| key.starts_with(prefix))
+    }
+
+    /// Reserves capacity for at least `additional` more keys in the in-memory index.
+    pub fn reserve(&mut self, additional: usize) {
+        self.index.reserve(additional);
+    }
+
+    /// Shrinks the capacity of the in-memory index as much as possible.
+    ///
+    /// This is useful to reclaim memory after removing a large number of keys.
+    pub fn shrink_index(&mut self) {
+        self.index.shrink_to_fit();
+    }
+
+    /// Returns the number of keys the in-memory index can hold without reallocating.
+    pub fn index_capacity(&self) -> usize {
+        self.index.capacity()
+    }
+
+    /// Gets the number of bytes read from and written to logfiles since the store was opened.
+    pub fn io_stats(&self) -> IoStats {
+        self.io_counters.stats()
+    }
+
+    /// Subscribes to changes of a given `key`.
+    ///
+    /// The returned [`Receiver`] gets an [`Event`] every time the key is set or
+    /// removed through this store, after the change has been written to the log.
+    /// Changes made by other processes are not observed.
+    pub fn subscribe(&self, key: &str) -> Receiver<Event> {
+        self.watchers.subscribe(key)
+    }
+
+    /// Removes all stale data from the disk.
+    ///
+    /// This runs a whole compaction at once, see [`compactor`](Self::compactor)
+    /// to compact incrementally instead. Only the latest `Set` record of every live key
+    /// is kept, so the compacted generation holds no `Remove` records at all.
+    ///
+    /// Live records are first copied into a temporary logfile, which is synced and then
+    /// renamed into a new generation. Only after the store directory is synced, so that
+    /// the rename is durable, is the new generation recorded in the store's manifest and
+    /// are the stale generations removed, oldest first. A crash at any point thus leaves
+    /// either the old generations or the compacted one intact on disk, possibly alongside
+    /// some of the others, which replay to the same data. Stale generations that the
+    /// manifest marks as orphaned are removed when the store is opened again.
+    ///
+    /// # Errors
+    /// This function propagates any I/O error that could arise while
+    /// writing to the disk. The process itself guarantees that no data
+    /// will be lost in case of a crash during cleanup.
+    pub fn clean_stale_data(&mut self) -> Result<u64> {
+        let _timer = self.time_op(OpKind::Compact);
+        let mut compactor = self.compactor(usize::MAX)?;
+
+        loop {
+            if let CompactStep::Done { reclaimed_bytes } = compactor.step()? {
+                return Ok(reclaimed_bytes);
+            }
+        }
+    }
+
+    /// Removes all stale data from the disk, like [`clean_stale_data`](Self::clean_stale_data),
+    /// calling `progress` after every live record is copied.
+    ///
+    /// # Errors
+    ///
+    /// See [`clean_stale_data`](Self::clean_stale_data).
+    pub fn compact_with_progress<F: FnMut(CompactProgress)>(
+        &mut self,
+        mut progress: F,
+    ) -> Result<u64> {
+        let mut compactor = self.compactor(0)?;
+
+        loop {
+            let step = compactor.step()?;
+            progress(compactor.progress());
+
+            if let CompactStep::Done { reclaimed_bytes } = step {
+                return Ok(reclaimed_bytes);
+            }
+        }
+    }
+
+    /// Starts an incremental compaction, which copies about `step_bytes` of live
+    /// records per [`Compactor::step`].
+    ///
+    /// # Errors
+    ///
+    /// This function propagates any I/O error that could arise while
+    /// creating the temporary logfile.
+    pub fn compactor(&mut self, step_bytes: usize) -> Result<Compactor<'_>> {
+        self.flush()?;
+
+        let clean_gen = self.curr_gen.wrapping_add(1);
+        let temp_dir = match &self.config.scratch_dir {
+            Some(scratch_dir) => StoreDir::new(scratch_dir),
+            None => self.dir.clone(),
+        };
+        let temp_name = temp_logfile_name(&self.config.naming, clean_gen);
+        let temp_file = temp_dir.create(&temp_name)?;
+        let writer = logfile_writer(self.counted(temp_file), FileHeader::LEN)?;
+
+        // Records are copied from the end of the list, so older versions of a key are
+        // copied before its latest one, which then wins on replay.
+        let mut pending: Vec<(String, CommandPointer)> = Vec::with_capacity(self.index.len());
+        for (key, cmd_ptr) in &self.index {
+            pending.push((key.clone(), *cmd_ptr));
+            if let Some(history) = &self.history {
+                pending.extend(history.versions(key).map(|cmd_ptr| (key.clone(), cmd_ptr)));
+            }
+        }
+        if self.config.compact_in_key_order {
+            pending.sort_by(|(a, _), (b, _)| b.cmp(a));
+        }
+
+        let state = CompactionState {
+            clean_gen,
+            temp_dir,
+            temp_name,
+            writer,
+            writer_pos: FileHeader::LEN,
+            remaining_bytes: pending.iter().map(|(_, cmd_ptr)| cmd_ptr.len()).sum(),
+            relocated: Vec::with_capacity(pending.len()),
+            pending,
+        };
+
+        Ok(Compactor::new(self, state, step_bytes))
+    }
+
+    /// Copies pending live records of a compaction until at least `step_bytes` were
+    /// copied, returning the number of bytes copied.
+    pub(crate) fn compaction_step(
+        &mut self,
+        state: &mut CompactionState,
+        step_bytes: usize,
+    ) -> Result<usize> {
+        let mut copied = 0;
+
+        while let Some((key, cmd_ptr)) = state.pending.pop() {
+            let length = copy_record(&mut self.readers, cmd_ptr, &mut state.writer)?;
+            let start = state.writer_pos;
+            state.writer_pos += length;
+
+            let clean_ptr =
+                CommandPointer::new(state.clean_gen, cmd_ptr.seq(), start..state.writer_pos);
+            state.relocated.push((key, clean_ptr));
+
+            state.remaining_bytes -= cmd_ptr.len();
+            copied += length;
+            if copied >= step_bytes {
+                break;
+            }
+        }
+
+        Ok(copied)
+    }
+
+    /// Replaces the stale generations with the compacted logfile once every live
+    /// record was copied, returning the amount of stale bytes removed.
+    pub(crate) fn finish_compaction(&mut self, state: CompactionState) -> Result<u64> {
+        let CompactionState {
+            clean_gen,
+            temp_dir,
+            temp_name,
+            mut writer,
+            writer_pos,
+            relocated,
+            ..
+        } = state;
+
+        // The compacted logfile must be durable before it replaces the stale generations,
+        // whatever the flush policy.
+        writer.flush()?;
+        writer.get_ref().get_ref().sync_data()?;
+        self.io_counters.record_sync();
+
+        let clean_name = self.config.naming.file_name(clean_gen);
+        move_file(&temp_dir, &temp_name, &self.dir, &temp_name, &clean_name)?;
+        self.dir.sync()?;
+
+        let manifest = Manifest {
+            first_gen: clean_gen,
+        };
+        write_manifest(&self.dir, &self.config.naming, manifest)?;
+
+        let marker = SyncMarker {
+            gen: clean_gen,
+            offset: writer_pos,
+        };
+        write_sync_marker(&self.dir, &self.config.naming, marker)?;
+
+        if let Some(history) = &mut self.history {
+            history.clear();
+        }
+        for (key, cmd_ptr) in relocated {
+            if let Some(old_cmd_ptr) = self.index.get_mut(&key) {
+                let older = mem::replace(old_cmd_ptr, cmd_ptr);
+
+                // Versions of a key were copied oldest first.
+                if let (Some(history), true) = (&mut self.history, older.gen() == clean_gen) {
+                    history.push(&key, older);
+                }
+            }
+        }
+
+        // Generations retained by earlier compactions are stale along with the new ones.
+        let mut stale_gens = mem::take(&mut self.retained_gens);
+        stale_gens.extend(self.readers.gens());
+
+        self.readers.clear();
+        self.readers.add(clean_gen);
+
+        self.log_bytes = (writer_pos - FileHeader::LEN) as u64;
+
+        if writer_pos > SIZE_THRESHOLD {
+            let new_gen = clean_gen.wrapping_add(1);
+            let new_logfile = get_logfile(&self.dir, &self.config.naming, new_gen)?;
+            let new_writer = logfile_writer(self.counted(new_logfile), FileHeader::LEN)?;
+
+            self.readers.add(new_gen);
+
+            self.curr_gen = new_gen;
+            self.writer = new_writer;
+            self.writer_pos = FileHeader::LEN;
+        } else {
+            self.curr_gen = clean_gen;
+            self.writer = writer;
+            self.writer_pos = writer_pos;
+        }
+        self.writer_version = FORMAT_VERSION;
+        self.dirty = false;
+
+        self.retained_gens = prune_generations(
+            &self.dir,
+            &self.config.naming,
+            &stale_gens,
+            self.config.retain_generations,
+        )?;
+
+        let reclaimed_bytes = mem::take(&mut self.stale_bytes);
+        self.update_compaction_threshold();
+        self.rebuild_bloom_filter();
+
+        Ok(reclaimed_bytes)
+    }
+
+    /// Estimates what [`clean_stale_data`](Self::clean_stale_data) would achieve right now.
+    ///
+    /// The estimate is computed from in-memory bookkeeping only and touches no disk.
+    pub fn compaction_estimate(&self) -> CompactionEstimate {
+        CompactionEstimate {
+            reclaimable_bytes: self.stale_bytes,
+            live_bytes: self.index.values().map(CommandPointer::len).sum::<usize>()
+                + self
+                    .history
+                    .as_ref()
+                    .map_or(0, |history| history.bytes() as usize),
+            generations: self.readers.len(),
+        }
+    }
+
+    /// Lists the generations of the store on disk with the size of their logfiles
+    /// in bytes, in sorted order.
+    ///
+    /// The generations are known to the store, so the store directory isn't scanned.
+    /// Buffered writes are not included in the size of the current generation
+    /// until the store is flushed.
+    ///
+    /// # Errors
+    ///
+    /// This function propagates any I/O error that could arise while
+    /// reading the sizes of the logfiles.
+    pub fn generations(&self) -> Result<Vec<(usize, u64)>> {
+        self.readers
+            .gens()
+            .into_iter()
+            .map(|gen| {
+                let len = self.dir.file_len(&self.config.naming.file_name(gen))?;
+                Ok((gen, len))
+            })
+            .collect()
+    }
+
+    /// Gets the Bloom filter over the live keys, if it was enabled with
+    /// [`KvStoreBuilder::bloom_filter`].
+    ///
+    /// Keys removed since the filter was last rebuilt, when opening or compacting,
+    /// may still be reported as present.
+    pub fn bloom_filter(&self) -> Option<&BloomFilter> {
+        self.bloom.as_ref()
+    }
+
+    /// Takes a read-only view of the store that can be shared between threads.
+    ///
+    /// Buffered writes are flushed first, and the index is copied into the view. The
+    /// view holds a reader of every generation's logfile open, regardless of
+    /// [`KvStoreBuilder::max_open_readers`], so it can still be read once they are
+    /// compacted away on platforms that allow removing open files.
+    ///
+    /// # Errors
+    ///
+    /// This function propagates any I/O error that could arise while flushing the log
+    /// or opening its logfiles.
+    pub fn read_snapshot(&mut self) -> Result<ReadSnapshot> {
+        self.flush()?;
+
+        let mut logs = HashMap::new();
+        for gen in self.readers.gens() {
+            logs.insert(gen, Arc::clone(self.readers.get(gen)?));
+        }
+
+        Ok(ReadSnapshot {
+            index: Arc::new(self.index.clone()),
+            logs: Arc::new(logs),
+        })
+    }
+
+    /// Lists every record stored in the logfile of a given generation, in order,
+    /// including stale ones.
+    ///
+    /// Buffered writes are flushed first, so that they are listed as well.
+    ///
+    /// # Errors
+    ///
+    /// This function returns [`KvsError::MissingLogfile`] if the store has no such
+    /// generation, and propagates deserialization and I/O errors that could arise
+    /// while reading the logfile.
+    pub fn dump_generation(&mut self, gen: usize) -> Result<Vec<LogRecord>> {
+        self.flush()?;
+
+        let logfile = self.readers.get(gen)?;
+
+        dump_records(BufReader::new(logfile.cursor(0)), gen)
+    }
+
+    /// Lists the generation, offset and key of every stale record, which compaction would
+    /// remove, ordered by generation and offset.
+    ///
+    /// A record is stale if the index doesn't point to it, which is the case for every
+    /// overwritten `Set` record and every `Remove` record. Every logfile is read in full,
+    /// so this is meant as a diagnostic rather than a hot path.
+    ///
+    /// # Errors
+    ///
+    /// This function propagates deserialization and I/O errors that could arise while
+    /// reading the log.
+    pub fn stale_records(&mut self) -> Result<Vec<(usize, usize, String)>> {
+        self.flush()?;
+
+        let mut stale = Vec::new();
+        for gen in self.readers.gens() {
+            let logfile = self.readers.get(gen)?;
+
+            for record in dump_records(BufReader::new(logfile.cursor(0)), gen)? {
+                let live = record.kind == RecordKind::Set
+                    && self.index.get(&record.key).is_some_and(|cmd_ptr| {
+                        cmd_ptr.gen() == gen && cmd_ptr.start() == record.offset
+                    });
+
+                if !live {
+                    stale.push((gen, record.offset, record.key));
+                }
+            }
+        }
+
+        Ok(stale)
+    }
+
+    /// Takes a snapshot of the state of the store.
+    ///
+    /// The sizes of logfiles are read from disk, see [`generations`](Self::generations).
+    ///
+    /// # Errors
+    ///
+    /// This function propagates any I/O error that could arise while
+    /// reading the sizes of the logfiles.
+    pub fn stats(&self) -> Result<Stats> {
+        let generations = self.generations()?;
+
+        Ok(Stats {
+            live_keys: self.index.len(),
+            stale_bytes: self.stale_bytes,
+            current_generation: self.curr_gen,
+            generations: generations.len(),
+            disk_bytes: generations.iter().map(|(_, size)| size).sum(),
+        })
+    }
+
+    /// Writes a compacted copy of the store into a new store directory at `dest`.
+    ///
+    /// All live key-value pairs are written into a single generation, leaving this
+    /// store untouched. The copy is a complete store, which can be opened with the same
+    /// naming options as this store, including through [`open_existing`](Self::open_existing).
+    ///
+    /// # Errors
+    ///
+    /// This function returns [`KvsError::StoreExists`] if `dest` already contains logfiles,
+    /// and propagates any I/O error that could arise while writing the copy.
+    pub fn compact_into<P: AsRef<Path>>(&mut self, dest: P) -> Result<()> {
+        self.flush()?;
+
+        fs::create_dir_all(&dest)?;
+        let dest = StoreDir::new(dest);
+        if !get_generation_list(&dest, &self.config.naming)?.is_empty() {
+            return Err(KvsError::StoreExists(dest.path().to_path_buf()));
+        }
+
+        let dest_file = get_logfile(&dest, &self.config.naming, 1)?;
+        let mut dest_writer = logfile_writer(self.counted(dest_file), FileHeader::LEN)?;
+
+        for cmd_ptr in self.index.values() {
+            copy_record(&mut self.readers, *cmd_ptr, &mut dest_writer)?;
+        }
+
+        dest_writer.flush()?;
+        dest_writer.get_ref().get_ref().sync_all()?;
+        self.io_counters.record_sync();
+
+        let naming = &self.config.naming;
+        write_manifest(&dest, naming, Manifest { first_gen: 1 })?;
+        write_marker(&dest, naming)?;
+        dest.sync()?;
+
+        Ok(())
+    }
+
+    /// Gets the path of the store directory.
+    pub fn path(&self) -> &Path {
+        self.dir.path()
+    }
+
+    /// Returns the number of bytes written but not yet flushed to the logfile.
+    ///
+    /// These bytes are lost if the process exits without a [`flush`](Self::flush).
+    /// Writes are flushed on their own once enough of them are buffered, so the count
+    /// stays bounded by the size of the write buffer.
+    pub fn unflushed_bytes(&self) -> usize {
+        self.writer.buffer().len()
+    }
+
+    /// Flushes any pending write operation to disk.
+    ///
+    /// # Errors
+    ///
+    /// This function propagates any I/O error that could arise while
+    /// flushing the buffer to the disk.
+    pub fn flush(&mut self) -> Result<()> {
+        if !self.writer.buffer().is_empty() {
+            self.writer.flush()?;
+        }
+        self.unflushed_writes = 0;
+        Ok(())
+    }
+
+    /// Extends the logfile of the current generation to `bytes` in size, so that
+    /// subsequent writes go into preallocated space.
+    ///
+    /// Only the size of the file grows: new records are still appended right after the
+    /// last one. This does nothing if the logfile is already at least `bytes` long.
+    ///
+    /// # Errors
+    ///
+    /// This function propagates any I/O error that could arise while
+    /// extending the logfile.
+    pub fn preallocate(&mut self, bytes: u64) -> Result<()> {
+        // Writes the file header of a fresh logfile before the zeroed space follows it.
+        self.flush()?;
+
+        let logfile = self.writer.get_ref().get_ref();
+        if logfile.metadata()?.len() < bytes {
+            logfile.set_len(bytes)?;
+        }
+
+        Ok(())
+    }
+
+    /// Flushes any pending write operation and syncs the current logfile to disk.
+    ///
+    /// The offset synced up to is then recorded in a `SYNCED` file next to the logfiles,
+    /// which compaction also updates once the compacted logfile is synced. When opening,
+    /// a record past that offset that is cut short by the end of the logfile is taken to
+    /// be torn by a crash, and the logfile is truncated right before it. Any other record
+    /// that can't be read, such as one before the offset or one followed by others, is
+    /// reported as an error. If the newest generation was never synced, its last record
+    /// may be torn. This does nothing if nothing was written since the last sync.
+    ///
+    /// # Errors
+    ///
+    /// This function propagates any I/O error that could arise while
+    /// flushing or syncing the logfile.
+    pub fn sync(&mut self) -> Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+
+        self.flush()?;
+        self.writer.get_ref().get_ref().sync_data()?;
+        self.io_counters.record_sync();
+        self.dirty = false;
+
+        let marker = SyncMarker {
+            gen: self.curr_gen,
+            offset: self.writer_pos,
+        };
+        write_sync_marker(&self.dir, &self.config.naming, marker)?;
+
+        Ok(())
+    }
+
+    /// Flushes any pending write operation to disk and closes the store.
+    ///
+    /// Dropping a [`KvStore`] also flushes it, but any error is ignored there.
+    /// Use this method to find out whether the final flush succeeded.
+    /// Under [`FlushPolicy::Sync`] the logfile is synced as well.
+    ///
+    /// # Errors
+    ///
+    /// This function propagates any I/O error that could arise while
+    /// flushing the buffer to the disk.
+    pub fn close(mut self) -> Result<()> {
+        match self.config.flush_policy {
+            FlushPolicy::Manual => self.flush(),
+            FlushPolicy::Sync => self.sync(),
+        }
+    }
+
+    /// Compacts the store into a single generation and closes it, so that opening it
+    /// again replays as little of the log as possible.
+    ///
+    /// Compaction is skipped if the store already has a single generation without
+    /// stale records. Otherwise it runs even below the compaction threshold, trading
+    /// time spent closing for time spent opening.
+    ///
+    /// # Errors
+    ///
+    /// This function propagates any I/O error that could arise while compacting, see
+    /// [`clean_stale_data`](Self::clean_stale_data), or closing the store.
+    pub fn close_compacting(mut self) -> Result<()> {
+        if self.stale_bytes > 0 || self.readers.len() > 1 {
+            self.clean_stale_data()?;
+        }
+
+        self.close()
+    }
+
+    /// Marks a key read by a point read as the most recently used one, under
+    /// [`EvictionPolicy::LruEvict`].
+    fn touch(&mut self, key: &str) {
+        if let Some(recency) = &mut self.recency {
+            recency.touch(key);
+        }
+    }
+
+    /// Starts timing an operation for [`KvStoreBuilder::on_slow_op`], if it was set.
+    fn time_op(&self, kind: OpKind) -> Option<OpTimer> {
+        let threshold = self
+            .config
+            .slow_op_threshold
+            .unwrap_or(DEFAULT_SLOW_OP_THRESHOLD);
+
+        self.config
+            .on_slow_op
+            .as_ref()
+            .map(|hook| hook.start(kind, threshold))
+    }
+
+    /// Wraps a logfile so that its I/O is accounted in [`IoStats`].
+    fn counted(&self, file: File) -> Counted<File> {
+        Counted::new(file, &self.io_counters)
+    }
+
+    /// Checks whether a `Set` record holding `value` should be compressed.
+    ///
+    /// Compressed records may only be appended to logfiles of the current format version.
+    #[cfg(feature = "compression")]
+    fn should_compress(&self, value: &str) -> bool {
+        match self.config.compression_threshold {
+            Some(threshold) => self.writer_version == FORMAT_VERSION && value.len() > threshold,
+            None => false,
+        }
+    }
+
+    #[cfg(not(feature = "compression"))]
+    fn should_compress(&self, _value: &str) -> bool {
+        false
+    }
+
+    /// Syncs the write that was just appended if the [`FlushPolicy`] requires it, or
+    /// flushes it once [`KvStoreBuilder::max_unflushed_writes`] writes are buffered,
+    /// unless it is part of a batch, which is synced or flushed as a whole.
+    fn apply_flush_policy(&mut self) -> Result<()> {
+        if self.batched_commits.is_some() {
+            return Ok(());
+        }
+
+        let flush_due = self
+            .config
+            .max_unflushed_writes
+            .is_some_and(|max| self.unflushed_writes >= max);
+
+        match self.config.flush_policy {
+            FlushPolicy::Sync => self.sync(),
+            FlushPolicy::Manual if flush_due => self.flush(),
+            FlushPolicy::Manual => Ok(()),
+        }
+    }
+
+    /// Sends a `command` committed at `offset` of the current generation to the
+    /// [`on_commit`](KvStoreBuilder::on_commit) channel, if there is one.
+    ///
+    /// Commits of a batch are held back until the whole batch was synced.
+    fn publish_commit(&mut self, command: &Command, seq: u64, offset: usize) {
+        if self.config.on_commit.is_none() {
+            return;
+        }
+
+        let mutation = match command {
+            Command::Set(key, value) => Mutation::Set(key.clone(), value.clone()),
+            Command::Remove(key) => Mutation::Remove(key.clone()),
+        };
+        let commit = Commit {
+            mutation,
+            generation: self.curr_gen,
+            offset,
+            seq,
+        };
+
+        match &mut self.batched_commits {
+            Some(batched) => batched.push(commit),
+            None => self.send_commit(commit),
+        }
+    }
+
+    fn send_commit(&self, commit: Commit) {
+        if let Some(on_commit) = &self.config.on_commit {
+            // A replica that went away shouldn't fail writes to the store.
+            let _ = on_commit.send(commit);
+        }
+    }
+
+    /// Replaces the integer stored at `key` with the result of `update`, which returns
+    /// [`None`] on overflow.
+    fn update_integer<F>(&mut self, key: String, update: F) -> Result<i64>
+    where
+        F: FnOnce(i64) -> Option<i64>,
+    {
+        let value = match self.index.get(&key).copied() {
+            Some(cmd_ptr) => self
+                .read_value(cmd_ptr)?
+                .parse()
+                .map_err(|_| KvsError::NotAnInteger(key.clone()))?,
+            None => 0,
+        };
+
+        let value = update(value).ok_or_else(|| KvsError::IntegerOverflow(key.clone()))?;
+        self.set(key, value.to_string())?;
+
+        Ok(value)
+    }
+
+    /// Assigns the sequence number of a new write.
+    fn next_seq(&mut self) -> u64 {
+        self.last_seq += 1;
+        self.last_seq
+    }
+
+    /// Sets the given `key` to provided `value`, without checking whether compaction
+    /// is due. Every set goes through here, so that it is timed for
+    /// [`KvStoreBuilder::on_slow_op`].
+    ///
+    /// Returns the record holding the value and whether it was written. Under
+    /// [`KvStoreBuilder::dedup_identical`], nothing is written if the current value
+    /// is identical, and its record is returned instead.
+    fn write_set(&mut self, key: String, value: String) -> Result<(CommandPointer, bool)> {
+        let _timer = self.time_op(OpKind::Set);
+
+        if self.config.dedup_identical {
+            if let Some(cmd_ptr) = self.index.get(&key).copied() {
+                if self.read_value(cmd_ptr)? == value {
+                    return Ok((cmd_ptr, false));
+                }
+            }
+        }
+
+        self.append_set(key, value).map(|cmd_ptr| (cmd_ptr, true))
+    }
+
+    /// Writes a `Set` command to the log and updates the index, without checking
+    /// whether compaction is due.
+    fn append_set(&mut self, key: String, value: String) -> Result<CommandPointer> {
+        let compress = self.should_compress(&value);
+
+        let command = Command::Set(key, value);
+        let payload = encode_payload(&command, compress)?;
+        if let Command::Set(key, _) = &command {
+            self.enforce_quota(key, RecordHeader::LEN + payload.len())?;
+        }
+
+        // Evictions are written first.
+        let start = self.writer_pos;
+        let seq = self.next_seq();
+        let end = start + write_payload(&mut self.writer, seq, &payload, compress)?;
+        self.writer_pos = end;
+        self.dirty = true;
+        self.unflushed_writes += 1;
+        if let Command::Set(key, value) = &command {
+            self.io_counters
+                .record_logical_write(key.len() + value.len());
+        }
+
+        let cmd_ptr = CommandPointer::new(self.curr_gen, seq, start..end);
+
+        if let Command::Set(key, _) = &command {
+            if let Some(bloom) = &mut self.bloom {
+                bloom.insert(key);
+            }
+            if let Some(recency) = &mut self.recency {
+                recency.touch(key);
+            }
+            if let Some(old_cmd_ptr) = self.index.insert(key.clone(), cmd_ptr) {
+                let stale = match &mut self.history {
+                    Some(history) => history.push(key, old_cmd_ptr),
+                    None => Some(old_cmd_ptr),
+                };
+                self.stale_bytes += stale.map_or(0, |cmd_ptr| cmd_ptr.len() as u64);
+            }
+        }
+        self.log_bytes += cmd_ptr.len() as u64;
+        self.debug_check_stale_bytes();
+
+        // The record is in the log even if syncing it fails, so the index has to point
+        // to it before the flush policy is applied.
+        self.apply_flush_policy()?;
+        self.publish_commit(&command, seq, start);
+        if let Command::Set(key, value) = command {
+            self.watchers.notify(&key, || Event::Set(value));
+        }
+
+        Ok(cmd_ptr)
+    }
+
+    /// Checks that setting `key` with a record of `record_len` bytes keeps the live
+    /// records within [`KvStoreBuilder::max_live_bytes`], if it was set.
+    ///
+    /// Under [`EvictionPolicy::LruEvict`], the least recently used other keys are
+    /// removed until the record fits.
+    fn enforce_quota(&mut self, key: &str, record_len: usize) -> Result<()> {
+        let limit = match self.config.max_live_bytes {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+
+        loop {
+            // Under version history, an overwritten record only becomes stale once it
+            // falls out of the window.
+            let replaced = match (&self.history, self.index.get(key)) {
+                (Some(history), Some(_)) => history.evicted_by_push(key),
+                (_, cmd_ptr) => cmd_ptr.copied(),
+            };
+            let replaced = replaced.map_or(0, |cmd_ptr| cmd_ptr.len());
+            let live_bytes =
+                self.log_bytes - self.stale_bytes - replaced as u64 + record_len as u64;
+
+            if live_bytes <= limit {
+                return Ok(());
+            }
+            // Evicting is pointless if the record wouldn't fit on its own.
+            if record_len as u64 > limit {
+                return Err(KvsError::QuotaExceeded { live_bytes, limit });
+            }
+
+            let evicted = self
+                .recency
+                .as_ref()
+                .and_then(|recency| recency.oldest_except(key))
+                .map(str::to_owned);
+            match evicted {
+                Some(evicted) => {
+                    self.append_remove(evicted)?;
+                }
+                None => return Err(KvsError::QuotaExceeded { live_bytes, limit }),
+            }
+        }
+    }
+
+    /// Writes a `Remove` command to the log and updates the index, without checking
+    /// whether compaction is due.
+    ///
+    /// Returns `true` if the key was present in the index.
+    fn append_remove(&mut self, key: String) -> Result<bool> {
+        let start = self.writer_pos;
+
+        let seq = self.next_seq();
+
+        let command = Command::Remove(key);
+
+        let length = write_record(&mut self.writer, seq, &command, false)?;
+        self.writer_pos += length;
+        self.dirty = true;
+        self.unflushed_writes += 1;
+
+        // Tombstones are never needed after compaction, so they're stale right away.
+        self.stale_bytes += length as u64;
+        self.log_bytes += length as u64;
+
+        let removed = if let Command::Remove(key) = &command {
+            if let Some(old_cmd_ptr) = self.index.remove(key) {
+                if let Some(recency) = &mut self.recency {
+                    recency.remove(key);
+                }
+                if let Some(history) = &mut self.history {
+                    let stale: usize = history.remove(key).iter().map(CommandPointer::len).sum();
+                    self.stale_bytes += stale as u64;
+                }
+
+                self.stale_bytes += old_cmd_ptr.len() as u64;
+                true
+            } else {
+                false
+            }
+        } else {
+            unreachable!()
+        };
+        self.debug_check_stale_bytes();
+
+        // Like the index, the removal is applied before the flush policy.
+        self.apply_flush_policy()?;
+        self.publish_commit(&command, seq, start);
+        if let Command::Remove(key) = &command {
+            if removed {
+                self.watchers.notify(key, || Event::Removed);
+            }
+        }
+
+        Ok(removed)
+    }
+
+    /// Checks whether enough stale bytes accumulated for compaction to run.
+    fn compaction_due(&self) -> bool {
+        self.stale_bytes > self.compaction_threshold
+    }
+
+    /// Recomputes the compaction threshold from the size of the live records.
+    fn update_compaction_threshold(&mut self) {
+        self.compaction_threshold = match self.config.compaction_mode {
+            CompactionMode::Fixed => SIZE_THRESHOLD as u64,
+            CompactionMode::Adaptive => {
+                let live_bytes = self.log_bytes - self.stale_bytes;
+                (live_bytes / 2).max(MIN_ADAPTIVE_THRESHOLD)
+            }
+            CompactionMode::Disabled => u64::MAX,
+        };
+    }
+
+    /// Rebuilds the Bloom filter from the live keys, if it is enabled, dropping removed
+    /// keys. The filter is sized for at least as many keys as there are live ones.
+    fn rebuild_bloom_filter(&mut self) {
+        if let Some((keys, false_positive_rate)) = self.config.bloom_filter {
+            let mut bloom = BloomFilter::new(keys.max(self.index.len()), false_positive_rate);
+            for key in self.index.keys() {
+                bloom.insert(key);
+            }
+            self.bloom = Some(bloom);
+        }
+    }
+
+    /// Orders the live keys by when they were last written, if eviction needs to know.
+    fn rebuild_recency(&mut self) {
+        if self.config.eviction == EvictionPolicy::LruEvict {
+            self.recency = Some(Recency::from_index(&self.index));
+        }
+    }
+
+    /// Rebuilds the older versions of live keys from the log, if they are kept, no
+    /// longer counting them as stale.
+    fn rebuild_history(&mut self) -> Result<()> {
+        let retained = match self.config.version_history {
+            0 | 1 => return Ok(()),
+            versions => versions - 1,
+        };
+        self.flush()?;
+
+        let mut history = History::new(retained);
+        let mut latest: HashMap<String, CommandPointer, IndexHasher> = HashMap::default();
+
+        for gen in self.readers.gens() {
+            let logfile = self.readers.get(gen)?;
+
+            for (key, cmd_ptr) in key_records(BufReader::new(logfile.cursor(0)), gen)? {
+                match cmd_ptr {
+                    Some(cmd_ptr) => {
+                        if let Some(overwritten) = latest.insert(key.clone(), cmd_ptr) {
+                            history.push(&key, overwritten);
+                        }
+                    }
+                    None => {
+                        latest.remove(&key);
+                        history.remove(&key);
+                    }
+                }
+            }
+        }
+
+        self.stale_bytes -= history.bytes();
+        self.history = Some(history);
+        Ok(())
+    }
+
+    /// Asserts that the stale byte count is consistent with the size of the log,
+    /// catching records counted as stale twice.
+    fn debug_check_stale_bytes(&self) {
+        debug_assert!(
+            self.stale_bytes <= self.log_bytes,
+            "{} stale bytes counted in {} bytes of records",
+            self.stale_bytes,
+            self.log_bytes
+        );
+    }
+
+    /// Reads the value of the `Set` command pointed to by `cmd_ptr`.
+    fn read_value(&mut self, cmd_ptr: CommandPointer) -> Result<String> {
+        SetValue::parse(self.read_record(cmd_ptr)?).map(Cow::into_owned)
+    }
+
+    /// Reads the serialized command pointed to by `cmd_ptr` into the read buffer.
+    fn read_record(&mut self, cmd_ptr: CommandPointer) -> Result<&[u8]> {
+        if cmd_ptr.gen() == self.curr_gen {
+            self.flush()?;
+        }
+
+        let logfile = self.readers.get(cmd_ptr.gen())?;
+        logfile.read_payload(cmd_ptr, &mut self.read_buf)?;
+
+        Ok(&self.read_buf)
+    }
+
+    /// Reads the header of the record pointed to by `cmd_ptr`.
+    ///
+    /// The length stored in the record header is checked against the length of `cmd_ptr`.
+    fn read_record_header(&mut self, cmd_ptr: CommandPointer) -> Result<RecordHeader> {
+        if cmd_ptr.gen() == self.curr_gen {
+            self.flush()?;
+        }
+
+        self.readers.get(cmd_ptr.gen())?.read_record_header(cmd_ptr)
+    }
+}
+
+/// Reports a failure to write to the store directory at `path` as
+/// [`KvsError::ReadOnlyFilesystem`], passing any other error through.
+fn read_only_error(err: KvsError, path: &Path) -> KvsError {
+    match err {
+        KvsError::Io(ref io_err)
+            if matches!(
+                io_err.kind(),
+                io::ErrorKind::ReadOnlyFilesystem | io::ErrorKind::PermissionDenied
+            ) =>
+        {
+            KvsError::ReadOnlyFilesystem(path.to_path_buf())
+        }
+        err => err,
+    }
+}
+
+/// Copies the record pointed to by `cmd_ptr` verbatim into `writer`, returning its length.
+fn copy_record<W: Write>(
+    readers: &mut Readers,
+    cmd_ptr: CommandPointer,
+    writer: &mut W,
+) -> Result<usize> {
+    let logfile = readers.get(cmd_ptr.gen())?;
+
+    let mut reader = logfile
+        .cursor(cmd_ptr.start() as u64)
+        .take(cmd_ptr.len() as u64);
+
+    Ok(io::copy(&mut reader, writer)? as usize)
+}
+
+impl TryFrom<&Path> for KvStore {
+    type Error = KvsError;
+
+    /// Opens a [`KvStore`] within provided `path`, see [`KvStore::open`].
+    fn try_from(path: &Path) -> Result<Self> {
+        Self::open(path)
+    }
+}
+
+impl Drop for KvStore {
+    fn drop(&mut self) {
+        if self.temp_dir.is_none() {
+            let _ = self.flush();
+        }
     }
 }
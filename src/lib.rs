@@ -1,14 +1,61 @@
 #![deny(missing_docs)]
+// The library reports everything through return values, printing is left to the binary.
+#![deny(clippy::print_stdout, clippy::print_stderr, clippy::dbg_macro)]
 
 //! A simple key-value store.
 
+#[cfg(feature = "tokio")]
+mod async_kvs;
+mod batch;
+mod bloom;
+mod builder;
 mod command;
+mod commit;
+mod compaction;
 mod error;
+mod history;
+mod io_stats;
 mod kvs;
+mod log_reader;
+mod prefixed;
+mod readers;
+mod receipt;
+mod recency;
+mod record;
+mod repair;
+mod slow_op;
+mod snapshot;
+mod stats;
+mod store_dir;
+mod transaction;
 mod utils;
+mod value_reader;
+mod watch;
 
 pub use crate::kvs::KvStore;
-pub use error::{KvsError, Result};
+#[cfg(feature = "tokio")]
+pub use async_kvs::AsyncKvStore;
+pub use batch::WriteBatch;
+pub use bloom::BloomFilter;
+pub use builder::{CompactionMode, EvictionPolicy, FlushPolicy, KvStoreBuilder, OpenMode};
+pub use commit::{Commit, Mutation};
+pub use compaction::{CompactProgress, CompactStep, CompactionEstimate, Compactor};
+pub use error::{ErrorKind, KvsError, Result};
+pub use io_stats::IoStats;
+pub use prefixed::PrefixedStore;
+pub use receipt::WriteReceipt;
+pub use record::{LogRecord, RecordKind};
+pub use repair::{RepairReport, TruncatedLog};
+pub use slow_op::OpKind;
+pub use snapshot::ReadSnapshot;
+pub use stats::Stats;
+pub use transaction::Transaction;
+pub use watch::Event;
 
-pub(crate) use command::{Command, CommandPointer};
-pub(crate) use utils::{get_generation_list, logfile_path};
+pub(crate) use batch::BatchOp;
+pub(crate) use builder::Config;
+pub(crate) use command::{Command, CommandPointer, ReplayCommand, SetValue};
+pub(crate) use io_stats::{Counted, IoCounters};
+pub(crate) use store_dir::StoreDir;
+pub(crate) use utils::get_generation_list;
+pub(crate) use watch::Watchers;
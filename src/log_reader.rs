@@ -0,0 +1,152 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::sync::Arc;
+#[cfg(not(any(unix, windows)))]
+use std::sync::Mutex;
+
+use crate::{
+    utils::{decompress_payload, RecordHeader},
+    CommandPointer, IoCounters, KvsError, Result,
+};
+
+/// A logfile opened for reading at given offsets, rather than from a cursor.
+///
+/// Reads only need `&self`, so threads sharing a reader can read the logfile concurrently.
+/// Platforms without positioned reads fall back to seeking a cursor shared by every
+/// read, which are then serialized.
+#[derive(Debug)]
+pub(crate) struct LogReader {
+    file: File,
+    counters: Arc<IoCounters>,
+    #[cfg(not(any(unix, windows)))]
+    cursor: Mutex<()>,
+}
+
+impl LogReader {
+    pub(crate) fn new(file: File, counters: &Arc<IoCounters>) -> Self {
+        Self {
+            file,
+            counters: Arc::clone(counters),
+            #[cfg(not(any(unix, windows)))]
+            cursor: Mutex::new(()),
+        }
+    }
+
+    /// Reads bytes starting at `offset` into `buf`, returning how many were read.
+    pub(crate) fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let n = self.read_at_uncounted(buf, offset)?;
+        self.counters.record_read(n);
+        Ok(n)
+    }
+
+    /// Reads exactly enough bytes starting at `offset` to fill `buf`.
+    ///
+    /// Fails with [`io::ErrorKind::UnexpectedEof`] if the logfile ends first.
+    pub(crate) fn read_exact_at(&self, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+        while !buf.is_empty() {
+            match self.read_at(buf, offset) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => {
+                    buf = &mut buf[n..];
+                    offset += n as u64;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads the header of the record pointed to by `cmd_ptr`.
+    ///
+    /// The length stored in the record header is checked against the length of `cmd_ptr`.
+    pub(crate) fn read_record_header(&self, cmd_ptr: CommandPointer) -> Result<RecordHeader> {
+        let mut header = [0; RecordHeader::LEN];
+        self.read_exact_at(&mut header, cmd_ptr.start() as u64)?;
+
+        let header = RecordHeader::decode(header);
+        if header.record_len() != cmd_ptr.len() || header.seq != cmd_ptr.seq() {
+            return Err(KvsError::Corruption {
+                gen: cmd_ptr.gen(),
+                offset: cmd_ptr.start(),
+            });
+        }
+
+        Ok(header)
+    }
+
+    /// Reads the payload of the record pointed to by `cmd_ptr` into `buf`, decompressed.
+    pub(crate) fn read_payload(&self, cmd_ptr: CommandPointer, buf: &mut Vec<u8>) -> Result<()> {
+        let header = self.read_record_header(cmd_ptr)?;
+
+        buf.resize(header.payload_len, 0);
+        self.read_exact_at(buf, (cmd_ptr.start() + RecordHeader::LEN) as u64)?;
+
+        if header.compressed {
+            decompress_payload(buf, cmd_ptr.gen(), cmd_ptr.start())?;
+        }
+
+        Ok(())
+    }
+
+    /// Creates a reader of the logfile starting at `offset`, for reading it sequentially.
+    pub(crate) fn cursor(&self, offset: u64) -> LogCursor<'_> {
+        LogCursor { log: self, offset }
+    }
+
+    #[cfg(unix)]
+    fn read_at_uncounted(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        std::os::unix::fs::FileExt::read_at(&self.file, buf, offset)
+    }
+
+    #[cfg(windows)]
+    fn read_at_uncounted(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        std::os::windows::fs::FileExt::seek_read(&self.file, buf, offset)
+    }
+
+    #[cfg(not(any(unix, windows)))]
+    fn read_at_uncounted(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let _cursor = self.cursor.lock().unwrap_or_else(|err| err.into_inner());
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(offset))?;
+        file.read(buf)
+    }
+}
+
+/// Reads a [`LogReader`] sequentially from its own position, which no other reader
+/// of the same logfile moves.
+#[derive(Debug)]
+pub(crate) struct LogCursor<'a> {
+    log: &'a LogReader,
+    offset: u64,
+}
+
+impl Read for LogCursor<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.log.read_at(buf, self.offset)?;
+        self.offset += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for LogCursor<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, delta) = match pos {
+            SeekFrom::Start(offset) => {
+                self.offset = offset;
+                return Ok(offset);
+            }
+            SeekFrom::Current(delta) => (self.offset, delta),
+            SeekFrom::End(delta) => (self.log.file.metadata()?.len(), delta),
+        };
+
+        self.offset = base.checked_add_signed(delta).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )
+        })?;
+        Ok(self.offset)
+    }
+}
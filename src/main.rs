@@ -4,6 +4,31 @@ use kvs::{KvStore, Result};
 fn main() -> Result<()> {
     let matches = args::get_cli_args();
 
+    // Repairing has to open the store on its own, as opening it as usual may fail.
+    if let ("repair", Some(args)) = matches.subcommand() {
+        let (mut store, report) = KvStore::repair("./data")?;
+
+        for truncated in &report.truncated {
+            println!(
+                "Truncated generation {} at offset {}, dropping {} bytes",
+                truncated.generation, truncated.offset, truncated.dropped_bytes
+            );
+        }
+        for key in &report.dropped_keys {
+            println!("Removed unreadable key {}", key);
+        }
+        if report.is_clean() {
+            println!("No corruption found");
+        }
+
+        if args.is_present("compact") {
+            let recovered = store.clean_stale_data()?;
+            println!("Recovered {} bytes", recovered);
+        }
+
+        return Ok(());
+    }
+
     let mut store = KvStore::open("./data")?;
 
     match matches.subcommand() {
@@ -31,6 +56,52 @@ fn main() -> Result<()> {
             let recovered = store.clean_stale_data()?;
             println!("Recovered {} bytes", recovered);
         }
+        ("stats", Some(args)) => {
+            let stats = store.stats()?;
+
+            if args.value_of("format") == Some("json") {
+                let stats = serde_json::json!({
+                    "live_keys": stats.live_keys,
+                    "stale_bytes": stats.stale_bytes,
+                    "current_generation": stats.current_generation,
+                    "generations": stats.generations,
+                    "disk_bytes": stats.disk_bytes,
+                });
+                println!("{}", stats);
+            } else {
+                println!("Live keys: {}", stats.live_keys);
+                println!("Stale bytes: {}", stats.stale_bytes);
+                println!("Current generation: {}", stats.current_generation);
+                println!("Generations: {}", stats.generations);
+                println!("Disk usage: {} bytes", stats.disk_bytes);
+            }
+        }
+        ("dump", Some(args)) => {
+            let gens = match args.value_of("generation") {
+                Some(gen) => vec![gen.parse().unwrap_or_else(|_| {
+                    eprintln!("Invalid generation: {}", gen);
+                    std::process::exit(1);
+                })],
+                None => store
+                    .generations()?
+                    .into_iter()
+                    .map(|(gen, _)| gen)
+                    .collect(),
+            };
+
+            for gen in gens {
+                for record in store.dump_generation(gen)? {
+                    let record = serde_json::json!({
+                        "generation": gen,
+                        "offset": record.offset,
+                        "seq": record.seq,
+                        "kind": record.kind.as_str(),
+                        "key": record.key,
+                    });
+                    println!("{}", record);
+                }
+            }
+        }
         _ => unreachable!(),
     };
 
@@ -0,0 +1,84 @@
+use crate::{KvStore, Result};
+
+/// A view of a [`KvStore`] that prepends a prefix to every key.
+///
+/// Keys are stored as the length of the prefix in bytes, a `:`, the prefix and then the
+/// key. Keeping the length in the stored key makes it unambiguous where the prefix ends,
+/// so views with different prefixes never see each other's keys, whatever the keys and
+/// prefixes contain. A key `b` under the prefix `a:` is stored as `2:a:b`, for example,
+/// and thus doesn't collide with the key `b:` under the prefix `a`, stored as `1:ab:`,
+/// nor with the key `a:b` outside of any view.
+///
+/// See [`KvStore::with_prefix`].
+///
+/// # Examples
+///
+/// ```rust no_run
+/// # use kvs::{Result, KvStore};
+/// # fn main() -> Result<()> {
+/// use std::env::current_dir;
+/// let mut store = KvStore::open(current_dir()?)?;
+///
+/// // Stored as `9:tenant-a/key`.
+/// store.with_prefix("tenant-a/").set("key", "value")?;
+/// assert_eq!(store.get("9:tenant-a/key")?, Some("value".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct PrefixedStore<'a> {
+    store: &'a mut KvStore,
+    prefix: String,
+}
+
+impl<'a> PrefixedStore<'a> {
+    pub(crate) fn new(store: &'a mut KvStore, prefix: &str) -> Self {
+        Self {
+            store,
+            prefix: prefix.to_owned(),
+        }
+    }
+
+    /// Gets the prefix prepended to every key.
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    /// Sets the given `key` under the prefix to provided `value`.
+    ///
+    /// # Errors
+    ///
+    /// See [`KvStore::set`].
+    pub fn set<K, V>(&mut self, key: K, value: V) -> Result<()>
+    where
+        K: Into<String>,
+        V: Into<String>,
+    {
+        let key = self.prefixed(key);
+        self.store.set(key, value)
+    }
+
+    /// Fetches the stored `value` of a given `key` under the prefix.
+    ///
+    /// # Errors
+    ///
+    /// See [`KvStore::get`].
+    pub fn get<K: Into<String>>(&mut self, key: K) -> Result<Option<String>> {
+        let key = self.prefixed(key);
+        self.store.get(key)
+    }
+
+    /// Removes a given `key` under the prefix, returning `true` if the key was saved.
+    ///
+    /// # Errors
+    ///
+    /// See [`KvStore::remove`].
+    pub fn remove<K: Into<String>>(&mut self, key: K) -> Result<bool> {
+        let key = self.prefixed(key);
+        self.store.remove(key)
+    }
+
+    fn prefixed<K: Into<String>>(&self, key: K) -> String {
+        format!("{}:{}{}", self.prefix.len(), self.prefix, key.into())
+    }
+}
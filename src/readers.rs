@@ -0,0 +1,131 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::{log_reader::LogReader, utils::LogNaming, IoCounters, KvsError, Result, StoreDir};
+
+/// Number of readers kept open unless configured otherwise.
+pub(crate) const DEFAULT_MAX_OPEN_READERS: usize = 1024;
+
+/// The generations of a store, with a reader of each one's logfile.
+///
+/// At most `max_open` readers are kept open at once. Once that many are, opening
+/// another one closes the least recently used, which is reopened when next needed.
+#[derive(Debug)]
+pub(crate) struct Readers {
+    dir: StoreDir,
+    naming: LogNaming,
+    io_counters: Arc<IoCounters>,
+    max_open: usize,
+    /// Every generation on disk, with its reader unless it is closed.
+    gens: HashMap<usize, Option<OpenReader>>,
+    open: usize,
+    clock: u64,
+}
+
+#[derive(Debug)]
+struct OpenReader {
+    reader: Arc<LogReader>,
+    last_used: u64,
+}
+
+impl Readers {
+    pub(crate) fn new(
+        dir: StoreDir,
+        naming: LogNaming,
+        io_counters: Arc<IoCounters>,
+        max_open: usize,
+    ) -> Self {
+        Self {
+            dir,
+            naming,
+            io_counters,
+            max_open: max_open.max(1),
+            gens: HashMap::new(),
+            open: 0,
+            clock: 0,
+        }
+    }
+
+    /// Adds a generation, whose reader is opened once it is first needed.
+    pub(crate) fn add(&mut self, gen: usize) {
+        self.gens.entry(gen).or_insert(None);
+    }
+
+    /// Adds a generation together with an already open reader of its logfile.
+    pub(crate) fn insert(&mut self, gen: usize, reader: Arc<LogReader>) {
+        if let Some(Some(_)) = self.gens.remove(&gen) {
+            self.open -= 1;
+        }
+
+        self.make_room();
+        self.clock += 1;
+        self.gens.insert(
+            gen,
+            Some(OpenReader {
+                reader,
+                last_used: self.clock,
+            }),
+        );
+        self.open += 1;
+    }
+
+    /// Gets the number of generations.
+    pub(crate) fn len(&self) -> usize {
+        self.gens.len()
+    }
+
+    /// Lists the generations in sorted order.
+    pub(crate) fn gens(&self) -> Vec<usize> {
+        let mut gens: Vec<usize> = self.gens.keys().copied().collect();
+        gens.sort_unstable();
+        gens
+    }
+
+    /// Removes every generation, closing their readers.
+    pub(crate) fn clear(&mut self) {
+        self.gens.clear();
+        self.open = 0;
+    }
+
+    /// Gets the reader of a given generation, reopening its logfile if it was closed.
+    pub(crate) fn get(&mut self, gen: usize) -> Result<&Arc<LogReader>> {
+        match self.gens.get(&gen) {
+            None => return Err(KvsError::MissingLogfile(gen)),
+            Some(Some(_)) => {}
+            Some(None) => {
+                let logfile = self.dir.open_read(&self.naming.file_name(gen))?;
+                let reader = LogReader::new(logfile, &self.io_counters);
+                self.insert(gen, Arc::new(reader));
+            }
+        }
+
+        self.clock += 1;
+        let open_reader = self
+            .gens
+            .get_mut(&gen)
+            .and_then(Option::as_mut)
+            .expect("reader was just opened");
+        open_reader.last_used = self.clock;
+
+        Ok(&open_reader.reader)
+    }
+
+    /// Closes the least recently used reader if no more can be opened.
+    fn make_room(&mut self) {
+        if self.open < self.max_open {
+            return;
+        }
+
+        let lru = self
+            .gens
+            .iter()
+            .filter_map(|(&gen, reader)| Some((gen, reader.as_ref()?.last_used)))
+            .min_by_key(|&(_, last_used)| last_used)
+            .map(|(gen, _)| gen);
+
+        if let Some(gen) = lru {
+            self.gens.insert(gen, None);
+            self.open -= 1;
+        }
+    }
+}
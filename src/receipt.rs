@@ -0,0 +1,14 @@
+/// Where a write was appended to the log and whether it is durable yet.
+///
+/// See [`KvStore::set_tracked`](crate::KvStore::set_tracked).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WriteReceipt {
+    /// Generation of the logfile holding the write.
+    pub generation: usize,
+    /// Byte offset of the write within its logfile.
+    pub offset: usize,
+    /// Whether the write was synced to disk according to the [`FlushPolicy`](crate::FlushPolicy).
+    ///
+    /// A write that isn't durable yet becomes durable after [`KvStore::sync`](crate::KvStore::sync).
+    pub durable: bool,
+}
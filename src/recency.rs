@@ -0,0 +1,62 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::utils::{Index, IndexHasher};
+
+/// Order in which the live keys of a store were last used, oldest first.
+///
+/// Used to pick the keys evicted under [`EvictionPolicy::LruEvict`](crate::EvictionPolicy::LruEvict).
+#[derive(Debug, Default)]
+pub(crate) struct Recency {
+    last_used: HashMap<String, u64, IndexHasher>,
+    by_age: BTreeMap<u64, String>,
+    clock: u64,
+}
+
+impl Recency {
+    /// Orders the keys of an index by when they were last written.
+    pub(crate) fn from_index(index: &Index) -> Self {
+        let mut keys: Vec<(&String, u64)> = index
+            .iter()
+            .map(|(key, cmd_ptr)| (key, cmd_ptr.seq()))
+            .collect();
+        keys.sort_unstable_by_key(|&(_, seq)| seq);
+
+        let mut recency = Self::default();
+        for (key, _) in keys {
+            recency.touch(key);
+        }
+        recency
+    }
+
+    /// Marks a key as the most recently used one.
+    pub(crate) fn touch(&mut self, key: &str) {
+        self.clock += 1;
+
+        match self.last_used.get_mut(key) {
+            Some(last_used) => {
+                let key = self.by_age.remove(last_used).expect("ages match keys");
+                *last_used = self.clock;
+                self.by_age.insert(self.clock, key);
+            }
+            None => {
+                self.last_used.insert(key.to_owned(), self.clock);
+                self.by_age.insert(self.clock, key.to_owned());
+            }
+        }
+    }
+
+    /// Forgets a removed key.
+    pub(crate) fn remove(&mut self, key: &str) {
+        if let Some(last_used) = self.last_used.remove(key) {
+            self.by_age.remove(&last_used);
+        }
+    }
+
+    /// Gets the least recently used key other than `except`.
+    pub(crate) fn oldest_except(&self, except: &str) -> Option<&str> {
+        self.by_age
+            .values()
+            .map(String::as_str)
+            .find(|&key| key != except)
+    }
+}
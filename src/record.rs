@@ -0,0 +1,32 @@
+/// A record stored in a logfile, as listed by
+/// [`KvStore::dump_generation`](crate::KvStore::dump_generation).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogRecord {
+    /// Byte offset of the record within its logfile.
+    pub offset: usize,
+    /// Sequence number of the write that produced the record.
+    pub seq: u64,
+    /// Whether the record sets or removes its key.
+    pub kind: RecordKind,
+    /// Key the record applies to.
+    pub key: String,
+}
+
+/// The operation a [`LogRecord`] stands for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RecordKind {
+    /// Sets the key to a value.
+    Set,
+    /// Removes the key.
+    Remove,
+}
+
+impl RecordKind {
+    /// Gets the name of the operation, as used by the command line interface.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Set => "set",
+            Self::Remove => "rm",
+        }
+    }
+}
@@ -0,0 +1,29 @@
+/// A logfile that was cut short while repairing a store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TruncatedLog {
+    /// Generation of the logfile.
+    pub generation: usize,
+    /// Offset of the first unreadable record, which the logfile now ends at.
+    pub offset: usize,
+    /// Number of bytes removed from the end of the logfile.
+    pub dropped_bytes: u64,
+}
+
+/// What was fixed while repairing a [`KvStore`](crate::KvStore).
+///
+/// See [`KvStore::repair`](crate::KvStore::repair).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RepairReport {
+    /// Logfiles that were truncated at their first unreadable record, in
+    /// generation order.
+    pub truncated: Vec<TruncatedLog>,
+    /// Keys that were removed because their value couldn't be read back.
+    pub dropped_keys: Vec<String>,
+}
+
+impl RepairReport {
+    /// Checks whether the store was found intact and nothing had to be fixed.
+    pub fn is_clean(&self) -> bool {
+        self.truncated.is_empty() && self.dropped_keys.is_empty()
+    }
+}
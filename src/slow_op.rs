@@ -0,0 +1,70 @@
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// An operation on a [`KvStore`](crate::KvStore) that can be reported as slow.
+///
+/// See [`KvStoreBuilder::on_slow_op`](crate::KvStoreBuilder::on_slow_op).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OpKind {
+    /// A [`get`](crate::KvStore::get).
+    Get,
+    /// A [`set`](crate::KvStore::set).
+    Set,
+    /// A [`remove`](crate::KvStore::remove).
+    Remove,
+    /// A [`clean_stale_data`](crate::KvStore::clean_stale_data), including one run
+    /// on its own by a write.
+    Compact,
+}
+
+/// Default duration an operation must take to be reported as slow.
+pub(crate) const DEFAULT_SLOW_OP_THRESHOLD: Duration = Duration::from_millis(100);
+
+/// Callback reporting operations that took at least as long as a threshold.
+#[derive(Clone)]
+pub(crate) struct SlowOpHook(Arc<dyn Fn(OpKind, Duration) + Send + Sync>);
+
+impl SlowOpHook {
+    pub(crate) fn new<F>(callback: F) -> Self
+    where
+        F: Fn(OpKind, Duration) + Send + Sync + 'static,
+    {
+        Self(Arc::new(callback))
+    }
+
+    /// Starts timing an operation, which is reported once the returned timer is dropped
+    /// if it took at least `threshold`.
+    pub(crate) fn start(&self, kind: OpKind, threshold: Duration) -> OpTimer {
+        OpTimer {
+            hook: self.clone(),
+            kind,
+            threshold,
+            started: Instant::now(),
+        }
+    }
+}
+
+impl fmt::Debug for SlowOpHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("SlowOpHook").finish_non_exhaustive()
+    }
+}
+
+/// Times an operation from its creation until it is dropped, including on early return.
+#[derive(Debug)]
+pub(crate) struct OpTimer {
+    hook: SlowOpHook,
+    kind: OpKind,
+    threshold: Duration,
+    started: Instant,
+}
+
+impl Drop for OpTimer {
+    fn drop(&mut self) {
+        let elapsed = self.started.elapsed();
+        if elapsed >= self.threshold {
+            (self.hook.0)(self.kind, elapsed);
+        }
+    }
+}
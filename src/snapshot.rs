@@ -0,0 +1,52 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::{log_reader::LogReader, utils::Index, KvsError, Result, SetValue};
+
+/// A read-only view of a [`KvStore`](crate::KvStore) as it was when the view was taken.
+///
+/// Reads only need `&self`, so a single view can be shared between threads, which read
+/// the same logfiles concurrently. Later writes to the store aren't visible through it.
+///
+/// See [`KvStore::read_snapshot`](crate::KvStore::read_snapshot).
+#[derive(Debug, Clone)]
+pub struct ReadSnapshot {
+    pub(crate) index: Arc<Index>,
+    pub(crate) logs: Arc<HashMap<usize, Arc<LogReader>>>,
+}
+
+impl ReadSnapshot {
+    /// Fetches the stored `value` of a given `key`.
+    ///
+    /// Returns [`None`] if the key did not exist when the view was taken.
+    ///
+    /// # Errors
+    ///
+    /// This function propagates deserialization and I/O errors that could arise while
+    /// reading the log.
+    pub fn get(&self, key: &str) -> Result<Option<String>> {
+        let cmd_ptr = match self.index.get(key) {
+            Some(cmd_ptr) => *cmd_ptr,
+            None => return Ok(None),
+        };
+        let logfile = self
+            .logs
+            .get(&cmd_ptr.gen())
+            .ok_or(KvsError::MissingLogfile(cmd_ptr.gen()))?;
+
+        let mut payload = Vec::new();
+        logfile.read_payload(cmd_ptr, &mut payload)?;
+
+        SetValue::parse(&payload).map(|value| Some(value.into_owned()))
+    }
+
+    /// Gets the number of keys in the view.
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    /// Checks whether the view holds no keys.
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+}
@@ -0,0 +1,16 @@
+/// A snapshot of the state of a [`KvStore`](crate::KvStore).
+///
+/// See [`KvStore::stats`](crate::KvStore::stats).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    /// Number of keys in the store.
+    pub live_keys: usize,
+    /// Bytes of stale records compaction would remove.
+    pub stale_bytes: u64,
+    /// Generation new writes are appended to.
+    pub current_generation: usize,
+    /// Number of generations on disk.
+    pub generations: usize,
+    /// Total size of all logfiles in bytes.
+    pub disk_bytes: u64,
+}
@@ -0,0 +1,258 @@
+use std::ffi::OsStr;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+#[cfg(all(feature = "dir-handle", unix))]
+use std::{os::unix::io::OwnedFd, sync::Arc};
+
+/// The directory holding the files of a store.
+///
+/// Files are named relative to the directory. A directory opened from a path reaches
+/// them by joining their names to that path, while one opened from a directory handle
+/// reaches them relative to the handle, wherever the directory it refers to is.
+#[derive(Debug, Clone)]
+pub(crate) struct StoreDir {
+    path: PathBuf,
+    #[cfg(all(feature = "dir-handle", unix))]
+    handle: Option<Arc<OwnedFd>>,
+}
+
+impl StoreDir {
+    /// Refers to the directory at `path`.
+    pub(crate) fn new<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            #[cfg(all(feature = "dir-handle", unix))]
+            handle: None,
+        }
+    }
+
+    /// Refers to the directory an open handle `dir` refers to, which was opened from
+    /// `path`. The path is only reported back, files are all reached through the handle.
+    ///
+    /// This fails with an [`io::ErrorKind::Unsupported`] error unless the `dir-handle`
+    /// feature is enabled on a Unix platform.
+    #[cfg(all(feature = "dir-handle", unix))]
+    pub(crate) fn from_handle<P: AsRef<Path>>(dir: File, path: P) -> io::Result<Self> {
+        Ok(Self {
+            path: path.as_ref().to_path_buf(),
+            handle: Some(Arc::new(dir.into())),
+        })
+    }
+
+    /// Refers to the directory an open handle `dir` refers to, which was opened from
+    /// `path`. The path is only reported back, files are all reached through the handle.
+    ///
+    /// This fails with an [`io::ErrorKind::Unsupported`] error unless the `dir-handle`
+    /// feature is enabled on a Unix platform.
+    #[cfg(not(all(feature = "dir-handle", unix)))]
+    pub(crate) fn from_handle<P: AsRef<Path>>(_dir: File, _path: P) -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "directory handles require the `dir-handle` feature on a Unix platform",
+        ))
+    }
+
+    /// Gets the path of the directory.
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Opens the file `name` for reading.
+    pub(crate) fn open_read(&self, name: &str) -> io::Result<File> {
+        #[cfg(all(feature = "dir-handle", unix))]
+        if let Some(handle) = &self.handle {
+            return at::open(handle, name, at::OFlags::RDONLY);
+        }
+
+        File::open(self.path.join(name))
+    }
+
+    /// Opens the existing file `name` for writing.
+    pub(crate) fn open_write(&self, name: &str) -> io::Result<File> {
+        #[cfg(all(feature = "dir-handle", unix))]
+        if let Some(handle) = &self.handle {
+            return at::open(handle, name, at::OFlags::WRONLY);
+        }
+
+        OpenOptions::new().write(true).open(self.path.join(name))
+    }
+
+    /// Opens the file `name` for reading and writing, creating it if it doesn't exist.
+    pub(crate) fn open_or_create(&self, name: &str) -> io::Result<File> {
+        #[cfg(all(feature = "dir-handle", unix))]
+        if let Some(handle) = &self.handle {
+            return at::open(handle, name, at::OFlags::RDWR | at::OFlags::CREATE);
+        }
+
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(self.path.join(name))
+    }
+
+    /// Creates the file `name` for writing, truncating it if it exists.
+    pub(crate) fn create(&self, name: &str) -> io::Result<File> {
+        #[cfg(all(feature = "dir-handle", unix))]
+        if let Some(handle) = &self.handle {
+            let flags = at::OFlags::WRONLY | at::OFlags::CREATE | at::OFlags::TRUNC;
+            return at::open(handle, name, flags);
+        }
+
+        File::create(self.path.join(name))
+    }
+
+    /// Reads the whole contents of the file `name`.
+    pub(crate) fn read(&self, name: &str) -> io::Result<Vec<u8>> {
+        let mut contents = Vec::new();
+        self.open_read(name)?.read_to_end(&mut contents)?;
+        Ok(contents)
+    }
+
+    /// Checks whether `name` is a regular file, following symbolic links.
+    pub(crate) fn is_file(&self, name: &str) -> bool {
+        #[cfg(all(feature = "dir-handle", unix))]
+        if let Some(handle) = &self.handle {
+            return at::is_file(handle, name);
+        }
+
+        self.path.join(name).is_file()
+    }
+
+    /// Gets the size of the file `name`.
+    pub(crate) fn file_len(&self, name: &str) -> io::Result<u64> {
+        #[cfg(all(feature = "dir-handle", unix))]
+        if let Some(handle) = &self.handle {
+            return at::file_len(handle, name);
+        }
+
+        Ok(fs::metadata(self.path.join(name))?.len())
+    }
+
+    /// Lists the names of the regular files in the directory, in no particular order.
+    ///
+    /// Names that aren't valid UTF-8 are left out, while errors reading the entries of
+    /// the directory are propagated.
+    pub(crate) fn file_names(&self) -> io::Result<Vec<String>> {
+        #[cfg(all(feature = "dir-handle", unix))]
+        if let Some(handle) = &self.handle {
+            return at::file_names(handle);
+        }
+
+        let entries = fs::read_dir(&self.path)?.collect::<io::Result<Vec<_>>>()?;
+
+        Ok(entries
+            .into_iter()
+            .map(|entry| entry.path())
+            .filter(|path| path.is_file())
+            .filter_map(|path| path.file_name().and_then(OsStr::to_str).map(str::to_owned))
+            .collect())
+    }
+
+    /// Removes the file `name`.
+    pub(crate) fn remove_file(&self, name: &str) -> io::Result<()> {
+        #[cfg(all(feature = "dir-handle", unix))]
+        if let Some(handle) = &self.handle {
+            return at::remove_file(handle, name);
+        }
+
+        fs::remove_file(self.path.join(name))
+    }
+
+    /// Renames the file `from` to `to`, replacing any file already there.
+    pub(crate) fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        self.rename_into(from, self, to)
+    }
+
+    /// Moves the file `from` to `to` in the directory `dest`, replacing any file
+    /// already there. This fails if `dest` is on another filesystem.
+    pub(crate) fn rename_into(&self, from: &str, dest: &StoreDir, to: &str) -> io::Result<()> {
+        #[cfg(all(feature = "dir-handle", unix))]
+        if self.handle.is_some() || dest.handle.is_some() {
+            let (from_dir, from) = self.at(from);
+            let (to_dir, to) = dest.at(to);
+            return Ok(rustix::fs::renameat(from_dir, &from, to_dir, &to)?);
+        }
+
+        fs::rename(self.path.join(from), dest.path.join(to))
+    }
+
+    /// Syncs the directory, making the creation, renaming and removal of its entries
+    /// durable.
+    ///
+    /// Directories can't be opened as files on Windows, where this does nothing.
+    pub(crate) fn sync(&self) -> io::Result<()> {
+        #[cfg(all(feature = "dir-handle", unix))]
+        if let Some(handle) = &self.handle {
+            return Ok(rustix::fs::fsync(handle)?);
+        }
+
+        #[cfg(unix)]
+        File::open(&self.path)?.sync_all()?;
+
+        Ok(())
+    }
+
+    /// Gets the directory handle and path `name` is reached through, which is relative
+    /// to the current directory if the directory has no handle.
+    #[cfg(all(feature = "dir-handle", unix))]
+    fn at(&self, name: &str) -> (std::os::unix::io::BorrowedFd<'_>, PathBuf) {
+        use std::os::unix::io::AsFd;
+
+        match &self.handle {
+            Some(handle) => (handle.as_fd(), PathBuf::from(name)),
+            None => (rustix::fs::CWD, self.path.join(name)),
+        }
+    }
+}
+
+/// File operations relative to a directory handle.
+#[cfg(all(feature = "dir-handle", unix))]
+mod at {
+    use std::fs::File;
+    use std::io;
+    use std::os::unix::io::OwnedFd;
+
+    pub(super) use rustix::fs::OFlags;
+    use rustix::fs::{AtFlags, Dir, FileType, Mode};
+
+    /// Permissions of created files before the umask is applied, the same as the
+    /// standard library uses.
+    const CREATE_MODE: u32 = 0o666;
+
+    pub(super) fn open(dir: &OwnedFd, name: &str, flags: OFlags) -> io::Result<File> {
+        let mode = Mode::from_raw_mode(CREATE_MODE);
+        let fd = rustix::fs::openat(dir, name, flags | OFlags::CLOEXEC, mode)?;
+        Ok(File::from(fd))
+    }
+
+    pub(super) fn is_file(dir: &OwnedFd, name: &str) -> bool {
+        rustix::fs::statat(dir, name, AtFlags::empty())
+            .is_ok_and(|stat| FileType::from_raw_mode(stat.st_mode).is_file())
+    }
+
+    pub(super) fn file_len(dir: &OwnedFd, name: &str) -> io::Result<u64> {
+        let stat = rustix::fs::statat(dir, name, AtFlags::empty())?;
+        Ok(stat.st_size as u64)
+    }
+
+    pub(super) fn file_names(dir: &OwnedFd) -> io::Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in Dir::read_from(dir)? {
+            let entry = entry?;
+            if let Ok(name) = entry.file_name().to_str() {
+                if is_file(dir, name) {
+                    names.push(name.to_owned());
+                }
+            }
+        }
+
+        Ok(names)
+    }
+
+    pub(super) fn remove_file(dir: &OwnedFd, name: &str) -> io::Result<()> {
+        Ok(rustix::fs::unlinkat(dir, name, AtFlags::empty())?)
+    }
+}
@@ -0,0 +1,99 @@
+use std::collections::HashMap;
+
+use crate::{KvStore, Result, WriteBatch};
+
+/// Changes to a [`KvStore`] that are staged in memory until they are committed.
+///
+/// Reads through the transaction see its own staged changes first, then the store.
+/// Nothing is written to the log before [`commit`](Self::commit). Dropping
+/// a transaction without committing it discards its changes.
+///
+/// See [`KvStore::begin`].
+///
+/// # Examples
+///
+/// ```rust no_run
+/// # use kvs::{Result, KvStore};
+/// # fn main() -> Result<()> {
+/// use std::env::current_dir;
+/// let mut store = KvStore::open(current_dir()?)?;
+///
+/// let mut transaction = store.begin();
+/// transaction.set("key1", "value1");
+/// assert_eq!(transaction.get("key1")?, Some("value1".to_owned()));
+/// transaction.commit()?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct Transaction<'a> {
+    store: &'a mut KvStore,
+    /// Staged values by key, where [`None`] stands for a removed key.
+    overlay: HashMap<String, Option<String>>,
+}
+
+impl<'a> Transaction<'a> {
+    pub(crate) fn new(store: &'a mut KvStore) -> Self {
+        Self {
+            store,
+            overlay: HashMap::new(),
+        }
+    }
+
+    /// Stages setting the given `key` to provided `value`.
+    pub fn set<K, V>(&mut self, key: K, value: V)
+    where
+        K: Into<String>,
+        V: Into<String>,
+    {
+        self.overlay.insert(key.into(), Some(value.into()));
+    }
+
+    /// Fetches the value of a given `key`, as staged in this transaction or stored.
+    ///
+    /// # Errors
+    ///
+    /// See [`KvStore::get`].
+    pub fn get<K: Into<String>>(&mut self, key: K) -> Result<Option<String>> {
+        let key = key.into();
+
+        match self.overlay.get(&key) {
+            Some(value) => Ok(value.clone()),
+            None => self.store.get(key),
+        }
+    }
+
+    /// Stages removing a given `key`, returning `true` if the key existed
+    /// for this transaction.
+    pub fn remove<K: Into<String>>(&mut self, key: K) -> bool {
+        let key = key.into();
+
+        let existed = match self.overlay.get(&key) {
+            Some(value) => value.is_some(),
+            None => self.store.contains_key(&key),
+        };
+
+        self.overlay.insert(key, None);
+        existed
+    }
+
+    /// Writes all staged changes to the store as a single [`WriteBatch`].
+    ///
+    /// # Errors
+    ///
+    /// See [`KvStore::write_batch`].
+    pub fn commit(self) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        for (key, value) in self.overlay {
+            match value {
+                Some(value) => batch.set(key, value),
+                None => batch.remove(key),
+            };
+        }
+
+        self.store.write_batch(batch)
+    }
+
+    /// Discards all staged changes.
+    pub fn rollback(self) {}
+}
@@ -1,84 +1,821 @@
 use std::collections::HashMap;
-use std::ffi::OsStr;
-use std::fs::{self, File, OpenOptions};
-use std::io::{Read, Seek};
-use std::num::ParseIntError;
-use std::path::{Path, PathBuf};
-
-use serde_json::Deserializer;
-
-use crate::{Command, CommandPointer, Result};
-
-/// Fetches all previous generations at a given path in sorted order.
-pub(crate) fn get_generation_list<P: AsRef<Path>>(path: P) -> Result<Vec<usize>> {
-    let mut generations: Vec<usize> = fs::read_dir(path)?
-        .flat_map(|entry| -> Result<_> { Ok(entry?.path()) })
-        .filter(|path| path.is_file() && path.extension() == Some(OsStr::new("log")))
-        .flat_map(|path| {
-            path.file_name()
-                .and_then(OsStr::to_str)
-                .map(|s| s.trim_end_matches(".log"))
-                .map(str::parse::<usize>)
-        })
-        .filter_map(|gens: std::result::Result<usize, ParseIntError>| gens.ok())
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    store_dir::StoreDir, Command, CommandPointer, Counted, KvsError, LogRecord, RecordKind,
+    ReplayCommand, Result,
+};
+
+/// Hasher of the in-memory index.
+#[cfg(not(feature = "fxhash"))]
+pub(crate) type IndexHasher = std::collections::hash_map::RandomState;
+/// Hasher of the in-memory index.
+#[cfg(feature = "fxhash")]
+pub(crate) type IndexHasher = std::hash::BuildHasherDefault<FxHasher>;
+
+/// The multiply-rotate hasher used by `rustc`, hashing a word at a time.
+///
+/// It is much faster than the standard library's SipHash for the short keys typical of
+/// a store, but is unkeyed, and so doesn't resist collisions crafted by an attacker.
+#[cfg(feature = "fxhash")]
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct FxHasher {
+    hash: u64,
+}
+
+#[cfg(feature = "fxhash")]
+impl FxHasher {
+    const SEED: u64 = 0x51_7c_c1_b7_27_22_0a_95;
+
+    fn add_to_hash(&mut self, word: u64) {
+        self.hash = (self.hash.rotate_left(5) ^ word).wrapping_mul(Self::SEED);
+    }
+}
+
+#[cfg(feature = "fxhash")]
+impl std::hash::Hasher for FxHasher {
+    fn write(&mut self, mut bytes: &[u8]) {
+        use std::convert::TryInto;
+
+        while bytes.len() >= 8 {
+            let (word, rest) = bytes.split_at(8);
+            self.add_to_hash(u64::from_le_bytes(word.try_into().expect("8 bytes")));
+            bytes = rest;
+        }
+        if bytes.len() >= 4 {
+            let (word, rest) = bytes.split_at(4);
+            self.add_to_hash(u32::from_le_bytes(word.try_into().expect("4 bytes")).into());
+            bytes = rest;
+        }
+        for &byte in bytes {
+            self.add_to_hash(byte.into());
+        }
+    }
+
+    fn write_u8(&mut self, i: u8) {
+        self.add_to_hash(i.into());
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        self.add_to_hash(i as u64);
+    }
+
+    fn finish(&self) -> u64 {
+        self.hash
+    }
+}
+
+/// In-memory index of the location of every live key's latest record.
+pub(crate) type Index = HashMap<String, CommandPointer, IndexHasher>;
+
+/// Describes how logfiles are named after their generation number.
+#[derive(Debug, Clone)]
+pub(crate) struct LogNaming {
+    pub(crate) prefix: String,
+    pub(crate) extension: String,
+}
+
+impl Default for LogNaming {
+    fn default() -> Self {
+        Self {
+            prefix: String::new(),
+            extension: "log".to_owned(),
+        }
+    }
+}
+
+impl LogNaming {
+    /// Gets the logfile name of a given generation.
+    pub(crate) fn file_name(&self, gen: usize) -> String {
+        if self.extension.is_empty() {
+            format!("{}{}", self.prefix, gen)
+        } else {
+            format!("{}{}.{}", self.prefix, gen, self.extension)
+        }
+    }
+
+    /// Extracts the generation number from a logfile name, if it matches this scheme.
+    pub(crate) fn parse(&self, file_name: &str) -> Option<usize> {
+        let gen = file_name.strip_prefix(self.prefix.as_str())?;
+        let gen = if self.extension.is_empty() {
+            gen
+        } else {
+            gen.strip_suffix(self.extension.as_str())?
+                .strip_suffix('.')?
+        };
+
+        gen.parse().ok()
+    }
+}
+
+/// Fetches all previous generations of a store directory in sorted order.
+///
+/// Errors encountered while reading directory entries are propagated rather than
+/// skipped, so that no existing generation can be silently left out. For the same
+/// reason, several logfile names parsing to the same generation, such as `1.log` and
+/// `01.log`, fail with [`KvsError::AmbiguousGeneration`] instead of picking one.
+pub(crate) fn get_generation_list(dir: &StoreDir, naming: &LogNaming) -> Result<Vec<usize>> {
+    let mut generations: Vec<usize> = dir
+        .file_names()?
+        .iter()
+        .filter_map(|name| naming.parse(name))
         .collect();
 
     generations.sort_unstable();
+    if let Some(pair) = generations.windows(2).find(|pair| pair[0] == pair[1]) {
+        return Err(KvsError::AmbiguousGeneration(pair[0]));
+    }
+
     Ok(generations)
 }
 
-pub(crate) fn logfile_path<P: AsRef<Path>>(path: P, gen: usize) -> PathBuf {
-    path.as_ref().join(format!("{}.log", gen))
+/// Removes sorted stale generations, except for the `retain` most recent ones, which
+/// are returned.
+///
+/// Removing the oldest generations first keeps the remaining ones contiguous if
+/// removal is interrupted.
+pub(crate) fn prune_generations(
+    dir: &StoreDir,
+    naming: &LogNaming,
+    stale_gens: &[usize],
+    retain: usize,
+) -> Result<Vec<usize>> {
+    let removed = stale_gens.len().saturating_sub(retain);
+    if removed == 0 {
+        return Ok(stale_gens.to_vec());
+    }
+
+    for &gen in &stale_gens[..removed] {
+        dir.remove_file(&naming.file_name(gen))?;
+    }
+    dir.sync()?;
+
+    Ok(stale_gens[removed..].to_vec())
+}
+
+/// Gets the name of the temporary logfile a compaction into a given generation writes to.
+///
+/// The name never matches the naming scheme, so the file is not mistaken for a generation.
+pub(crate) fn temp_logfile_name(naming: &LogNaming, gen: usize) -> String {
+    format!("{}.compacting", naming.file_name(gen))
+}
+
+/// Contents of the marker file every store directory holds.
+const MARKER: &[u8] = b"kvs 1\n";
+
+fn marker_name(naming: &LogNaming) -> String {
+    format!("{}KVSTORE", naming.prefix)
+}
+
+/// Checks whether a store directory holds the marker file of a store.
+pub(crate) fn has_marker(dir: &StoreDir, naming: &LogNaming) -> bool {
+    dir.is_file(&marker_name(naming))
+}
+
+/// Writes the marker file of a store to a store directory, unless it has one.
+pub(crate) fn write_marker(dir: &StoreDir, naming: &LogNaming) -> Result<()> {
+    if has_marker(dir, naming) {
+        return Ok(());
+    }
+
+    let mut file = dir.create(&marker_name(naming))?;
+    file.write_all(MARKER)?;
+    file.sync_all()?;
+    Ok(dir.sync()?)
+}
+
+/// Records which generations of a store are authoritative.
+///
+/// Compaction updates the manifest before removing the generations it replaced, so
+/// that any of them left behind by a crash are recognized as orphaned when opening.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Manifest {
+    /// The oldest authoritative generation, all later ones are authoritative as well.
+    pub(crate) first_gen: usize,
+}
+
+fn manifest_name(naming: &LogNaming) -> String {
+    format!("{}MANIFEST", naming.prefix)
+}
+
+/// Reads the manifest of the store in a store directory, if it has one.
+pub(crate) fn read_manifest(dir: &StoreDir, naming: &LogNaming) -> Result<Option<Manifest>> {
+    match dir.read(&manifest_name(naming)) {
+        Ok(contents) => Ok(Some(serde_json::from_slice(&contents)?)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Atomically replaces the manifest of the store in a store directory.
+pub(crate) fn write_manifest(dir: &StoreDir, naming: &LogNaming, manifest: Manifest) -> Result<()> {
+    let manifest_name = manifest_name(naming);
+    let temp_name = format!("{}.tmp", manifest_name);
+
+    let mut file = dir.create(&temp_name)?;
+    serde_json::to_writer(&mut file, &manifest)?;
+    file.sync_all()?;
+
+    dir.rename(&temp_name, &manifest_name)?;
+    Ok(dir.sync()?)
+}
+
+/// Records up to which offset the logfile of a generation was last synced to disk.
+///
+/// Records before the offset are durable, so only records past it can have been torn
+/// by a crash. [`KvStore::sync`](crate::KvStore::sync) updates the marker, and so does
+/// compaction once the compacted logfile is synced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct SyncMarker {
+    /// Generation of the synced logfile.
+    pub(crate) gen: usize,
+    /// Offset right past the last synced record.
+    pub(crate) offset: usize,
+}
+
+fn sync_marker_name(naming: &LogNaming) -> String {
+    format!("{}SYNCED", naming.prefix)
+}
+
+/// Reads the sync marker of the store in a store directory, if it has a readable one.
+///
+/// The marker itself isn't synced, so a crash may leave it empty. Such a marker is
+/// ignored, as if there was none.
+pub(crate) fn read_sync_marker(dir: &StoreDir, naming: &LogNaming) -> Result<Option<SyncMarker>> {
+    match dir.read(&sync_marker_name(naming)) {
+        Ok(contents) => Ok(serde_json::from_slice(&contents).ok()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Replaces the sync marker of the store in a store directory.
+///
+/// The marker must only be written once the logfile is synced up to its offset.
+pub(crate) fn write_sync_marker(
+    dir: &StoreDir,
+    naming: &LogNaming,
+    marker: SyncMarker,
+) -> Result<()> {
+    let marker_name = sync_marker_name(naming);
+    let temp_name = format!("{}.tmp", marker_name);
+
+    dir.create(&temp_name)?
+        .write_all(&serde_json::to_vec(&marker)?)?;
+    dir.rename(&temp_name, &marker_name)?;
+
+    Ok(())
+}
+
+/// Moves the file `src` of `src_dir` to `dest` in `dest_dir`, which replaces any file
+/// already there atomically.
+///
+/// Renaming fails across filesystems, in which case `src` is copied to `staging` in
+/// `dest_dir` first, then synced and renamed to `dest`.
+pub(crate) fn move_file(
+    src_dir: &StoreDir,
+    src: &str,
+    dest_dir: &StoreDir,
+    staging: &str,
+    dest: &str,
+) -> Result<()> {
+    match src_dir.rename_into(src, dest_dir, dest) {
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            let mut staging_file = dest_dir.create(staging)?;
+            io::copy(&mut src_dir.open_read(src)?, &mut staging_file)?;
+            staging_file.sync_all()?;
+            dest_dir.rename(staging, dest)?;
+            src_dir.remove_file(src)?;
+            Ok(())
+        }
+        result => Ok(result?),
+    }
 }
 
-/// Opens logfile for read/append operations at given path with given generation number.
+/// Opens logfile for read/write operations in a store directory with given generation number.
 ///
 /// This function will create a new logfile, if one matching the generation does not already exist.
-pub(crate) fn get_logfile<P: AsRef<Path>>(path: P, gen: usize) -> Result<File> {
-    let new_path = logfile_path(path, gen);
+pub(crate) fn get_logfile(dir: &StoreDir, naming: &LogNaming, gen: usize) -> Result<File> {
+    Ok(dir.open_or_create(&naming.file_name(gen))?)
+}
+
+/// Version of the logfile format written by this build.
+///
+/// Version 2 logfiles may contain compressed records. They are written by builds with
+/// the `compression` feature, which can also read version 1 logfiles.
+#[cfg(not(feature = "compression"))]
+pub(crate) const FORMAT_VERSION: u16 = 1;
+#[cfg(feature = "compression")]
+pub(crate) const FORMAT_VERSION: u16 = 2;
+
+/// The header at the start of every logfile, identifying its format.
+///
+/// It holds a magic number, the format version as a little-endian `u16`, the codec
+/// records are serialized with and a byte reserved for future use, written as zero.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct FileHeader {
+    pub(crate) version: u16,
+    pub(crate) codec: u8,
+}
+
+/// Codec of records serialized as JSON, the only one supported so far.
+///
+/// Logfiles written before the codec was recorded hold a zero in its place, so they
+/// are read as JSON as well.
+pub(crate) const JSON_CODEC: u8 = 0;
+
+impl FileHeader {
+    /// Length of an encoded header.
+    pub(crate) const LEN: usize = 8;
+
+    const MAGIC: [u8; 4] = *b"KVSL";
+
+    /// Gets the header of logfiles written by this build.
+    pub(crate) fn current() -> Self {
+        Self {
+            version: FORMAT_VERSION,
+            codec: JSON_CODEC,
+        }
+    }
+
+    pub(crate) fn encode(&self) -> [u8; Self::LEN] {
+        let mut bytes = [0; Self::LEN];
+        bytes[..4].copy_from_slice(&Self::MAGIC);
+        bytes[4..6].copy_from_slice(&self.version.to_le_bytes());
+        bytes[6] = self.codec;
+        bytes
+    }
+
+    /// Decodes a header, returning [`None`] if it doesn't start with the magic number.
+    pub(crate) fn decode(bytes: [u8; Self::LEN]) -> Option<Self> {
+        if bytes[..4] != Self::MAGIC {
+            return None;
+        }
+
+        Some(Self {
+            version: u16::from_le_bytes([bytes[4], bytes[5]]),
+            codec: bytes[6],
+        })
+    }
+}
+
+/// Wraps a logfile in a buffered writer that appends records at offset `end`.
+///
+/// If the logfile is empty, the file header is written to the buffer first and `end`
+/// is ignored. Otherwise, `end` is where its last record ends, which may be before the
+/// end of the file if space was preallocated.
+pub(crate) fn logfile_writer(
+    mut file: Counted<File>,
+    end: usize,
+) -> Result<BufWriter<Counted<File>>> {
+    let is_empty = file.get_ref().metadata()?.len() == 0;
+    if !is_empty {
+        file.seek(SeekFrom::Start(end as u64))?;
+    }
+
+    let mut writer = BufWriter::new(file);
+    if is_empty {
+        writer.write_all(&FileHeader::current().encode())?;
+    }
 
-    Ok(OpenOptions::new()
-        .read(true)
-        .append(true)
-        .create(true)
-        .open(new_path)?)
+    Ok(writer)
+}
+
+/// Reads and validates the file header of a logfile of a given generation,
+/// returning the format version of the logfile.
+///
+/// Returns [`None`] if the logfile is empty and thus has no header yet.
+pub(crate) fn check_file_header<R: Read>(mut logfile: R, gen: usize) -> Result<Option<u16>> {
+    let mut header = [0; FileHeader::LEN];
+    let mut filled = 0;
+
+    while filled < header.len() {
+        match logfile.read(&mut header[filled..]) {
+            Ok(0) if filled == 0 => return Ok(None),
+            Ok(0) => return Err(KvsError::Corruption { gen, offset: 0 }),
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    match FileHeader::decode(header) {
+        Some(FileHeader { version, .. }) if !(1..=FORMAT_VERSION).contains(&version) => {
+            Err(KvsError::UnsupportedFormat {
+                found: version,
+                expected: FORMAT_VERSION,
+            })
+        }
+        Some(FileHeader { codec, .. }) if codec != JSON_CODEC => {
+            Err(KvsError::UnsupportedCodec(codec))
+        }
+        Some(FileHeader { version, .. }) => Ok(Some(version)),
+        None => Err(KvsError::Corruption { gen, offset: 0 }),
+    }
+}
+
+/// The header preceding every record in a logfile.
+///
+/// It holds the length of the serialized command that follows it, as a little-endian
+/// `u32`, and the sequence number of the write, as a little-endian `u64`. The highest
+/// bit of the length is set if the serialized command is compressed.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RecordHeader {
+    pub(crate) payload_len: usize,
+    pub(crate) seq: u64,
+    pub(crate) compressed: bool,
+}
+
+impl RecordHeader {
+    /// Length of an encoded header.
+    pub(crate) const LEN: usize = 12;
+
+    const COMPRESSED: u32 = 1 << 31;
+
+    pub(crate) fn encode(&self) -> [u8; Self::LEN] {
+        let mut length = self.payload_len as u32;
+        if self.compressed {
+            length |= Self::COMPRESSED;
+        }
+
+        let mut bytes = [0; Self::LEN];
+        bytes[..4].copy_from_slice(&length.to_le_bytes());
+        bytes[4..].copy_from_slice(&self.seq.to_le_bytes());
+        bytes
+    }
+
+    pub(crate) fn decode(bytes: [u8; Self::LEN]) -> Self {
+        let mut length = [0; 4];
+        let mut seq = [0; 8];
+        length.copy_from_slice(&bytes[..4]);
+        seq.copy_from_slice(&bytes[4..]);
+
+        let length = u32::from_le_bytes(length);
+
+        Self {
+            payload_len: (length & !Self::COMPRESSED) as usize,
+            seq: u64::from_le_bytes(seq),
+            compressed: length & Self::COMPRESSED != 0,
+        }
+    }
+
+    /// Gets the length of the whole record, header included.
+    pub(crate) fn record_len(&self) -> usize {
+        Self::LEN + self.payload_len
+    }
+}
+
+/// Serializes `command` and writes it as a single record, returning the record's length.
+///
+/// The serialized command is compressed if `compress` is set.
+pub(crate) fn write_record<W: Write>(
+    writer: W,
+    seq: u64,
+    command: &Command,
+    compress: bool,
+) -> Result<usize> {
+    let payload = encode_payload(command, compress)?;
+    write_payload(writer, seq, &payload, compress)
+}
+
+/// Serializes `command` into the payload of a record, compressing it if `compress` is set.
+pub(crate) fn encode_payload(command: &Command, compress: bool) -> Result<Vec<u8>> {
+    let payload = serde_json::to_vec(command)?;
+
+    #[cfg(feature = "compression")]
+    let payload = if compress {
+        lz4_flex::compress_prepend_size(&payload)
+    } else {
+        payload
+    };
+    #[cfg(not(feature = "compression"))]
+    debug_assert!(!compress, "compression requires the `compression` feature");
+
+    Ok(payload)
+}
+
+/// Writes an encoded `payload` as a single record, returning the record's length.
+pub(crate) fn write_payload<W: Write>(
+    mut writer: W,
+    seq: u64,
+    payload: &[u8],
+    compressed: bool,
+) -> Result<usize> {
+    let header = RecordHeader {
+        payload_len: payload.len(),
+        seq,
+        compressed,
+    };
+
+    writer.write_all(&header.encode())?;
+    writer.write_all(payload)?;
+
+    Ok(header.record_len())
+}
+
+/// Reads the payload of the next record, at `offset` of the logfile of generation `gen`,
+/// into `buf`, returning the record's header.
+///
+/// Returns [`None`] if the logfile ends right before the next record, or if the next
+/// header is zeroed, which marks space preallocated by [`KvStore::preallocate`](crate::KvStore::preallocate).
+/// A header claiming a payload that runs past `log_len`, the length of the logfile,
+/// fails with [`KvsError::Corruption`] before the payload is allocated.
+pub(crate) fn read_record<R: Read>(
+    mut reader: R,
+    buf: &mut Vec<u8>,
+    gen: usize,
+    offset: usize,
+    log_len: u64,
+) -> Result<Option<RecordHeader>> {
+    let mut header = [0; RecordHeader::LEN];
+    let mut filled = 0;
+
+    while filled < header.len() {
+        match reader.read(&mut header[filled..]) {
+            Ok(0) if filled == 0 => return Ok(None),
+            Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    if header == [0; RecordHeader::LEN] {
+        return Ok(None);
+    }
+
+    let header = RecordHeader::decode(header);
+    if offset as u64 + header.record_len() as u64 > log_len {
+        return Err(KvsError::Corruption { gen, offset });
+    }
+
+    buf.resize(header.payload_len, 0);
+    reader.read_exact(buf)?;
+
+    Ok(Some(header))
+}
+
+/// Decompresses the payload of a compressed record in place.
+///
+/// The `offset` of the record is only used to report corruption.
+pub(crate) fn decompress_payload(buf: &mut Vec<u8>, gen: usize, offset: usize) -> Result<()> {
+    #[cfg(feature = "compression")]
+    {
+        *buf = lz4_flex::decompress_size_prepended(buf)
+            .map_err(|_| KvsError::Corruption { gen, offset })?;
+        Ok(())
+    }
+
+    // Compressed records are only valid in logfiles of a format version this build rejects.
+    #[cfg(not(feature = "compression"))]
+    {
+        let _ = buf;
+        Err(KvsError::Corruption { gen, offset })
+    }
+}
+
+/// Outcome of replaying a single logfile.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Replayed {
+    /// Amount of stale bytes that can be recovered.
+    pub(crate) stale_bytes: u64,
+    /// Highest sequence number found in the logfile.
+    pub(crate) last_seq: u64,
+    /// Format version of the logfile, unless it is empty.
+    pub(crate) version: Option<u16>,
+    /// Offset right past the last record, where new records are appended.
+    pub(crate) end: usize,
+    /// Whether a torn record was found at `end`, which the logfile should be truncated to.
+    pub(crate) torn: bool,
+}
+
+/// Lists the offset and sequence number of every record stored in a logfile, in order.
+pub(crate) fn record_sequences<R: Read + Seek>(
+    mut logfile: R,
+    gen: usize,
+) -> Result<Vec<(usize, u64)>> {
+    let mut records = Vec::new();
+    let mut start = FileHeader::LEN;
+    let mut payload = Vec::new();
+
+    let log_len = logfile.seek(SeekFrom::End(0))?;
+    logfile.rewind()?;
+    if check_file_header(&mut logfile, gen)?.is_none() {
+        return Ok(records);
+    }
+
+    while let Some(header) = read_record(&mut logfile, &mut payload, gen, start, log_len)? {
+        records.push((start, header.seq));
+        start += header.record_len();
+    }
+
+    Ok(records)
+}
+
+/// Lists every record stored in a logfile, in order.
+pub(crate) fn dump_records<R: Read + Seek>(mut logfile: R, gen: usize) -> Result<Vec<LogRecord>> {
+    let mut records = Vec::new();
+    let mut start = FileHeader::LEN;
+    let mut payload = Vec::new();
+
+    let log_len = logfile.seek(SeekFrom::End(0))?;
+    logfile.rewind()?;
+    if check_file_header(&mut logfile, gen)?.is_none() {
+        return Ok(records);
+    }
+
+    while let Some(header) = read_record(&mut logfile, &mut payload, gen, start, log_len)? {
+        if header.compressed {
+            decompress_payload(&mut payload, gen, start)?;
+        }
+
+        let (kind, key) = match serde_json::from_slice(&payload)? {
+            ReplayCommand::Set(key, _) => (RecordKind::Set, key),
+            ReplayCommand::Remove(key) => (RecordKind::Remove, key),
+        };
+        records.push(LogRecord {
+            offset: start,
+            seq: header.seq,
+            kind,
+            key,
+        });
+        start += header.record_len();
+    }
+
+    Ok(records)
+}
+
+/// Lists the key of every record stored in a logfile, in order, together with a pointer
+/// to the record if it is a `Set` record, or [`None`] if it is a `Remove` record.
+pub(crate) fn key_records<R: Read + Seek>(
+    mut logfile: R,
+    gen: usize,
+) -> Result<Vec<(String, Option<CommandPointer>)>> {
+    let mut records = Vec::new();
+    let mut start = FileHeader::LEN;
+    let mut payload = Vec::new();
+
+    let log_len = logfile.seek(SeekFrom::End(0))?;
+    logfile.rewind()?;
+    if check_file_header(&mut logfile, gen)?.is_none() {
+        return Ok(records);
+    }
+
+    while let Some(header) = read_record(&mut logfile, &mut payload, gen, start, log_len)? {
+        if header.compressed {
+            decompress_payload(&mut payload, gen, start)?;
+        }
+
+        let end = start + header.record_len();
+        records.push(match serde_json::from_slice(&payload)? {
+            ReplayCommand::Set(key, _) => {
+                (key, Some(CommandPointer::new(gen, header.seq, start..end)))
+            }
+            ReplayCommand::Remove(key) => (key, None),
+        });
+        start = end;
+    }
+
+    Ok(records)
+}
+
+/// Determines how [`replay`] treats a record it can't read, which it otherwise fails on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Recovery {
+    /// Fails on every record that can't be read.
+    Strict,
+    /// Stops right before a record cut short by the end of the logfile, which was torn
+    /// by a crash, if it starts at or past `synced_end`, the offset the logfile is known
+    /// to be durable up to.
+    TornTail { synced_end: usize },
+    /// Stops right before the first record that can't be read, whatever is wrong with it.
+    Truncate,
+}
+
+impl Recovery {
+    /// Checks whether a record starting at `start` that is cut short by the end of the
+    /// logfile is dropped.
+    fn drops_cut_short(self, start: usize) -> bool {
+        match self {
+            Self::Strict => false,
+            Self::TornTail { synced_end } => start >= synced_end,
+            Self::Truncate => true,
+        }
+    }
 }
 
 /// Restores the in-memory index by replaying all `Command`s stored in a logfile.
 ///
-/// This function returns the amount of stale bytes that can be recovered.
+/// Replay stops right before a record that can't be read instead of failing if the
+/// `recovery` mode drops it, in which case the result is marked as torn.
 pub(crate) fn replay<R: Read + Seek>(
     mut logfile: R,
-    index: &mut HashMap<String, CommandPointer>,
+    index: &mut Index,
     gen: usize,
-) -> Result<usize> {
-    let (mut start, mut stale) = (0, 0);
+    recovery: Recovery,
+) -> Result<Replayed> {
+    let (mut start, mut stale, mut last_seq) = (FileHeader::LEN, 0, 0);
+    let mut payload = Vec::new();
+    let mut torn = false;
 
+    let log_len = logfile.seek(SeekFrom::End(0))?;
     logfile.rewind()?;
-    let mut stream = Deserializer::from_reader(logfile).into_iter::<Command>();
+    let version = check_file_header(&mut logfile, gen)?;
+    if version.is_none() {
+        return Ok(Replayed {
+            stale_bytes: 0,
+            last_seq: 0,
+            version,
+            end: FileHeader::LEN,
+            torn,
+        });
+    }
+
+    loop {
+        // A record can only be torn by a crash if it is cut short by the end of the
+        // logfile. A record that is damaged otherwise may be followed by intact ones.
+        let header = match read_record(&mut logfile, &mut payload, gen, start, log_len) {
+            Ok(Some(header)) => Ok(header),
+            Ok(None) => break,
+            Err(err) if is_cut_short(&err) && recovery.drops_cut_short(start) => {
+                torn = true;
+                break;
+            }
+            Err(err) => Err(err),
+        };
+
+        let record = header.and_then(|header| {
+            if header.compressed {
+                decompress_payload(&mut payload, gen, start)?;
+            }
+            let command =
+                serde_json::from_slice(&payload).map_err(|source| KvsError::ReplayFailed {
+                    gen,
+                    offset: start,
+                    source,
+                })?;
+            Ok((header, command))
+        });
+
+        let (header, command) = match record {
+            Ok(record) => record,
+            Err(err) if recovery == Recovery::Truncate && is_torn(&err) => {
+                torn = true;
+                break;
+            }
+            Err(err) => return Err(err),
+        };
 
-    while let Some(command) = stream.next() {
-        let command = command?;
-        let end = stream.byte_offset();
+        let end = start + header.record_len();
+        last_seq = last_seq.max(header.seq);
 
         match command {
-            Command::Set(key, _) => {
-                let cmd_ptr = CommandPointer::new(gen, start..end);
+            ReplayCommand::Set(key, _) => {
+                let cmd_ptr = CommandPointer::new(gen, header.seq, start..end);
 
                 if let Some(old_cmd_ptr) = index.insert(key, cmd_ptr) {
-                    stale += old_cmd_ptr.len();
+                    stale += old_cmd_ptr.len() as u64;
                 }
             }
-            Command::Remove(key) => {
+            ReplayCommand::Remove(key) => {
                 if let Some(old_cmd) = index.remove(&key) {
-                    stale += old_cmd.len();
+                    stale += old_cmd.len() as u64;
                 }
-                stale += end - start;
+                stale += (end - start) as u64;
             }
         }
 
         start = end;
     }
 
-    Ok(stale)
+    Ok(Replayed {
+        stale_bytes: stale,
+        last_seq,
+        version,
+        end: start,
+        torn,
+    })
+}
+
+/// Checks whether an error of [`read_record`] is how a record cut short by the end of
+/// the logfile fails.
+fn is_cut_short(err: &KvsError) -> bool {
+    match err {
+        KvsError::Io(err) => err.kind() == io::ErrorKind::UnexpectedEof,
+        KvsError::Corruption { .. } => true,
+        _ => false,
+    }
+}
+
+/// Checks whether an error reading a record is how a partially written or otherwise
+/// damaged record fails.
+pub(crate) fn is_torn(err: &KvsError) -> bool {
+    match err {
+        KvsError::Io(err) => err.kind() == io::ErrorKind::UnexpectedEof,
+        KvsError::Serde(_) | KvsError::ReplayFailed { .. } | KvsError::Corruption { .. } => true,
+        _ => false,
+    }
 }
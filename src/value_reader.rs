@@ -0,0 +1,192 @@
+use std::io::{self, BufRead, Cursor, Read};
+
+use crate::{KvsError, Result};
+
+/// A reader over the value of a `Set` record.
+///
+/// Uncompressed values are decoded from the serialized record as they are read,
+/// while compressed ones have to be decompressed into memory up front.
+#[derive(Debug)]
+pub(crate) enum ValueReader<R> {
+    Streamed(JsonStrReader<R>),
+    Buffered(Cursor<Vec<u8>>),
+}
+
+impl<R: BufRead> Read for ValueReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Streamed(reader) => reader.read(buf),
+            Self::Buffered(reader) => reader.read(buf),
+        }
+    }
+}
+
+/// Decodes the contents of a JSON string incrementally, without holding all of it in memory.
+#[derive(Debug)]
+pub(crate) struct JsonStrReader<R> {
+    inner: R,
+    /// An escaped character that didn't fit into the caller's buffer yet.
+    pending: [u8; 4],
+    pending_range: (usize, usize),
+    done: bool,
+}
+
+impl<R: BufRead> JsonStrReader<R> {
+    /// Positions a reader over the value of a serialized `Set` command, `["key","value"]`.
+    ///
+    /// The `gen` and `offset` of the record are only used to report corruption.
+    pub(crate) fn new_set_value(mut inner: R, gen: usize, offset: usize) -> Result<Self> {
+        let corruption = |_| KvsError::Corruption { gen, offset };
+
+        match next_token(&mut inner)? {
+            b'[' => {}
+            // A serialized `Remove` command is just the key.
+            b'"' => {
+                return Err(KvsError::UnexpectedCommand {
+                    expected: "set",
+                    got: "remove",
+                })
+            }
+            _ => return Err(KvsError::Corruption { gen, offset }),
+        }
+
+        let mut key = Self::new(inner);
+        key.expect_token(b'"').map_err(corruption)?;
+        io::copy(&mut key, &mut io::sink()).map_err(corruption)?;
+        key.done = false;
+
+        key.expect_token(b',').map_err(corruption)?;
+        key.expect_token(b'"').map_err(corruption)?;
+
+        Ok(key)
+    }
+
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            pending: [0; 4],
+            pending_range: (0, 0),
+            done: false,
+        }
+    }
+
+    fn expect_token(&mut self, expected: u8) -> io::Result<()> {
+        if next_token(&mut self.inner)? == expected {
+            Ok(())
+        } else {
+            Err(invalid_data())
+        }
+    }
+
+    fn next_byte(&mut self) -> io::Result<u8> {
+        let mut byte = [0];
+        self.inner.read_exact(&mut byte)?;
+        Ok(byte[0])
+    }
+
+    fn read_hex_escape(&mut self) -> io::Result<u32> {
+        let mut hex = [0; 4];
+        self.inner.read_exact(&mut hex)?;
+
+        std::str::from_utf8(&hex)
+            .ok()
+            .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+            .ok_or_else(invalid_data)
+    }
+
+    /// Decodes the escape sequence following a backslash into the pending buffer.
+    fn read_escape(&mut self) -> io::Result<()> {
+        let c = match self.next_byte()? {
+            b'"' => '"',
+            b'\\' => '\\',
+            b'/' => '/',
+            b'b' => '\u{8}',
+            b'f' => '\u{c}',
+            b'n' => '\n',
+            b'r' => '\r',
+            b't' => '\t',
+            b'u' => {
+                let mut code = self.read_hex_escape()?;
+
+                if (0xD800..0xDC00).contains(&code) {
+                    if self.next_byte()? != b'\\' || self.next_byte()? != b'u' {
+                        return Err(invalid_data());
+                    }
+                    let low = self.read_hex_escape()?;
+                    if !(0xDC00..0xE000).contains(&low) {
+                        return Err(invalid_data());
+                    }
+                    code = 0x10000 + ((code - 0xD800) << 10) + (low - 0xDC00);
+                }
+
+                char::from_u32(code).ok_or_else(invalid_data)?
+            }
+            _ => return Err(invalid_data()),
+        };
+
+        let len = c.encode_utf8(&mut self.pending).len();
+        self.pending_range = (0, len);
+        Ok(())
+    }
+}
+
+impl<R: BufRead> Read for JsonStrReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if buf.is_empty() {
+                return Ok(0);
+            }
+
+            let (start, end) = self.pending_range;
+            if start < end {
+                let len = buf.len().min(end - start);
+                buf[..len].copy_from_slice(&self.pending[start..start + len]);
+                self.pending_range.0 += len;
+                return Ok(len);
+            }
+
+            if self.done {
+                return Ok(0);
+            }
+
+            let available = self.inner.fill_buf()?;
+            if available.is_empty() {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+
+            match available.iter().position(|&b| b == b'"' || b == b'\\') {
+                Some(0) => {
+                    let special = available[0];
+                    self.inner.consume(1);
+
+                    if special == b'"' {
+                        self.done = true;
+                    } else {
+                        self.read_escape()?;
+                    }
+                }
+                plain => {
+                    let len = plain.unwrap_or(available.len()).min(buf.len());
+                    buf[..len].copy_from_slice(&available[..len]);
+                    self.inner.consume(len);
+                    return Ok(len);
+                }
+            }
+        }
+    }
+}
+
+/// Reads the next byte that isn't JSON whitespace.
+fn next_token<R: BufRead>(reader: &mut R) -> io::Result<u8> {
+    let mut byte = [0];
+    loop {
+        reader.read_exact(&mut byte)?;
+        if !matches!(byte[0], b' ' | b'\t' | b'\n' | b'\r') {
+            return Ok(byte[0]);
+        }
+    }
+}
+
+fn invalid_data() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "invalid JSON string")
+}
@@ -0,0 +1,54 @@
+use std::collections::HashMap;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Mutex, PoisonError};
+
+/// A change made to a subscribed key through [`KvStore`](crate::KvStore).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// The key was set to the contained value.
+    Set(String),
+    /// The key was removed.
+    Removed,
+}
+
+/// Registry of per-key event subscribers.
+#[derive(Debug, Default)]
+pub(crate) struct Watchers {
+    subscribers: Mutex<HashMap<String, Vec<Sender<Event>>>>,
+}
+
+impl Watchers {
+    /// Registers a new subscriber for `key`.
+    pub(crate) fn subscribe(&self, key: &str) -> Receiver<Event> {
+        let (tx, rx) = mpsc::channel();
+
+        self.subscribers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(key.to_owned())
+            .or_default()
+            .push(tx);
+
+        rx
+    }
+
+    /// Sends an event to every subscriber of `key`.
+    ///
+    /// The event is only built if the key has subscribers. Subscribers whose
+    /// receiver has been dropped are forgotten.
+    pub(crate) fn notify<F: FnOnce() -> Event>(&self, key: &str, event: F) {
+        let mut subscribers = self
+            .subscribers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        if let Some(senders) = subscribers.get_mut(key) {
+            let event = event();
+            senders.retain(|tx| tx.send(event.clone()).is_ok());
+
+            if senders.is_empty() {
+                subscribers.remove(key);
+            }
+        }
+    }
+}
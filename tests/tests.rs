@@ -1,11 +1,52 @@
 use assert_cmd::prelude::*;
-use kvs::{KvStore, Result};
+#[cfg(feature = "tokio")]
+use kvs::AsyncKvStore;
+use kvs::{
+    Commit, CompactStep, CompactionMode, ErrorKind, Event, EvictionPolicy, FlushPolicy, IoStats,
+    KvStore, KvsError, Mutation, OpKind, OpenMode, RecordKind, Result, WriteBatch,
+};
 use predicates::ord::eq;
+use predicates::prelude::*;
 use predicates::str::{contains, is_empty, PredicateStrExt};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::convert::{TryFrom, TryInto};
+use std::path::Path;
 use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
+// Counts the allocations of a thread while it runs `count_allocations`.
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get().map(|count| count + 1)));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn count_allocations<T>(f: impl FnOnce() -> T) -> (T, usize) {
+    ALLOCATIONS.with(|count| count.set(Some(0)));
+    let result = f();
+    let count = ALLOCATIONS.with(|count| count.take()).unwrap_or_default();
+    (result, count)
+}
+
 // `kvs` with no args should exit with a non-zero code.
 #[test]
 fn cli_no_args() {
@@ -17,7 +58,7 @@ fn cli_no_args() {
 fn cli_version() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["-V"])
+        .args(["-V"])
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
 }
@@ -28,7 +69,7 @@ fn cli_get_non_existent_key() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -41,7 +82,7 @@ fn cli_rm_non_existent_key() {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "key1"])
+        .args(["rm", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .failure()
@@ -54,7 +95,7 @@ fn cli_set() {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "key1", "value1"])
+        .args(["set", "key1", "value1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -72,7 +113,7 @@ fn cli_get_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -80,7 +121,7 @@ fn cli_get_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key2"])
+        .args(["get", "key2"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -100,7 +141,7 @@ fn cli_rm_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "key1"])
+        .args(["rm", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -108,7 +149,7 @@ fn cli_rm_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -121,13 +162,13 @@ fn cli_rm_stored() -> Result<()> {
 fn cli_invalid_get() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get"])
+        .args(["get"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "extra", "field"])
+        .args(["get", "extra", "field"])
         .assert()
         .failure();
 }
@@ -136,19 +177,19 @@ fn cli_invalid_get() {
 fn cli_invalid_set() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set"])
+        .args(["set"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "missing_field"])
+        .args(["set", "missing_field"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "extra", "extra", "field"])
+        .args(["set", "extra", "extra", "field"])
         .assert()
         .failure();
 }
@@ -157,13 +198,13 @@ fn cli_invalid_set() {
 fn cli_invalid_rm() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm"])
+        .args(["rm"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "extra", "field"])
+        .args(["rm", "extra", "field"])
         .assert()
         .failure();
 }
@@ -172,9 +213,109 @@ fn cli_invalid_rm() {
 fn cli_invalid_subcommand() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["unknown", "subcommand"])
+        .args(["unknown", "subcommand"])
+        .assert()
+        .failure();
+}
+
+// `kvs stats` should print the state of the store, as text or as JSON.
+#[test]
+fn cli_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let mut store = KvStore::open(temp_dir.path().join("./data"))?;
+    store.set("key1", "value1")?;
+    store.set("key1", "value2")?;
+    store.set("key2", "value1")?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["stats"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(
+            contains("Live keys: 2")
+                .and(contains("Stale bytes: "))
+                .and(contains("Current generation: 1"))
+                .and(contains("Generations: 1"))
+                .and(contains("Disk usage: ")),
+        );
+
+    let output = Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["stats", "--format", "json"])
+        .current_dir(&temp_dir)
+        .output()?;
+    assert!(output.status.success());
+
+    let stats: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+    assert_eq!(stats["live_keys"], 2);
+    assert!(stats["stale_bytes"].as_u64().unwrap() > 0);
+    assert_eq!(stats["current_generation"], 1);
+    assert_eq!(stats["generations"], 1);
+    assert!(stats["disk_bytes"].as_u64().unwrap() > 0);
+
+    Ok(())
+}
+
+// Should dump every record of the log, including overwritten ones, as JSON lines.
+#[test]
+fn cli_dump() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let mut store = KvStore::open(temp_dir.path().join("./data"))?;
+    store.set("key1", "value1")?;
+    store.set("key1", "value2")?;
+    store.set("key2", "value1")?;
+    store.remove("key2")?;
+    drop(store);
+
+    for args in [&["dump"][..], &["dump", "--generation", "1"]] {
+        let output = Command::cargo_bin("kvs")
+            .unwrap()
+            .args(args)
+            .current_dir(&temp_dir)
+            .output()?;
+        assert!(output.status.success());
+
+        let records: Vec<serde_json::Value> = std::str::from_utf8(&output.stdout)
+            .unwrap()
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<serde_json::Result<_>>()?;
+        let summary: Vec<_> = records
+            .iter()
+            .map(|record| {
+                (
+                    record["kind"].as_str().unwrap(),
+                    record["key"].as_str().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("set", "key1"),
+                ("set", "key1"),
+                ("set", "key2"),
+                ("rm", "key2")
+            ]
+        );
+        assert!(records.iter().all(|record| record["generation"] == 1));
+        assert_eq!(records[0]["offset"], FILE_HEADER_LEN);
+        assert!(records[1]["offset"].as_u64() > records[0]["offset"].as_u64());
+    }
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["dump", "--generation", "7"])
+        .current_dir(&temp_dir)
         .assert()
         .failure();
+
+    Ok(())
 }
 
 // Should get previously stored value.
@@ -186,14 +327,14 @@ fn get_stored_value() -> Result<()> {
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
 
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
 
     // Open from disk again and check persistent data.
     drop(store);
     let mut store = KvStore::open(temp_dir.path())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
 
     Ok(())
 }
@@ -205,16 +346,16 @@ fn overwrite_value() -> Result<()> {
     let mut store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
     store.set("key1".to_owned(), "value2".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key1")?, Some("value2".to_owned()));
 
     // Open from disk again and check persistent data.
     drop(store);
     let mut store = KvStore::open(temp_dir.path())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key1")?, Some("value2".to_owned()));
     store.set("key1".to_owned(), "value3".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key1")?, Some("value3".to_owned()));
 
     Ok(())
 }
@@ -226,12 +367,12 @@ fn get_non_existent_value() -> Result<()> {
     let mut store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
-    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key2")?, None);
 
     // Open from disk again and check persistent data.
     drop(store);
     let mut store = KvStore::open(temp_dir.path())?;
-    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key2")?, None);
 
     Ok(())
 }
@@ -250,7 +391,7 @@ fn remove_key() -> Result<()> {
     let mut store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert!(store.remove("key1".to_owned()).is_ok());
-    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key1")?, None);
     Ok(())
 }
 
@@ -299,3 +440,3285 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+// Should write each key of a batch only once, keeping its last value.
+#[test]
+fn set_many_deduplicates_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set_many(vec![
+        ("key1".to_owned(), "value1".to_owned()),
+        ("key2".to_owned(), "value2".to_owned()),
+        ("key1".to_owned(), "value3".to_owned()),
+        ("key2".to_owned(), "value4".to_owned()),
+        ("key3".to_owned(), "value5".to_owned()),
+    ])?;
+    store.flush()?;
+
+    assert_eq!(store.get("key1")?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2")?, Some("value4".to_owned()));
+    assert_eq!(store.get("key3")?, Some("value5".to_owned()));
+
+    assert_eq!(read_records(temp_dir.path().join("1.log"))?.len(), 3);
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2")?, Some("value4".to_owned()));
+
+    Ok(())
+}
+
+// Should notify subscribers about changes of the watched key only.
+#[test]
+fn subscribe_key_changes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    let events = store.subscribe("key1");
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    store.remove("key1".to_owned())?;
+
+    assert_eq!(events.try_recv(), Ok(Event::Set("value1".to_owned())));
+    assert_eq!(events.try_recv(), Ok(Event::Removed));
+    assert!(events.try_recv().is_err());
+
+    Ok(())
+}
+
+// Should report the generation holding a key, which changes after compaction.
+#[test]
+fn get_with_location() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+
+    let (value, gen, _) = store.get_with_location("key1")?.unwrap();
+    assert_eq!(value, "value3");
+    assert_eq!(gen, 1);
+    assert_eq!(store.get_with_location("key3")?, None);
+
+    store.clean_stale_data()?;
+
+    let (value, new_gen, _) = store.get_with_location("key1")?.unwrap();
+    assert_eq!(value, "value3");
+    assert_ne!(new_gen, gen);
+
+    Ok(())
+}
+
+// Should name, discover and compact logfiles using a custom naming scheme.
+#[test]
+fn custom_logfile_naming() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let builder = || KvStore::builder().prefix("kvs-").extension("data");
+
+    // A foreign file matching the default scheme must be ignored.
+    std::fs::write(temp_dir.path().join("1.log"), "not a kvs log")?;
+
+    let mut store = builder().open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    drop(store);
+    assert!(temp_dir.path().join("kvs-1.data").is_file());
+
+    let mut store = builder().open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value3".to_owned()));
+    store.clean_stale_data()?;
+    drop(store);
+    assert!(!temp_dir.path().join("kvs-1.data").exists());
+    assert!(temp_dir.path().join("kvs-2.data").is_file());
+    assert!(temp_dir.path().join("1.log").is_file());
+
+    let mut store = builder().open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// `close` should surface errors of the final flush.
+#[cfg(target_os = "linux")]
+#[test]
+fn close_surfaces_flush_error() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.close()?;
+
+    let broken_dir = TempDir::new().expect("unable to create temporary working directory");
+    std::os::unix::fs::symlink("/dev/full", broken_dir.path().join("1.log"))?;
+
+    let mut store = KvStore::open(broken_dir.path())?;
+    store.remove("key1".to_owned())?;
+    assert!(store.close().is_err());
+
+    Ok(())
+}
+
+// Should reuse a single buffer across several gets.
+#[test]
+fn get_into_reuses_buffer() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "a rather long value1".to_owned())?;
+    store.set("key2".to_owned(), "value\n\"2\"".to_owned())?;
+
+    let mut buf = String::new();
+    assert!(store.get_into("key1", &mut buf)?);
+    assert_eq!(buf, "a rather long value1");
+    let capacity = buf.capacity();
+
+    assert!(store.get_into("key2", &mut buf)?);
+    assert_eq!(buf, "value\n\"2\"");
+    assert_eq!(buf.capacity(), capacity);
+
+    assert!(!store.get_into("key3", &mut buf)?);
+    assert_eq!(buf, "value\n\"2\"");
+
+    Ok(())
+}
+
+// Should remove every key under a prefix and leave other keys intact.
+#[test]
+fn remove_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("session:1:user".to_owned(), "alice".to_owned())?;
+    store.set("session:1:token".to_owned(), "abc".to_owned())?;
+    store.set("session:10:user".to_owned(), "bob".to_owned())?;
+    store.set("session:2:user".to_owned(), "carol".to_owned())?;
+    store.set("other".to_owned(), "value".to_owned())?;
+
+    assert_eq!(store.remove_prefix("session:1:")?, 2);
+    assert_eq!(store.remove_prefix("session:1:")?, 0);
+
+    assert_eq!(store.get("session:1:user")?, None);
+    assert_eq!(store.get("session:1:token")?, None);
+    assert_eq!(store.get("session:10:user")?, Some("bob".to_owned()));
+    assert_eq!(store.get("session:2:user")?, Some("carol".to_owned()));
+    assert_eq!(store.get("other")?, Some("value".to_owned()));
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("session:1:user")?, None);
+    assert_eq!(store.get("session:10:user")?, Some("bob".to_owned()));
+
+    Ok(())
+}
+
+// `OpenMode::Verified` should detect a corrupted value that `OpenMode::Fast` doesn't read.
+#[test]
+fn verified_open_detects_corruption() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    replace_bytes(temp_dir.path().join("1.log"), b"\"value1\"", b"12345678")?;
+
+    assert!(KvStore::builder()
+        .open_mode(OpenMode::Verified)
+        .open(temp_dir.path())
+        .is_err());
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+    assert!(store.get("key1").is_err());
+    assert!(store.verify().is_err());
+
+    Ok(())
+}
+
+// A record length that disagrees with the index should be reported as corruption.
+#[test]
+fn record_length_mismatch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.flush()?;
+
+    let (_, gen, offset) = store.get_with_location("key2")?.unwrap();
+
+    let logfile = temp_dir.path().join("1.log");
+    let mut contents = std::fs::read(&logfile)?;
+    contents[offset] += 1;
+    std::fs::write(&logfile, contents)?;
+
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    match store.get("key2") {
+        Err(KvsError::Corruption { gen: g, offset: o }) => assert_eq!((g, o), (gen, offset)),
+        res => panic!("expected corruption error, got {:?}", res),
+    }
+
+    Ok(())
+}
+
+// A record header claiming more bytes than the logfile holds should be reported as
+// corrupted rather than trusted.
+#[test]
+fn oversized_record_length() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.sync()?;
+    drop(store);
+
+    // Sets the payload length of the first record to nearly 2 GiB.
+    let logfile = temp_dir.path().join("1.log");
+    let mut contents = std::fs::read(&logfile)?;
+    contents[8..12].copy_from_slice(&0x7fff_fff0u32.to_le_bytes());
+    std::fs::write(&logfile, contents)?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::Corruption { gen, offset }) => assert_eq!((gen, offset), (1, 8)),
+        res => panic!("expected corruption error, got {:?}", res.map(|_| ())),
+    }
+
+    Ok(())
+}
+
+/// Length of the header at the start of every logfile.
+const FILE_HEADER_LEN: usize = 8;
+
+/// Reads the payloads of all records stored in a logfile.
+fn read_records<P: AsRef<Path>>(path: P) -> Result<Vec<serde_json::Value>> {
+    let contents = std::fs::read(path)?;
+    let mut records = Vec::new();
+
+    let mut rest = &contents[FILE_HEADER_LEN..];
+    // A zeroed header marks preallocated space.
+    while !rest.is_empty() && rest[..12] != [0; 12] {
+        let (header, tail) = rest.split_at(12);
+        let length = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+        let (payload, tail) = tail.split_at(length);
+        records.push(serde_json::from_slice(payload)?);
+        rest = tail;
+    }
+
+    Ok(records)
+}
+
+/// Replaces every occurrence of `from` in a file with `to` of the same length.
+fn replace_bytes<P: AsRef<Path>>(path: P, from: &[u8], to: &[u8]) -> Result<()> {
+    assert_eq!(from.len(), to.len());
+
+    let mut contents = std::fs::read(&path)?;
+    let mut i = 0;
+    while i + from.len() <= contents.len() {
+        if &contents[i..i + from.len()] == from {
+            contents[i..i + from.len()].copy_from_slice(to);
+            i += from.len();
+        } else {
+            i += 1;
+        }
+    }
+
+    std::fs::write(path, contents)?;
+    Ok(())
+}
+
+// Concurrent awaited operations on an `AsyncKvStore` should all be applied.
+#[cfg(feature = "tokio")]
+#[tokio::test(flavor = "multi_thread")]
+async fn async_store_concurrent_ops() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = AsyncKvStore::open(temp_dir.path()).await?;
+
+    let tasks: Vec<_> = (0..100)
+        .map(|i| {
+            let store = store.clone();
+            tokio::spawn(async move { store.set(format!("key{}", i), format!("value{}", i)).await })
+        })
+        .collect();
+    for task in tasks {
+        task.await.unwrap()?;
+    }
+
+    for i in 0..100 {
+        assert_eq!(
+            store.get(format!("key{}", i)).await?,
+            Some(format!("value{}", i))
+        );
+    }
+    assert!(store.remove("key0").await?);
+    assert_eq!(store.get("key0").await?, None);
+
+    Ok(())
+}
+
+// Should grow the index capacity on `reserve` and reclaim it on `shrink_index`.
+#[test]
+fn reserve_and_shrink_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.reserve(1000);
+    let reserved = store.index_capacity();
+    assert!(reserved >= 1000);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.shrink_index();
+    assert!(store.index_capacity() < reserved);
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Sequence numbers should increase across overwrites and persist across reopening.
+#[test]
+fn sequence_increases_on_overwrite() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    assert_eq!(store.sequence_of("key1"), None);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let first = store.sequence_of("key1").unwrap();
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    let second = store.sequence_of("key1").unwrap();
+    assert!(second > first);
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.sequence_of("key1"), Some(second));
+
+    store.clean_stale_data()?;
+    assert_eq!(store.sequence_of("key1"), Some(second));
+    store.set("key1".to_owned(), "value4".to_owned())?;
+    assert!(store.sequence_of("key1").unwrap() > second);
+
+    Ok(())
+}
+
+// Should only set keys whose sequence number is the expected one.
+#[test]
+fn set_if_seq() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    // Absent key.
+    assert!(!store.set_if_seq("key1", "value1", Some(1))?);
+    assert!(store.set_if_seq("key1", "value1", None)?);
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+
+    // Matching sequence.
+    let seq = store.sequence_of("key1");
+    assert!(store.set_if_seq("key1", "value2", seq)?);
+    assert_eq!(store.get("key1")?, Some("value2".to_owned()));
+
+    // Mismatching sequence, the key was overwritten since.
+    store.flush()?;
+    assert!(!store.set_if_seq("key1", "value3", seq)?);
+    assert!(!store.set_if_seq("key1", "value3", None)?);
+    assert_eq!(store.unflushed_bytes(), 0);
+    assert_eq!(store.get("key1")?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Stale byte accounting should match a recount from the log after heavy churn.
+#[test]
+fn stale_bytes_survive_churn() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    for iter in 0..50_000 {
+        let key = format!("key{}", iter % 16);
+        match iter % 5 {
+            0 => {
+                store.remove(&key)?;
+            }
+            1 => {
+                // Tombstones of missing keys are stale as well.
+                store.remove(format!("missing{}", iter))?;
+            }
+            _ => store.set(key, format!("value{}", iter).repeat(iter % 7))?,
+        }
+    }
+
+    // Compaction ran in between, resetting the count.
+    assert!(store.stats()?.current_generation > 1);
+    let stale_bytes = store.stats()?.stale_bytes;
+    assert!(stale_bytes > 0);
+    store.rebuild_index()?;
+    assert_eq!(store.stats()?.stale_bytes, stale_bytes);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.stats()?.stale_bytes, stale_bytes);
+
+    Ok(())
+}
+
+// Compaction should leave no tombstones behind, including those of keys set again later.
+#[test]
+fn compaction_drops_tombstones() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1", "value1")?;
+    store.remove("key1")?;
+    store.set("key1", "value2")?;
+    store.set("key2", "value1")?;
+    store.remove("key2")?;
+    store.remove("missing")?;
+    assert!(store
+        .dump_generation(1)?
+        .iter()
+        .any(|record| record.kind == RecordKind::Remove));
+
+    store.clean_stale_data()?;
+    let records = store.dump_generation(2)?;
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].kind, RecordKind::Set);
+    assert_eq!(records[0].key, "key1");
+    assert_eq!(store.get("key1")?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Should compact through a scratch directory, even on another filesystem.
+#[test]
+fn compaction_scratch_dir() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // A tmpfs is usually a different filesystem, which exercises the copy fallback.
+    let scratch_dir = if Path::new("/dev/shm").is_dir() {
+        tempfile::tempdir_in("/dev/shm")
+    } else {
+        TempDir::new()
+    }
+    .expect("unable to create temporary scratch directory");
+
+    let mut store = KvStore::builder()
+        .scratch_dir(scratch_dir.path())
+        .open(temp_dir.path())?;
+    for iter in 0..10 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    store.clean_stale_data()?;
+
+    let mut files: Vec<_> = std::fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.map(|entry| entry.file_name()))
+        .collect::<std::io::Result<_>>()?;
+    files.sort();
+    assert_eq!(files, vec!["2.log", "KVSTORE", "MANIFEST", "SYNCED"]);
+    assert_eq!(std::fs::read_dir(scratch_dir.path())?.count(), 0);
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some("value9".to_owned())
+        );
+    }
+
+    Ok(())
+}
+
+// Should mark store directories, and only open existing stores if asked to.
+#[test]
+fn open_existing() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store_dir = temp_dir.path().join("store");
+
+    let err = KvStore::open_existing(temp_dir.path()).unwrap_err();
+    assert!(matches!(err, KvsError::StoreNotFound(ref path) if path == temp_dir.path()));
+    assert!(KvStore::open_existing(&store_dir).is_err());
+    assert!(!store_dir.exists());
+
+    drop(KvStore::open(&store_dir)?);
+    assert!(store_dir.join("KVSTORE").is_file());
+
+    let mut store = KvStore::open_existing(&store_dir)?;
+    store.set("key1", "value1")?;
+    drop(store);
+    let mut store = KvStore::open_existing(&store_dir)?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Should report progress once per live key copied by compaction.
+#[test]
+fn compact_with_progress() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for iter in 0..3 {
+        for key_id in 0..50 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    let estimate = store.compaction_estimate();
+
+    let mut updates = Vec::new();
+    let reclaimed = store.compact_with_progress(|progress| updates.push(progress))?;
+    assert_eq!(reclaimed, estimate.reclaimable_bytes);
+
+    assert_eq!(updates.len(), 50);
+    for (done, progress) in updates.iter().enumerate() {
+        assert_eq!(progress.keys_done, done + 1);
+        assert_eq!(progress.keys_total, 50);
+    }
+    assert_eq!(updates[49].bytes_rewritten, estimate.live_bytes);
+    assert!(updates
+        .windows(2)
+        .all(|pair| pair[0].bytes_rewritten < pair[1].bytes_rewritten));
+
+    Ok(())
+}
+
+// Should refuse to open a store at the path of a file.
+#[test]
+fn open_file_path() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let file_path = temp_dir.path().join("data");
+    std::fs::write(&file_path, "not a store")?;
+
+    let err = KvStore::open(&file_path).unwrap_err();
+    assert!(matches!(err, KvsError::NotADirectory(ref path) if path == &file_path));
+    assert_eq!(
+        err.to_string(),
+        format!("store path {} is not a directory", file_path.display())
+    );
+    assert_eq!(std::fs::read_to_string(&file_path)?, "not a store");
+
+    Ok(())
+}
+
+// Should send every write committed to the log through the commit channel.
+#[test]
+fn on_commit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (tx, rx) = std::sync::mpsc::channel();
+    let mut store = KvStore::builder()
+        .on_commit(tx)
+        .flush_policy(FlushPolicy::Sync)
+        .open(temp_dir.path())?;
+
+    store.set("key1", "value1")?;
+    store.set("key2", "value2")?;
+    store.remove("key1")?;
+    store.set("key2", "value3")?;
+
+    let commits: Vec<Commit> = rx.try_iter().collect();
+    let mutations: Vec<_> = commits
+        .iter()
+        .map(|commit| commit.mutation.clone())
+        .collect();
+    assert_eq!(
+        mutations,
+        vec![
+            Mutation::Set("key1".to_owned(), "value1".to_owned()),
+            Mutation::Set("key2".to_owned(), "value2".to_owned()),
+            Mutation::Remove("key1".to_owned()),
+            Mutation::Set("key2".to_owned(), "value3".to_owned()),
+        ]
+    );
+
+    // Commits point at their records, which were synced before being sent.
+    let records = KvStore::open(temp_dir.path())?.dump_generation(1)?;
+    assert_eq!(records.len(), commits.len());
+    for (commit, record) in commits.iter().zip(&records) {
+        assert_eq!(commit.generation, 1);
+        assert_eq!(commit.offset, record.offset);
+        assert_eq!(commit.seq, record.seq);
+    }
+    assert_eq!(store.sequence_of("key2"), Some(commits[3].seq));
+
+    // Writes keep working once the receiver is gone.
+    drop(rx);
+    store.set("key3", "value4")?;
+
+    Ok(())
+}
+
+// A replica applying the commits of a primary should end up with the same data.
+#[test]
+fn apply_command() -> Result<()> {
+    let primary_dir = TempDir::new().expect("unable to create temporary working directory");
+    let replica_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (tx, rx) = std::sync::mpsc::channel();
+    let mut primary = KvStore::builder().on_commit(tx).open(primary_dir.path())?;
+    let mut replica = KvStore::open(replica_dir.path())?;
+
+    for iter in 0..5 {
+        for key_id in 0..20 {
+            primary.set(
+                format!("key{}", key_id),
+                format!("value{}-{}", key_id, iter),
+            )?;
+        }
+        primary.remove(format!("key{}", iter))?;
+    }
+    primary.remove("missing")?;
+
+    let commits: Vec<Commit> = rx.try_iter().collect();
+    for commit in &commits {
+        replica.apply_command(commit.mutation.clone())?;
+    }
+    // Applying a commit again changes nothing.
+    replica.apply_command(commits[commits.len() - 2].mutation.clone())?;
+
+    let mut expected = BTreeMap::new();
+    primary.for_each(|key, value| {
+        expected.insert(key.to_owned(), value.to_owned());
+        Ok(())
+    })?;
+    let mut replicated = BTreeMap::new();
+    replica.for_each(|key, value| {
+        replicated.insert(key.to_owned(), value.to_owned());
+        Ok(())
+    })?;
+    assert_eq!(expected.len(), 19);
+    assert_eq!(replicated, expected);
+
+    Ok(())
+}
+
+// Adaptive compaction should run more often than the fixed threshold for small stores,
+// and less often for large ones.
+#[test]
+fn adaptive_compaction() -> Result<()> {
+    // Compaction is the only thing that syncs under the manual flush policy.
+    let compactions = |mode: CompactionMode, keys: usize, value_len: usize| -> Result<u64> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::builder()
+            .compaction_mode(mode)
+            .open(temp_dir.path())?;
+        let value = "x".repeat(value_len);
+
+        for _ in 0..4 {
+            for key_id in 0..keys {
+                store.set(format!("key{}", key_id), value.as_str())?;
+            }
+        }
+        for key_id in 0..keys {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?.as_deref(),
+                Some(&*value)
+            );
+        }
+        Ok(store.io_stats().syncs)
+    };
+
+    // About 270 KiB of stale records for 90 KiB of live ones.
+    let fixed = compactions(CompactionMode::Fixed, 1000, 64)?;
+    let adaptive = compactions(CompactionMode::Adaptive, 1000, 64)?;
+    assert!(adaptive > fixed, "{} <= {}", adaptive, fixed);
+
+    // About 10 MiB of stale records for 3 MiB of live ones.
+    let fixed = compactions(CompactionMode::Fixed, 400, 8 * 1024)?;
+    let adaptive = compactions(CompactionMode::Adaptive, 400, 8 * 1024)?;
+    assert!(adaptive < fixed, "{} >= {}", adaptive, fixed);
+
+    Ok(())
+}
+
+// Should keep every record ever written with compaction disabled.
+#[test]
+fn disabled_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder()
+        .compaction_mode(CompactionMode::Disabled)
+        .open(temp_dir.path())?;
+    let value = "x".repeat(1024);
+
+    for _ in 0..20 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), value.as_str())?;
+        }
+    }
+    store.remove("key0")?;
+    assert!(store.compaction_estimate().reclaimable_bytes > 1024 * 1024);
+
+    let records = store.dump_generation(1)?;
+    assert_eq!(records.len(), 2001);
+    assert_eq!(
+        records.last().map(|record| record.kind),
+        Some(RecordKind::Remove)
+    );
+    assert_eq!(store.generations()?.len(), 1);
+    assert_eq!(store.get("key0")?, None);
+    assert_eq!(store.get("key1")?.as_deref(), Some(&*value));
+
+    // Compaction still runs when asked for.
+    store.clean_stale_data()?;
+    assert_eq!(store.dump_generation(2)?.len(), 99);
+
+    Ok(())
+}
+
+// Should open a store through `TryFrom<&Path>`.
+#[test]
+fn try_from_path() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let mut store = KvStore::try_from(temp_dir.path())?;
+    store.set("key1", "value1")?;
+    drop(store);
+
+    let mut store: KvStore = temp_dir.path().try_into()?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Generations retained by compaction should stay on disk without affecting reads.
+#[test]
+fn retain_generations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_exists = |gen: usize| temp_dir.path().join(format!("{}.log", gen)).exists();
+    let open = || {
+        KvStore::builder()
+            .retain_generations(1)
+            .open(temp_dir.path())
+    };
+
+    let mut store = open()?;
+    store.set("key1", "value1")?;
+    store.set("key2", "value1")?;
+    store.clean_stale_data()?;
+    store.set("key1", "value2")?;
+    store.remove("key2")?;
+    store.clean_stale_data()?;
+
+    // Generation 1 was pruned once generation 2 was retained in its place.
+    assert!(!log_exists(1));
+    assert!(log_exists(2));
+    assert_eq!(store.generations()?.len(), 1);
+    assert_eq!(store.generations()?[0].0, 3);
+    drop(store);
+
+    let mut store = open()?;
+    assert!(log_exists(2));
+    assert_eq!(store.get("key1")?, Some("value2".to_owned()));
+    assert_eq!(store.get("key2")?, None);
+    assert_eq!(store.stats()?.live_keys, 1);
+    drop(store);
+
+    // Without retention, opening removes them.
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(!log_exists(2));
+    assert_eq!(store.get("key1")?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Should return the stored record of a key verbatim.
+#[test]
+fn get_raw_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "value1")?;
+    store.set("key2", "value\n2")?;
+    assert_eq!(store.get_raw_record("missing")?, None);
+
+    let record = store.get_raw_record("key2")?.unwrap();
+    let payload_len = u32::from_le_bytes(record[..4].try_into().unwrap()) as usize;
+    assert_eq!(record.len(), 12 + payload_len);
+    let seq = u64::from_le_bytes(record[4..12].try_into().unwrap());
+    assert_eq!(store.sequence_of("key2"), Some(seq));
+
+    let command: serde_json::Value = serde_json::from_slice(&record[12..])?;
+    assert_eq!(command, serde_json::json!(["key2", "value\n2"]));
+
+    // The record is the one stored in the log.
+    let log = std::fs::read(temp_dir.path().join("1.log"))?;
+    assert!(log.ends_with(&record));
+
+    Ok(())
+}
+
+// Closing while compacting should leave a single generation of live records.
+#[test]
+fn close_compacting() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for iter in 0..20 {
+        for key_id in 0..50 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+        store.remove(format!("key{}", iter))?;
+    }
+    let live_bytes = store.compaction_estimate().live_bytes;
+    store.close_compacting()?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    let generations = store.generations()?;
+    assert_eq!(generations.len(), 1);
+    assert_eq!(generations[0].1, (FILE_HEADER_LEN + live_bytes) as u64);
+    assert_eq!(store.stats()?.stale_bytes, 0);
+    assert_eq!(store.get("key19")?, None);
+    assert_eq!(store.get("key0")?, Some("value19".to_owned()));
+
+    // An already compacted store is closed as is.
+    store.close_compacting()?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.generations()?, generations);
+
+    Ok(())
+}
+
+// Should read every key from the generation it was explicitly written to.
+#[test]
+fn set_in_generation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1", "value1")?;
+    store.set_in_generation("key2", "value2", 3)?;
+    store.set_in_generation("key3", "value3", 3)?;
+    store.set_in_generation("key1", "value4", 4)?;
+    store.set("key2", "value5")?;
+
+    let gens: Vec<usize> = store
+        .generations()?
+        .into_iter()
+        .map(|(gen, _)| gen)
+        .collect();
+    assert_eq!(gens, vec![1, 2, 3, 4]);
+    let keys = |store: &mut KvStore, gen: usize| -> Result<Vec<String>> {
+        Ok(store
+            .dump_generation(gen)?
+            .into_iter()
+            .map(|record| record.key)
+            .collect())
+    };
+    assert_eq!(keys(&mut store, 1)?, vec!["key1"]);
+    assert!(keys(&mut store, 2)?.is_empty());
+    assert_eq!(keys(&mut store, 3)?, vec!["key2", "key3"]);
+    assert_eq!(keys(&mut store, 4)?, vec!["key1", "key2"]);
+
+    for _ in 0..2 {
+        assert_eq!(store.get("key1")?, Some("value4".to_owned()));
+        assert_eq!(store.get("key2")?, Some("value5".to_owned()));
+        assert_eq!(store.get("key3")?, Some("value3".to_owned()));
+        store = KvStore::open(temp_dir.path())?;
+    }
+
+    Ok(())
+}
+
+// Should truncate a record torn after the last sync, and only such a record, when opening.
+#[test]
+fn torn_write_recovery() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_path = temp_dir.path().join("1.log");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1", "value1")?;
+    store.set("key2", "value2")?;
+    store.sync()?;
+    let synced_len = std::fs::metadata(&log_path)?.len();
+
+    store.set("key3", "value3")?;
+    store.set("key4", "value4")?;
+    store.flush()?;
+    drop(store);
+
+    // A crash leaves only part of the writes after the sync behind.
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(&log_path)?
+        .set_len(synced_len + 20)?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(std::fs::metadata(&log_path)?.len(), synced_len);
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3")?, None);
+    assert_eq!(store.get("key4")?, None);
+
+    store.set("key3", "value3")?;
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key3")?, Some("value3".to_owned()));
+    drop(store);
+
+    // Synced records are never dropped.
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(&log_path)?
+        .set_len(synced_len - 5)?;
+    assert!(KvStore::open(temp_dir.path()).is_err());
+
+    Ok(())
+}
+
+// A crash tearing writes made after a compaction should be recovered from on open,
+// even though the store was never synced explicitly.
+#[test]
+fn torn_write_after_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for iter in 0..3 {
+        for key_id in 0..20 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    store.clean_stale_data()?;
+    let (gen, compacted_len) = *store.generations()?.last().unwrap();
+
+    store.set("key20", "value0")?;
+    store.set("key21", "value0")?;
+    drop(store);
+
+    // A crash leaves only part of the writes after the compaction behind.
+    let log_path = temp_dir.path().join(format!("{}.log", gen));
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(&log_path)?
+        .set_len(compacted_len + 20)?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(std::fs::metadata(&log_path)?.len(), compacted_len);
+    assert_eq!(store.get("key19")?, Some("value2".to_owned()));
+    assert_eq!(store.get("key20")?, None);
+    assert_eq!(store.get("key21")?, None);
+
+    Ok(())
+}
+
+// A crash tearing the last write of a store that was never synced should be recovered
+// from on open.
+#[test]
+fn torn_write_without_sync() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_path = temp_dir.path().join("1.log");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "value1")?;
+    store.flush()?;
+    let flushed_len = std::fs::metadata(&log_path)?.len();
+    store.set("key2", "value2")?;
+    drop(store);
+
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(&log_path)?
+        .set_len(flushed_len + 20)?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(std::fs::metadata(&log_path)?.len(), flushed_len);
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2")?, None);
+
+    Ok(())
+}
+
+// A damaged record followed by intact ones wasn't torn by a crash, so opening should
+// fail instead of truncating the records after it, even if none of them were synced.
+#[test]
+fn corruption_before_unsynced_records() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_path = temp_dir.path().join("1.log");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "value1")?;
+    store.set("key2", "value2")?;
+    store.set("key3", "value3")?;
+    let offset = store.dump_generation(1)?[0].offset;
+    drop(store);
+
+    // Garbles the payload of the first record, right past its header.
+    let mut contents = std::fs::read(&log_path)?;
+    let log_len = contents.len() as u64;
+    contents[offset + 12] = b'x';
+    std::fs::write(&log_path, contents)?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::ReplayFailed {
+            offset: failed_at, ..
+        }) => assert_eq!(failed_at, offset),
+        other => panic!("expected a replay failure, got {:?}", other.map(|_| ())),
+    }
+    assert_eq!(std::fs::metadata(&log_path)?.len(), log_len);
+
+    Ok(())
+}
+
+// Writes three keys to a synced store and then garbles the record of the last one,
+// which opening the store doesn't recover from.
+fn corrupt_store(path: &Path) -> Result<()> {
+    let mut store = KvStore::open(path)?;
+    store.set("key1", "value1")?;
+    store.set("key2", "value2")?;
+    store.set("key3", "value3")?;
+    store.sync()?;
+    drop(store);
+
+    let log_path = path.join("1.log");
+    let mut contents = std::fs::read(&log_path)?;
+    let len = contents.len();
+    contents[len - 8..].copy_from_slice(&[0xff; 8]);
+    std::fs::write(&log_path, contents)?;
+
+    assert!(KvStore::open(path).is_err());
+    Ok(())
+}
+
+// Repairing should cut the log short at the first unreadable record.
+#[test]
+fn repair() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert!(matches!(
+        KvStore::repair(temp_dir.path()),
+        Err(KvsError::StoreNotFound(_))
+    ));
+
+    corrupt_store(temp_dir.path())?;
+    let len = std::fs::metadata(temp_dir.path().join("1.log"))?.len();
+
+    let (mut store, report) = KvStore::repair(temp_dir.path())?;
+    assert!(!report.is_clean());
+    assert_eq!(report.truncated.len(), 1);
+    let truncated = report.truncated[0];
+    assert_eq!(truncated.generation, 1);
+    assert_eq!(truncated.offset as u64 + truncated.dropped_bytes, len);
+    assert!(report.dropped_keys.is_empty());
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3")?, None);
+    drop(store);
+
+    let (_, report) = KvStore::repair(temp_dir.path())?;
+    assert!(report.is_clean());
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// `kvs repair` should leave a corrupted store readable and report what it fixed.
+#[test]
+fn cli_repair() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    corrupt_store(&temp_dir.path().join("data"))?;
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["repair", "--compact"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Truncated generation 1 at offset").and(contains("Recovered ")));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["repair"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("No corruption found").trim());
+
+    Ok(())
+}
+
+// Should reopen logfiles closed to stay within the limit of open readers.
+#[test]
+fn max_open_readers() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder()
+        .max_open_readers(2)
+        .open(temp_dir.path())?;
+
+    for gen in 1..=20 {
+        store.set_in_generation(format!("key{}", gen), format!("value{}", gen), gen)?;
+    }
+
+    for _ in 0..2 {
+        for gen in (1..=20).chain((1..=20).rev()) {
+            assert_eq!(
+                store.get(format!("key{}", gen))?,
+                Some(format!("value{}", gen))
+            );
+        }
+        assert_eq!(store.generations()?.len(), 20);
+
+        store = KvStore::builder()
+            .max_open_readers(2)
+            .open(temp_dir.path())?;
+    }
+
+    store.clean_stale_data()?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Should report operations taking at least the slow operation threshold.
+#[test]
+fn on_slow_op() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let reported = Arc::new(Mutex::new(Vec::new()));
+    let sink = Arc::clone(&reported);
+    let mut store = KvStore::builder()
+        .slow_op_threshold(Duration::ZERO)
+        .on_slow_op(move |kind, _| sink.lock().unwrap().push(kind))
+        .open(temp_dir.path())?;
+
+    store.set("key1", "value1")?;
+    store.get("key1")?;
+    store.get("key2")?;
+    store.remove("key1")?;
+    store.clean_stale_data()?;
+    assert_eq!(
+        *reported.lock().unwrap(),
+        vec![
+            OpKind::Set,
+            OpKind::Get,
+            OpKind::Get,
+            OpKind::Remove,
+            OpKind::Compact
+        ]
+    );
+    drop(store);
+
+    let sink = Arc::clone(&reported);
+    let mut store = KvStore::builder()
+        .slow_op_threshold(Duration::from_secs(3600))
+        .on_slow_op(move |kind, _| sink.lock().unwrap().push(kind))
+        .open(temp_dir.path())?;
+    store.set("key1", "value1")?;
+    store.get("key1")?;
+    assert_eq!(reported.lock().unwrap().len(), 5);
+    drop(store);
+
+    // Every setter reports its sets.
+    reported.lock().unwrap().clear();
+    let sink = Arc::clone(&reported);
+    let mut store = KvStore::builder()
+        .slow_op_threshold(Duration::ZERO)
+        .on_slow_op(move |kind, _| sink.lock().unwrap().push(kind))
+        .open(temp_dir.path())?;
+    store.set_tracked("key1", "value1")?;
+    store.set_nx("key2", "value2")?;
+    store.set_json("key3", &3)?;
+    store.set_many(vec![
+        ("key4".to_owned(), "value4".to_owned()),
+        ("key5".to_owned(), "value5".to_owned()),
+    ])?;
+    let mut batch = WriteBatch::new();
+    batch.set("key6", "value6");
+    store.write_batch(batch)?;
+    assert_eq!(*reported.lock().unwrap(), vec![OpKind::Set; 6]);
+
+    Ok(())
+}
+
+// Should write the compacted logfile in key order.
+#[test]
+fn compact_in_key_order() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder()
+        .compact_in_key_order(true)
+        .open(temp_dir.path())?;
+
+    for key_id in (0..100).map(|key_id| key_id * 37 % 100) {
+        store.set(format!("key{:03}", key_id), "value1")?;
+        store.set(format!("key{:03}", key_id), "value2")?;
+    }
+    store.clean_stale_data()?;
+
+    let keys: Vec<String> = store
+        .dump_generation(2)?
+        .into_iter()
+        .map(|record| record.key)
+        .collect();
+    let mut sorted = keys.clone();
+    sorted.sort();
+    assert_eq!(keys.len(), 100);
+    assert_eq!(keys, sorted);
+
+    for key_id in 0..100 {
+        assert_eq!(
+            store.get(format!("key{:03}", key_id))?,
+            Some("value2".to_owned())
+        );
+    }
+
+    Ok(())
+}
+
+// Should move a store over another directory, whether it exists or not.
+#[test]
+fn replace_dir() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let src = temp_dir.path().join("src");
+    let dst = temp_dir.path().join("dst");
+
+    let mut store = KvStore::open(&dst)?;
+    store.set("key1", "old")?;
+    store.set("key2", "old")?;
+    drop(store);
+
+    let mut store = KvStore::open(&src)?;
+    store.set("key1", "new")?;
+    drop(store);
+
+    KvStore::replace_dir(&src, &dst)?;
+    assert!(!src.exists());
+    let mut entries: Vec<_> = std::fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.map(|entry| entry.file_name()))
+        .collect::<std::io::Result<_>>()?;
+    entries.sort();
+    assert_eq!(entries, vec!["dst"]);
+
+    let mut store = KvStore::open(&dst)?;
+    assert_eq!(store.get("key1")?, Some("new".to_owned()));
+    assert_eq!(store.get("key2")?, None);
+    drop(store);
+
+    let moved = temp_dir.path().join("moved");
+    KvStore::replace_dir(&dst, &moved)?;
+    let mut store = KvStore::open(&moved)?;
+    assert_eq!(store.get("key1")?, Some("new".to_owned()));
+    drop(store);
+
+    assert!(matches!(
+        KvStore::replace_dir(&src, &moved),
+        Err(KvsError::NotADirectory(_))
+    ));
+
+    Ok(())
+}
+
+// Should write a compacted, openable copy of the store elsewhere.
+#[test]
+fn compact_into() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dest_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    for iter in 0..10 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    store.remove("key0".to_owned())?;
+
+    let dest = dest_dir.path().join("copy");
+    store.compact_into(&dest)?;
+    assert!(matches!(
+        store.compact_into(&dest),
+        Err(KvsError::StoreExists(_))
+    ));
+
+    assert_eq!(read_records(dest.join("1.log"))?.len(), 99);
+    assert_eq!(store.get("key1")?, Some("9".to_owned()));
+
+    let mut copy = KvStore::open_existing(&dest)?;
+    assert_eq!(copy.get("key0")?, None);
+    for key_id in 1..100 {
+        assert_eq!(copy.get(format!("key{}", key_id))?, Some("9".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Opening a store with a missing non-current generation should report that generation.
+#[test]
+fn open_detects_missing_generation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let value = "x".repeat(10 * 1024);
+
+    // Each reopen after exceeding the size threshold starts a new generation.
+    for gen in 1..=2 {
+        let mut store = KvStore::open(temp_dir.path())?;
+        for key_id in 0..110 {
+            store.set(format!("key{}-{}", gen, key_id), value.clone())?;
+        }
+    }
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    assert!(temp_dir.path().join("3.log").is_file());
+    std::fs::remove_file(temp_dir.path().join("2.log"))?;
+
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::MissingLogfile(2))
+    ));
+
+    Ok(())
+}
+
+// Should count the bytes read from and written to logfiles.
+#[test]
+fn io_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.io_stats(), IoStats::default());
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.flush()?;
+
+    let log_size = std::fs::metadata(temp_dir.path().join("1.log"))?.len();
+    assert_eq!(store.io_stats().bytes_written, log_size);
+    assert_eq!(store.io_stats().bytes_read, 0);
+
+    store.get("key1")?;
+    let stats = store.io_stats();
+    let record_size = log_size - FILE_HEADER_LEN as u64;
+    assert!(stats.bytes_read >= record_size && stats.bytes_read <= log_size);
+    assert_eq!(stats.bytes_written, log_size);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.io_stats().bytes_read, log_size);
+    assert_eq!(store.io_stats().bytes_written, 0);
+
+    Ok(())
+}
+
+// Overwrites and compactions should write more to logfiles than the data that was set.
+#[test]
+fn write_amplification() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.io_stats().write_amplification(), None);
+
+    store.set("key1", "value1")?;
+    assert_eq!(store.io_stats().logical_bytes_written, 10);
+
+    for iter in 0..100 {
+        store.set(format!("key{}", iter % 10), format!("value{}", iter))?;
+    }
+    store.flush()?;
+    let stats = store.io_stats();
+    let amplification = stats.write_amplification().unwrap();
+    assert!(amplification > 1.0);
+
+    store.clean_stale_data()?;
+    let compacted = store.io_stats();
+    assert_eq!(compacted.logical_bytes_written, stats.logical_bytes_written);
+    assert!(compacted.write_amplification().unwrap() > amplification);
+
+    Ok(())
+}
+
+// The compaction estimate should match what a real compaction does.
+#[test]
+fn compaction_estimate() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    for iter in 0..10 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    store.remove("key0".to_owned())?;
+    store.flush()?;
+
+    let estimate = store.compaction_estimate();
+    assert_eq!(estimate.generations, 1);
+    let before = std::fs::metadata(temp_dir.path().join("1.log"))?.len() as usize;
+    assert_eq!(
+        FILE_HEADER_LEN + estimate.reclaimable_bytes as usize + estimate.live_bytes,
+        before
+    );
+
+    assert_eq!(store.clean_stale_data()?, estimate.reclaimable_bytes);
+    let after = std::fs::metadata(temp_dir.path().join("2.log"))?.len() as usize;
+    assert_eq!(after, FILE_HEADER_LEN + estimate.live_bytes);
+
+    Ok(())
+}
+
+// Should merge all keys of another store, overwriting keys present in both.
+#[test]
+fn merge_from() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path().join("a"))?;
+    let mut other = KvStore::open(temp_dir.path().join("b"))?;
+
+    store.set("key1".to_owned(), "a1".to_owned())?;
+    store.set("key2".to_owned(), "a2".to_owned())?;
+    other.set("key2".to_owned(), "b2".to_owned())?;
+    other.set("key3".to_owned(), "b3".to_owned())?;
+    other.set("key4".to_owned(), "b4".to_owned())?;
+    other.remove("key4".to_owned())?;
+
+    assert_eq!(store.merge_from(&mut other)?, 2);
+
+    assert_eq!(store.get("key1")?, Some("a1".to_owned()));
+    assert_eq!(store.get("key2")?, Some("b2".to_owned()));
+    assert_eq!(store.get("key3")?, Some("b3".to_owned()));
+    assert_eq!(store.get("key4")?, None);
+    assert_eq!(other.get("key1")?, None);
+
+    Ok(())
+}
+
+// `set_nx` should only set absent keys and write nothing otherwise.
+#[test]
+fn set_nx() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let log_size = || {
+        std::fs::metadata(temp_dir.path().join("1.log"))
+            .expect("unable to read logfile metadata")
+            .len()
+    };
+
+    assert!(store.set_nx("key1".to_owned(), "value1".to_owned())?);
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    store.flush()?;
+    let size = log_size();
+
+    assert!(!store.set_nx("key1".to_owned(), "value2".to_owned())?);
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    store.flush()?;
+    assert_eq!(log_size(), size);
+    assert_eq!(store.compaction_estimate().reclaimable_bytes, 0);
+
+    Ok(())
+}
+
+// Opening a logfile written in a newer format version should fail with a clear error.
+#[test]
+fn open_unsupported_format() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let logfile = temp_dir.path().join("1.log");
+    let mut contents = std::fs::read(&logfile)?;
+    assert_eq!(&contents[..4], b"KVSL");
+    contents[4..6].copy_from_slice(&99u16.to_le_bytes());
+    std::fs::write(&logfile, contents)?;
+
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::UnsupportedFormat { found: 99, .. })
+    ));
+
+    Ok(())
+}
+
+// Logfiles record the codec their records are serialized with, and opening one
+// written with a codec this build lacks should fail instead of misreading it.
+#[test]
+fn open_unsupported_codec() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let logfile = temp_dir.path().join("1.log");
+    let mut contents = std::fs::read(&logfile)?;
+    assert_eq!(contents[6], 0);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    drop(store);
+
+    contents[6] = 1;
+    std::fs::write(&logfile, contents)?;
+
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::UnsupportedCodec(1))
+    ));
+
+    Ok(())
+}
+
+// Large compressible values should shrink on disk and round-trip unchanged.
+#[cfg(feature = "compression")]
+#[test]
+fn compressed_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let builder = || KvStore::builder().compress_values_above(1024);
+    let large = "{\"field\": \"some repetitive value\"}, ".repeat(4096);
+
+    let mut store = builder().open(temp_dir.path())?;
+    store.set("large".to_owned(), large.clone())?;
+    store.set("small".to_owned(), "value".to_owned())?;
+    store.flush()?;
+
+    let log_size = std::fs::metadata(temp_dir.path().join("1.log"))?.len();
+    assert!(log_size < large.len() as u64 / 10);
+    assert_eq!(store.get("large")?, Some(large.clone()));
+    assert_eq!(store.get("small")?, Some("value".to_owned()));
+
+    store.clean_stale_data()?;
+    drop(store);
+
+    let mut store = builder().open(temp_dir.path())?;
+    assert_eq!(store.get("large")?, Some(large.clone()));
+    drop(store);
+
+    // Compressed records can be read back without compressing new values.
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("large")?, Some(large));
+
+    Ok(())
+}
+
+// Should not write a record when setting an identical value with deduplication enabled.
+#[test]
+fn dedup_identical() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_path = temp_dir.path().join("1.log");
+    let mut store = KvStore::builder()
+        .dedup_identical(true)
+        .open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.flush()?;
+    let len = std::fs::metadata(&log_path)?.len();
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.flush()?;
+    assert_eq!(std::fs::metadata(&log_path)?.len(), len);
+    assert_eq!(read_records(&log_path)?.len(), 1);
+
+    let receipt = store.set_tracked("key1", "value1")?;
+    assert_eq!((receipt.generation, receipt.offset), (1, 8));
+    assert_eq!(store.set_counted("key1", "value1")?, 0);
+    store.flush()?;
+    assert_eq!(read_records(&log_path)?.len(), 1);
+    assert_eq!(store.compaction_estimate().reclaimable_bytes, 0);
+
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.flush()?;
+    assert_eq!(read_records(&log_path)?.len(), 2);
+    assert_eq!(store.get("key1")?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Should count buffered bytes until they are flushed.
+#[test]
+fn unflushed_bytes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.flush()?;
+    assert_eq!(store.unflushed_bytes(), 0);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let after_first = store.unflushed_bytes();
+    assert!(after_first > 0);
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert!(store.unflushed_bytes() > after_first);
+
+    store.flush()?;
+    assert_eq!(store.unflushed_bytes(), 0);
+
+    Ok(())
+}
+
+// Writes should only be reported durable if the flush policy synced them.
+#[test]
+fn set_tracked_durability() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    let receipt = store.set_tracked("key1", "value1")?;
+    assert_eq!(receipt.generation, 1);
+    assert_eq!(receipt.offset, FILE_HEADER_LEN);
+    assert!(!receipt.durable);
+    drop(store);
+
+    let mut store = KvStore::builder()
+        .flush_policy(FlushPolicy::Sync)
+        .open(temp_dir.path())?;
+    let receipt = store.set_tracked("key2", "value2")?;
+    assert_eq!(receipt.generation, 1);
+    assert!(receipt.offset > FILE_HEADER_LEN);
+    assert!(receipt.durable);
+
+    // A synced write has already reached the logfile.
+    let log_size = std::fs::metadata(temp_dir.path().join("1.log"))?.len();
+    assert!(log_size > receipt.offset as u64);
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// A store opened through a directory handle should keep using that directory after it
+// is moved away from its path.
+#[cfg(all(unix, feature = "dir-handle"))]
+#[test]
+fn open_dir() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store_path = temp_dir.path().join("store");
+    let moved_path = temp_dir.path().join("moved");
+    std::fs::create_dir(&store_path)?;
+
+    let mut store = KvStore::open_dir(std::fs::File::open(&store_path)?, &store_path)?;
+    store.set("key1", "value1")?;
+    assert_eq!(store.path(), store_path);
+
+    // Another directory takes the place of the store's.
+    std::fs::rename(&store_path, &moved_path)?;
+    std::fs::create_dir(&store_path)?;
+    store.set("key2", "value2")?;
+    store.clean_stale_data()?;
+    drop(store);
+
+    assert_eq!(std::fs::read_dir(&store_path)?.count(), 0);
+    let mut store = KvStore::open(&moved_path)?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+
+    let file_path = temp_dir.path().join("file");
+    std::fs::write(&file_path, "")?;
+    assert_eq!(
+        KvStore::open_dir(std::fs::File::open(&file_path)?, &file_path)
+            .map(|_| ())
+            .map_err(|err| err.kind()),
+        Err(ErrorKind::NotADirectory)
+    );
+
+    Ok(())
+}
+
+// Without support for directory handles, opening a store through one should fail
+// instead of falling back to its path.
+#[cfg(all(unix, not(feature = "dir-handle")))]
+#[test]
+fn open_dir_unsupported() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    match KvStore::open_dir(std::fs::File::open(temp_dir.path())?, temp_dir.path()) {
+        Err(KvsError::Io(err)) => assert_eq!(err.kind(), std::io::ErrorKind::Unsupported),
+        other => panic!("expected an unsupported error, got {:?}", other.map(|_| ())),
+    }
+    assert_eq!(std::fs::read_dir(temp_dir.path())?.count(), 0);
+
+    Ok(())
+}
+
+// Should return the stored value, or an empty string for a missing key.
+#[test]
+fn get_or_default() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1", "value1")?;
+    assert_eq!(store.get_or_default("key1")?, "value1");
+    assert_eq!(store.get_or_default("key2")?, "");
+
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get_or_default("key1")?, "");
+
+    Ok(())
+}
+
+// Should restore correct reads from an index that went out of sync with the log.
+#[test]
+fn rebuild_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.set("key0", "overwritten")?;
+    store.remove("key9".to_owned())?;
+    let estimate = store.compaction_estimate();
+
+    store.scramble_index();
+    assert_ne!(store.get("key1")?, Some("value1".to_owned()));
+
+    store.rebuild_index()?;
+    assert_eq!(store.get("key0")?, Some("overwritten".to_owned()));
+    for i in 1..9 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    assert_eq!(store.get("key9")?, None);
+    assert_eq!(store.compaction_estimate(), estimate);
+
+    // The store stays usable afterwards.
+    store.set("key10", "value10")?;
+    assert_eq!(store.get("key10")?, Some("value10".to_owned()));
+
+    Ok(())
+}
+
+// Preallocated space should grow the logfile without moving where records are written.
+#[test]
+fn preallocate() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let logfile = temp_dir.path().join("1.log");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.preallocate(64 * 1024)?;
+    assert_eq!(std::fs::metadata(&logfile)?.len(), 64 * 1024);
+
+    store.set("key1", "value1")?;
+    store.set("key2", "value2")?;
+    store.flush()?;
+    assert_eq!(std::fs::metadata(&logfile)?.len(), 64 * 1024);
+    assert_eq!(read_records(&logfile)?.len(), 2);
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    drop(store);
+
+    // Reopening should continue right after the last record.
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+    store.set("key3", "value3")?;
+    store.flush()?;
+
+    let records = read_records(&logfile)?;
+    assert_eq!(records.len(), 3);
+    assert_eq!(records[2], serde_json::json!(["key3", "value3"]));
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3")?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Driving a compactor step by step should keep every live key.
+#[test]
+fn compactor_steps() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    for iter in 0..5 {
+        for key_id in 0..100 {
+            store.set(
+                format!("key{}", key_id),
+                format!("value{}-{}", key_id, iter),
+            )?;
+        }
+    }
+    let estimate = store.compaction_estimate();
+
+    // An abandoned compaction leaves the store untouched.
+    let mut compactor = store.compactor(256)?;
+    assert!(matches!(compactor.step()?, CompactStep::InProgress { .. }));
+    drop(compactor);
+    assert_eq!(store.compaction_estimate(), estimate);
+
+    let mut compactor = store.compactor(256)?;
+    let mut steps = 0;
+    let reclaimed = loop {
+        steps += 1;
+        match compactor.step()? {
+            CompactStep::InProgress {
+                copied_bytes,
+                remaining_bytes,
+            } => {
+                assert!(copied_bytes >= 256);
+                assert!(remaining_bytes > 0);
+            }
+            CompactStep::Done { reclaimed_bytes } => break reclaimed_bytes,
+        }
+    };
+    assert!(steps > 1);
+    assert_eq!(
+        compactor.step()?,
+        CompactStep::Done {
+            reclaimed_bytes: reclaimed
+        }
+    );
+    drop(compactor);
+
+    assert_eq!(reclaimed, estimate.reclaimable_bytes);
+    assert_eq!(store.compaction_estimate().reclaimable_bytes, 0);
+
+    let mut files: Vec<_> = std::fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.map(|entry| entry.file_name()))
+        .collect::<std::io::Result<_>>()?;
+    files.sort();
+    assert_eq!(files, vec!["2.log", "KVSTORE", "MANIFEST", "SYNCED"]);
+
+    for key_id in 0..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}-4", key_id))
+        );
+    }
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}-4", key_id))
+        );
+    }
+
+    Ok(())
+}
+
+// Should report the generations on disk with their logfile sizes.
+#[test]
+fn generations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_size = |gen: usize| -> Result<u64> {
+        Ok(std::fs::metadata(temp_dir.path().join(format!("{}.log", gen)))?.len())
+    };
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "value1")?;
+    store.set("key1", "value2")?;
+    store.flush()?;
+    assert_eq!(store.generations()?, vec![(1, log_size(1)?)]);
+
+    store.clean_stale_data()?;
+    store.set("key2", "value1")?;
+    store.flush()?;
+    assert_eq!(store.generations()?, vec![(2, log_size(2)?)]);
+    drop(store);
+
+    // Pre-create a large current generation, so that reopening starts a new one.
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(temp_dir.path().join("2.log"))?
+        .set_len(2 * 1024 * 1024)?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.generations()?,
+        vec![(2, 2 * 1024 * 1024), (3, log_size(3)?)]
+    );
+
+    Ok(())
+}
+
+// Each compaction should prune the generations retained by the previous ones, which the
+// store keeps track of without scanning the directory.
+#[test]
+fn retained_generations_pruned() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let logs_on_disk = || -> Result<Vec<usize>> {
+        let mut gens: Vec<usize> = std::fs::read_dir(temp_dir.path())?
+            .filter_map(|entry| {
+                let name = entry.ok()?.file_name().into_string().ok()?;
+                name.strip_suffix(".log")?.parse().ok()
+            })
+            .collect();
+        gens.sort_unstable();
+        Ok(gens)
+    };
+
+    let mut store = KvStore::builder()
+        .retain_generations(2)
+        .open(temp_dir.path())?;
+    let expected = [vec![1, 2], vec![1, 2, 3], vec![2, 3, 4], vec![3, 4, 5]];
+    for (iter, expected) in expected.iter().enumerate() {
+        store.set("key1", format!("value{}", iter + 1))?;
+        store.clean_stale_data()?;
+        assert_eq!(&logs_on_disk()?, expected);
+    }
+    drop(store);
+
+    // Reopening picks up where the last compaction left off.
+    let mut store = KvStore::builder()
+        .retain_generations(2)
+        .open(temp_dir.path())?;
+    assert_eq!(logs_on_disk()?, vec![3, 4, 5]);
+    store.set("key1", "value5")?;
+    store.clean_stale_data()?;
+    assert_eq!(logs_on_disk()?, vec![4, 5, 6]);
+    assert_eq!(store.get("key1")?, Some("value5".to_owned()));
+
+    Ok(())
+}
+
+// The generations known to the store should match the logfiles on disk.
+#[test]
+fn generations_match_disk() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let on_disk = || -> Result<Vec<usize>> {
+        let mut gens: Vec<usize> = std::fs::read_dir(temp_dir.path())?
+            .filter_map(|entry| {
+                let name = entry.ok()?.file_name().into_string().ok()?;
+                name.strip_suffix(".log")?.parse().ok()
+            })
+            .collect();
+        gens.sort_unstable();
+        Ok(gens)
+    };
+    let known = |store: &KvStore| -> Result<Vec<usize>> {
+        Ok(store
+            .generations()?
+            .into_iter()
+            .map(|(gen, _)| gen)
+            .collect())
+    };
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(known(&store)?, on_disk()?);
+
+    // Compacting over a megabyte of live data starts a new generation after it.
+    let value = "x".repeat(1024);
+    for key_id in 0..1100 {
+        store.set(format!("key{}", key_id), value.as_str())?;
+    }
+    store.set("key0", "overwritten")?;
+    store.clean_stale_data()?;
+    assert_eq!(known(&store)?, vec![2, 3]);
+    assert_eq!(known(&store)?, on_disk()?);
+
+    store.clean_stale_data()?;
+    assert_eq!(known(&store)?, vec![4, 5]);
+    assert_eq!(known(&store)?, on_disk()?);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(known(&store)?, vec![4, 5]);
+    assert_eq!(known(&store)?, on_disk()?);
+
+    Ok(())
+}
+
+// Compaction only replaces the stale generations once the compacted logfile is durable.
+// A crash can't be simulated here, so this reproduces what the store directory may look
+// like if the process dies at each point of a compaction and checks that it opens intact:
+// - before the rename, only the temporary logfile was added next to the old generations,
+// - after the rename, the old generations may not have been removed yet, or only the
+//   oldest ones were.
+#[test]
+fn compaction_crash_consistency() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store_dir = temp_dir.path().join("store");
+
+    let mut store = KvStore::open(&store_dir)?;
+    for iter in 0..3 {
+        for key_id in 0..50 {
+            store.set(
+                format!("key{}", key_id),
+                format!("value{}-{}", key_id, iter),
+            )?;
+        }
+        drop(store);
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(store_dir.join(format!("{}.log", iter + 1)))?;
+        // Starts a new generation when reopening.
+        file.set_len(2 * 1024 * 1024)?;
+        store = KvStore::open(&store_dir)?;
+    }
+    store.remove("key0".to_owned())?;
+    store.flush()?;
+
+    let check = |dir: &Path| -> Result<()> {
+        let mut store = KvStore::open(dir)?;
+        assert_eq!(store.get("key0")?, None);
+        for key_id in 1..50 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(format!("value{}-2", key_id))
+            );
+        }
+        Ok(())
+    };
+    let snapshot = |name: &str| -> Result<std::path::PathBuf> {
+        let dest = temp_dir.path().join(name);
+        std::fs::create_dir(&dest)?;
+        for entry in std::fs::read_dir(&store_dir)? {
+            let entry = entry?;
+            std::fs::copy(entry.path(), dest.join(entry.file_name()))?;
+        }
+        Ok(dest)
+    };
+
+    let old_gens = snapshot("old")?;
+
+    let mut compactor = store.compactor(128)?;
+    compactor.step()?;
+    check(&snapshot("before_rename")?)?;
+    while let CompactStep::InProgress { .. } = compactor.step()? {}
+    drop(compactor);
+    drop(store);
+
+    // Compaction created generation 5 from generations 1 to 4.
+    let after_rename = snapshot("after_rename")?;
+    for gen in 1..=4 {
+        let name = format!("{}.log", gen);
+        std::fs::copy(old_gens.join(&name), after_rename.join(&name))?;
+    }
+    check(&after_rename)?;
+
+    let partially_removed = snapshot("partially_removed")?;
+    for gen in 3..=4 {
+        let name = format!("{}.log", gen);
+        std::fs::copy(old_gens.join(&name), partially_removed.join(&name))?;
+    }
+    check(&partially_removed)?;
+
+    check(&store_dir)
+}
+
+// Should trust the manifest over the directory listing, removing orphaned generations.
+#[test]
+fn open_removes_orphaned_generations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let orphan_dir = temp_dir.path().join("orphan");
+
+    let mut orphan = KvStore::open(&orphan_dir)?;
+    orphan.set("ghost".to_owned(), "boo".to_owned())?;
+    orphan.set("key1".to_owned(), "stale".to_owned())?;
+    drop(orphan);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    for iter in 0..2 {
+        store.set("key1".to_owned(), format!("value{}", iter))?;
+    }
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.compactor(usize::MAX)?.step()?;
+    drop(store);
+    assert!(temp_dir.path().join("MANIFEST").exists());
+
+    // Simulates a crash right after the manifest was updated, leaving a stale generation.
+    let orphan_path = temp_dir.path().join("1.log");
+    assert!(!orphan_path.exists());
+    std::fs::copy(orphan_dir.join("1.log"), &orphan_path)?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(!orphan_path.exists());
+    assert_eq!(store.get("ghost")?, None);
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2")?, None);
+
+    Ok(())
+}
+
+// Should update integer values in place, starting from 0 for missing keys.
+#[test]
+fn increment_and_decrement() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    assert_eq!(store.increment("counter", 5)?, 5);
+    assert_eq!(store.increment("counter", 2)?, 7);
+    assert_eq!(store.decrement("counter", 10)?, -3);
+    assert_eq!(store.decrement("other", 1)?, -1);
+    assert_eq!(store.get("counter")?, Some("-3".to_owned()));
+
+    store.set("existing", "41")?;
+    assert_eq!(store.increment("existing", 1)?, 42);
+
+    store.set("max", i64::MAX.to_string())?;
+    assert!(matches!(
+        store.increment("max", 1),
+        Err(KvsError::IntegerOverflow(key)) if key == "max"
+    ));
+    assert!(matches!(
+        store.decrement("counter", i64::MAX),
+        Err(KvsError::IntegerOverflow(_))
+    ));
+    assert_eq!(store.get("max")?, Some(i64::MAX.to_string()));
+
+    store.set("text", "value")?;
+    assert!(matches!(
+        store.increment("text", 1),
+        Err(KvsError::NotAnInteger(key)) if key == "text"
+    ));
+    assert_eq!(store.get("text")?, Some("value".to_owned()));
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.increment("counter", 3)?, 0);
+
+    Ok(())
+}
+
+// Views with different prefixes should not see each other's keys.
+#[test]
+fn prefixed_views() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    let mut tenant_a = store.with_prefix("a/");
+    tenant_a.set("key1", "value-a")?;
+    tenant_a.set("key2", "only-a")?;
+    assert_eq!(tenant_a.prefix(), "a/");
+
+    let mut tenant_b = store.with_prefix("b/");
+    tenant_b.set("key1", "value-b")?;
+    assert_eq!(tenant_b.get("key1")?, Some("value-b".to_owned()));
+    assert_eq!(tenant_b.get("key2")?, None);
+    assert!(!tenant_b.remove("key2")?);
+
+    let mut tenant_a = store.with_prefix("a/");
+    assert_eq!(tenant_a.get("key1")?, Some("value-a".to_owned()));
+    assert!(tenant_a.remove("key1")?);
+    assert_eq!(tenant_a.get("key1")?, None);
+
+    assert_eq!(store.get("2:a/key2")?, Some("only-a".to_owned()));
+    assert_eq!(store.get("2:b/key1")?, Some("value-b".to_owned()));
+    assert_eq!(store.get("key1")?, None);
+
+    Ok(())
+}
+
+// Keys crafted to reach into another prefix, or outside of the view, should stay isolated.
+#[test]
+fn prefixed_views_isolation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("a:b", "raw")?;
+    store.with_prefix("a").set(":b", "prefix-a")?;
+    store.with_prefix("a:").set("b", "prefix-a:")?;
+    store.with_prefix("").set("a:b", "empty-prefix")?;
+    store.with_prefix("1:a").set(":b", "prefix-1:a")?;
+
+    assert_eq!(store.get("a:b")?, Some("raw".to_owned()));
+    assert_eq!(
+        store.with_prefix("a").get(":b")?,
+        Some("prefix-a".to_owned())
+    );
+    assert_eq!(
+        store.with_prefix("a:").get("b")?,
+        Some("prefix-a:".to_owned())
+    );
+    assert_eq!(
+        store.with_prefix("").get("a:b")?,
+        Some("empty-prefix".to_owned())
+    );
+    assert_eq!(
+        store.with_prefix("1:a").get(":b")?,
+        Some("prefix-1:a".to_owned())
+    );
+    assert_eq!(store.with_prefix("a").get("b")?, None);
+    assert_eq!(store.with_prefix("a:").get(":b")?, None);
+
+    assert!(store.with_prefix("a").remove(":b")?);
+    assert_eq!(store.get("a:b")?, Some("raw".to_owned()));
+    assert_eq!(
+        store.with_prefix("a:").get("b")?,
+        Some("prefix-a:".to_owned())
+    );
+
+    Ok(())
+}
+
+// Streaming a value should yield the same bytes as getting it, including escaped characters.
+#[test]
+fn get_reader_streams_value() -> Result<()> {
+    use std::io::Read;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    let large: String = (0..20_000)
+        .map(|i| format!("line {} \"quoted\" \\ tab\t żółć 🦀\u{1}\n", i))
+        .collect();
+    store.set("large", large.clone())?;
+    store.set("key \"with\" quotes", "small")?;
+    store.remove("removed".to_owned())?;
+
+    let mut streamed = Vec::new();
+    store
+        .get_reader("large")?
+        .expect("value should exist")
+        .read_to_end(&mut streamed)?;
+    assert_eq!(streamed, large.as_bytes());
+
+    // Works with a reader buffer smaller than an escaped character.
+    let mut reader = store
+        .get_reader("key \"with\" quotes")?
+        .expect("value should exist");
+    let mut byte = [0];
+    let mut streamed = Vec::new();
+    while reader.read(&mut byte)? == 1 {
+        streamed.push(byte[0]);
+    }
+    assert_eq!(streamed, b"small");
+    drop(reader);
+
+    assert!(store.get_reader("removed")?.is_none());
+    assert!(store.get_reader("missing")?.is_none());
+
+    Ok(())
+}
+
+// Every error variant should map to its own kind.
+#[test]
+fn error_kinds() -> Result<()> {
+    let io = std::io::Error::from(std::io::ErrorKind::NotFound);
+    let serde = serde_json::from_str::<u32>("").unwrap_err();
+
+    let errors = vec![
+        (KvsError::Io(io), ErrorKind::Io),
+        (KvsError::Serde(serde), ErrorKind::Serde),
+        (KvsError::MissingLogfile(3), ErrorKind::MissingLogfile),
+        (
+            KvsError::UnexpectedCommand {
+                expected: "set",
+                got: "remove",
+            },
+            ErrorKind::UnexpectedCommand,
+        ),
+        (
+            KvsError::Corruption { gen: 1, offset: 8 },
+            ErrorKind::Corruption,
+        ),
+        (KvsError::StoreExists("data".into()), ErrorKind::StoreExists),
+        (
+            KvsError::UnsupportedFormat {
+                found: 2,
+                expected: 1,
+            },
+            ErrorKind::UnsupportedFormat,
+        ),
+        (
+            KvsError::NotAnInteger("key".to_owned()),
+            ErrorKind::NotAnInteger,
+        ),
+        (
+            KvsError::IntegerOverflow("key".to_owned()),
+            ErrorKind::IntegerOverflow,
+        ),
+        (
+            KvsError::ReadOnlyFilesystem("data".into()),
+            ErrorKind::ReadOnlyFilesystem,
+        ),
+        (
+            KvsError::SequenceUnavailable(1),
+            ErrorKind::SequenceUnavailable,
+        ),
+        (
+            KvsError::StoreNotFound("data".into()),
+            ErrorKind::StoreNotFound,
+        ),
+        (
+            KvsError::NotADirectory("data".into()),
+            ErrorKind::NotADirectory,
+        ),
+        (
+            KvsError::QuotaExceeded {
+                live_bytes: 2,
+                limit: 1,
+            },
+            ErrorKind::QuotaExceeded,
+        ),
+        (KvsError::UnsupportedCodec(1), ErrorKind::UnsupportedCodec),
+        (
+            KvsError::ReplayFailed {
+                gen: 1,
+                offset: 8,
+                source: serde_json::from_str::<u32>("").unwrap_err(),
+            },
+            ErrorKind::ReplayFailed,
+        ),
+        (
+            KvsError::AmbiguousGeneration(2),
+            ErrorKind::AmbiguousGeneration,
+        ),
+        (
+            KvsError::NotAnObject("key".to_owned()),
+            ErrorKind::NotAnObject,
+        ),
+    ];
+    for (error, kind) in &errors {
+        assert_eq!(error.kind(), *kind);
+    }
+    assert_ne!(ErrorKind::MissingLogfile, ErrorKind::UnexpectedCommand);
+
+    // Works on errors returned by the store.
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key", "value")?;
+    let err = store.increment("key", 1).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::NotAnInteger);
+
+    Ok(())
+}
+
+// Errors should be classified by the I/O failure they report.
+#[test]
+fn error_predicates() {
+    use std::io;
+
+    let not_found = KvsError::Io(io::Error::from(io::ErrorKind::NotFound));
+    assert!(not_found.is_not_found());
+    assert!(!not_found.is_permission_denied());
+
+    let denied = KvsError::Io(io::Error::from(io::ErrorKind::PermissionDenied));
+    assert!(denied.is_permission_denied());
+    assert!(!denied.is_not_found());
+
+    let other = KvsError::Io(io::Error::from(io::ErrorKind::UnexpectedEof));
+    assert!(!other.is_not_found());
+    assert!(!other.is_permission_denied());
+
+    assert!(KvsError::StoreNotFound("data".into()).is_not_found());
+    assert!(KvsError::ReadOnlyFilesystem("data".into()).is_permission_denied());
+    assert!(!KvsError::MissingLogfile(3).is_not_found());
+}
+
+// Should discard every write after a given sequence number.
+#[test]
+fn truncate_to() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1", "value1")?;
+    store.set("key2", "value1")?;
+    let seq = store.sequence_of("key2").unwrap();
+    store.set("key1", "value2")?;
+    store.remove("key2".to_owned())?;
+    store.set("key3", "value1")?;
+
+    store.truncate_to(seq)?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3")?, None);
+    assert_eq!(read_records(temp_dir.path().join("1.log"))?.len(), 2);
+
+    // New writes continue after the truncated log.
+    store.set("key4", "value1")?;
+    assert!(store.sequence_of("key4").unwrap() > seq);
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3")?, None);
+    assert_eq!(store.get("key4")?, Some("value1".to_owned()));
+
+    // Writes in an older generation are out of reach.
+    drop(store);
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(temp_dir.path().join("1.log"))?
+        .set_len(2 * 1024 * 1024)?;
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "value3")?;
+    assert!(matches!(
+        store.truncate_to(seq),
+        Err(KvsError::SequenceUnavailable(s)) if s == seq
+    ));
+    assert_eq!(store.get("key1")?, Some("value3".to_owned()));
+    assert_eq!(store.get("key4")?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Should tell whether any key starts with a given prefix.
+#[test]
+fn contains_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(!store.contains_prefix(""));
+
+    store.set("user:1", "value1")?;
+    store.set("user:2", "value2")?;
+    store.set("session:1", "value3")?;
+
+    assert!(store.contains_prefix("user:"));
+    assert!(store.contains_prefix("session:1"));
+    assert!(store.contains_prefix(""));
+    assert!(!store.contains_prefix("users"));
+    assert!(!store.contains_prefix("session:12"));
+
+    store.remove("session:1".to_owned())?;
+    assert!(!store.contains_prefix("session:"));
+
+    Ok(())
+}
+
+// Should round-trip values through JSON.
+#[test]
+fn get_and_set_json() -> Result<()> {
+    #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+    struct User {
+        name: String,
+        age: u32,
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    let user = User {
+        name: "Alice".to_owned(),
+        age: 30,
+    };
+    store.set_json("user:1", &user)?;
+    assert_eq!(store.get_json::<_, User>("user:1")?, Some(user));
+    assert_eq!(
+        store.get("user:1")?,
+        Some(r#"{"name":"Alice","age":30}"#.to_owned())
+    );
+    assert_eq!(store.get_json::<_, User>("user:2")?, None);
+
+    store.set("invalid", "not json")?;
+    assert!(matches!(
+        store.get_json::<_, User>("invalid"),
+        Err(KvsError::Serde(_))
+    ));
+
+    Ok(())
+}
+
+// Opening a store in a directory that can't be written to should fail with a clear error.
+#[cfg(unix)]
+#[test]
+fn open_read_only_directory() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store_dir = temp_dir.path().join("store");
+
+    let mut store = KvStore::open(&store_dir)?;
+    store.set("key1", "value1")?;
+    drop(store);
+
+    std::fs::set_permissions(&store_dir, std::fs::Permissions::from_mode(0o555))?;
+    std::fs::set_permissions(
+        store_dir.join("1.log"),
+        std::fs::Permissions::from_mode(0o444),
+    )?;
+
+    // Permissions don't apply to privileged users, which can write anyway.
+    if std::fs::OpenOptions::new()
+        .write(true)
+        .open(store_dir.join("1.log"))
+        .is_err()
+    {
+        assert!(matches!(
+            KvStore::open(&store_dir),
+            Err(KvsError::ReadOnlyFilesystem(path)) if path == store_dir
+        ));
+        assert!(matches!(
+            KvStore::open(store_dir.join("nested")),
+            Err(KvsError::ReadOnlyFilesystem(_))
+        ));
+    }
+
+    std::fs::set_permissions(&store_dir, std::fs::Permissions::from_mode(0o755))?;
+
+    Ok(())
+}
+
+// Should only write tombstones for keys that are present.
+#[test]
+fn remove_many() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1", "value1")?;
+    store.set("key2", "value2")?;
+    store.set("key3", "value3")?;
+
+    let removed = store.remove_many(vec!["key1", "missing", "key3", "key1"])?;
+    assert_eq!(removed, 2);
+    assert_eq!(store.get("key1")?, None);
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3")?, None);
+    assert_eq!(store.remove_many(Vec::<String>::new())?, 0);
+    store.flush()?;
+
+    let records = read_records(temp_dir.path().join("1.log"))?;
+    assert_eq!(records.len(), 5);
+    assert_eq!(records[3], serde_json::json!("key1"));
+    assert_eq!(records[4], serde_json::json!("key3"));
+
+    Ok(())
+}
+
+// Should apply all writes of a batch in order.
+#[test]
+fn write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "value1")?;
+
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key2", "value2")
+        .remove("key1")
+        .remove("missing")
+        .set("key2", "value3");
+    assert_eq!(batch.len(), 4);
+    store.write_batch(batch)?;
+
+    assert_eq!(store.get("key1")?, None);
+    assert_eq!(store.get("key2")?, Some("value3".to_owned()));
+    store.flush()?;
+    assert_eq!(read_records(temp_dir.path().join("1.log"))?.len(), 4);
+
+    Ok(())
+}
+
+// A batch should be synced once under the sync flush policy, whatever its size.
+#[test]
+fn write_batch_syncs_once() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (sender, receiver) = std::sync::mpsc::channel();
+    let mut store = KvStore::builder()
+        .flush_policy(FlushPolicy::Sync)
+        .on_commit(sender)
+        .open(temp_dir.path())?;
+
+    for size in [1, 10, 100] {
+        let mut batch = WriteBatch::new();
+        for key_id in 0..size {
+            batch.set(format!("key{}", key_id), format!("value{}", size));
+        }
+        batch.remove("key0");
+
+        let syncs = store.io_stats().syncs;
+        store.write_batch(batch)?;
+        assert_eq!(store.io_stats().syncs, syncs + 1);
+        assert_eq!(receiver.try_iter().count(), size + 1);
+    }
+
+    store.set("key1", "value1")?;
+    store.remove("key1")?;
+    assert_eq!(store.io_stats().syncs, 5);
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0")?, None);
+    assert_eq!(store.get("key99")?, Some("value100".to_owned()));
+
+    Ok(())
+}
+
+// Reads within a transaction should see its own changes before they're committed.
+#[test]
+fn transaction_read_your_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "value1")?;
+    store.set("key2", "value2")?;
+
+    let mut transaction = store.begin();
+    transaction.set("key1", "staged");
+    transaction.set("key3", "value3");
+    assert!(transaction.remove("key2"));
+    assert!(!transaction.remove("key2"));
+    assert!(!transaction.remove("missing"));
+
+    assert_eq!(transaction.get("key1")?, Some("staged".to_owned()));
+    assert_eq!(transaction.get("key2")?, None);
+    assert_eq!(transaction.get("key3")?, Some("value3".to_owned()));
+    transaction.set("key2", "restaged");
+    assert_eq!(transaction.get("key2")?, Some("restaged".to_owned()));
+    drop(transaction);
+
+    // Nothing reached the store.
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3")?, None);
+
+    Ok(())
+}
+
+// Committed changes should be written to the log and persist.
+#[test]
+fn transaction_commit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "value1")?;
+    store.set("key2", "value2")?;
+
+    let mut transaction = store.begin();
+    transaction.set("key1", "committed");
+    transaction.remove("key2");
+    transaction.set("key3", "value3");
+    transaction.commit()?;
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("committed".to_owned()));
+    assert_eq!(store.get("key2")?, None);
+    assert_eq!(store.get("key3")?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Rolled back changes should be discarded without writing anything.
+#[test]
+fn transaction_rollback() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "value1")?;
+
+    let mut transaction = store.begin();
+    transaction.set("key1", "rolled back");
+    transaction.remove("key1");
+    transaction.set("key2", "value2");
+    transaction.rollback();
+
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2")?, None);
+    store.flush()?;
+    assert_eq!(read_records(temp_dir.path().join("1.log"))?.len(), 1);
+
+    Ok(())
+}
+
+// Syncing should only reach the disk if something was written since the last sync.
+#[test]
+fn sync_skips_clean_writer() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.sync()?;
+    store.flush()?;
+    assert_eq!(store.io_stats().syncs, 0);
+
+    store.set("key1", "value1")?;
+    store.sync()?;
+    assert_eq!(store.io_stats().syncs, 1);
+
+    let written = store.io_stats().bytes_written;
+    store.flush()?;
+    store.sync()?;
+    assert_eq!(store.io_stats().syncs, 1);
+    assert_eq!(store.io_stats().bytes_written, written);
+
+    // A flushed write still needs to be synced.
+    store.remove("key1".to_owned())?;
+    store.flush()?;
+    store.sync()?;
+    assert_eq!(store.io_stats().syncs, 2);
+
+    Ok(())
+}
+
+// Should visit every key with its value, stopping at the first error.
+#[test]
+fn for_each() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    let mut total = 0;
+    for key_id in 0..100 {
+        let value = "x".repeat(key_id);
+        total += value.len();
+        store.set(format!("key{}", key_id), value)?;
+    }
+    store.set("key0", "overwritten")?;
+    total += "overwritten".len();
+    store.remove("key1".to_owned())?;
+    total -= 1;
+
+    let mut visited = 0;
+    let mut lengths = 0;
+    store.for_each(|key, value| {
+        assert!(key.starts_with("key"));
+        visited += 1;
+        lengths += value.len();
+        Ok(())
+    })?;
+    assert_eq!(visited, 99);
+    assert_eq!(lengths, total);
+
+    let mut visited = 0;
+    let result = store.for_each(|_, _| {
+        visited += 1;
+        if visited == 10 {
+            Err(KvsError::MissingLogfile(0))
+        } else {
+            Ok(())
+        }
+    });
+    assert!(matches!(result, Err(KvsError::MissingLogfile(0))));
+    assert_eq!(visited, 10);
+
+    // The store stays usable after stopping early.
+    assert_eq!(store.get("key2")?, Some("xx".to_owned()));
+
+    Ok(())
+}
+
+// Should find the keys set to a given value, including overwritten ones.
+#[test]
+fn contains_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1", "shared")?;
+    store.set("key2", "shared")?;
+    store.set("key3", "shared")?;
+    store.set("key4", "unique")?;
+    store.set("key3", "other")?;
+    store.set("key5", "stale")?;
+    store.set("key5", "fresh")?;
+
+    assert!(store.contains_value("shared")?);
+    assert!(store.contains_value("unique")?);
+    assert!(!store.contains_value("stale")?);
+    assert!(!store.contains_value("missing")?);
+
+    let mut keys = store.keys_with_value("shared")?;
+    keys.sort();
+    assert_eq!(keys, vec!["key1", "key2"]);
+    assert!(store.keys_with_value("missing")?.is_empty());
+
+    // The store stays usable after stopping early.
+    assert_eq!(store.get("key4")?, Some("unique".to_owned()));
+
+    Ok(())
+}
+
+// The Bloom filter should never miss a live key, and rarely report an absent one.
+#[test]
+fn bloom_filter() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder()
+        .bloom_filter(1000, 0.01)
+        .open(temp_dir.path())?;
+    assert!(KvStore::open(TempDir::new()?.path())?
+        .bloom_filter()
+        .is_none());
+
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+
+    for _ in 0..2 {
+        for key_id in 0..1000 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(format!("value{}", key_id))
+            );
+        }
+
+        assert_eq!(store.get("absent")?, None);
+
+        let bloom = store.bloom_filter().expect("bloom filter is enabled");
+        let false_positives = (0..10_000)
+            .filter(|key_id| bloom.may_contain(&format!("absent{}", key_id)))
+            .count();
+        assert!(false_positives < 300, "{} false positives", false_positives);
+
+        let serialized = serde_json::to_string(bloom)?;
+        let deserialized: kvs::BloomFilter = serde_json::from_str(&serialized)?;
+        assert_eq!(&deserialized, bloom);
+
+        drop(store);
+        store = KvStore::builder()
+            .bloom_filter(1000, 0.01)
+            .open(temp_dir.path())?;
+    }
+
+    Ok(())
+}
+
+// Should count the bytes every write appends to the log.
+#[test]
+fn set_and_remove_counted() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_path = temp_dir.path().join("1.log");
+    let mut store = KvStore::builder()
+        .dedup_identical(true)
+        .open(temp_dir.path())?;
+    store.flush()?;
+
+    let mut expected_len = std::fs::metadata(&log_path)?.len();
+    for written in [
+        store.set_counted("key1", "value1")?,
+        store.set_counted("key2", "x".repeat(1000))?,
+        store.set_counted("key1", "value2")?,
+        store.remove_counted("key2")?,
+        store.remove_counted("key3")?,
+    ] {
+        assert!(written > 0);
+        expected_len += written as u64;
+    }
+    assert_eq!(store.set_counted("key1", "value2")?, 0);
+
+    store.flush()?;
+    assert_eq!(std::fs::metadata(&log_path)?.len(), expected_len);
+
+    Ok(())
+}
+
+// Should reject sets once the live records reach the quota.
+#[test]
+fn max_live_bytes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder()
+        .max_live_bytes(4096)
+        .open(temp_dir.path())?;
+    let value = "x".repeat(100);
+
+    let mut stored = 0;
+    let err = loop {
+        match store.set(format!("key{}", stored), value.as_str()) {
+            Ok(()) => stored += 1,
+            Err(err) => break err,
+        }
+    };
+    assert_eq!(err.kind(), ErrorKind::QuotaExceeded);
+    assert!(stored > 30 && stored < 40, "{} keys stored", stored);
+    assert_eq!(store.get(format!("key{}", stored))?, None);
+
+    // Overwriting with a value of the same size doesn't grow the live records.
+    store.set("key0", "y".repeat(100))?;
+    assert_eq!(
+        store.set("key0", "y".repeat(200)).map_err(|err| err.kind()),
+        Err(ErrorKind::QuotaExceeded)
+    );
+
+    store.remove("key1")?;
+    store.set(format!("key{}", stored), value.as_str())?;
+    drop(store);
+
+    let mut store = KvStore::builder()
+        .max_live_bytes(4096)
+        .open(temp_dir.path())?;
+    assert_eq!(store.get("key0")?, Some("y".repeat(100)));
+    assert_eq!(
+        store
+            .set("another", value.as_str())
+            .map_err(|err| err.kind()),
+        Err(ErrorKind::QuotaExceeded)
+    );
+
+    Ok(())
+}
+
+// Should evict the least recently used keys to make room for new ones.
+#[test]
+fn lru_eviction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = || {
+        KvStore::builder()
+            .max_live_bytes(4096)
+            .eviction(EvictionPolicy::LruEvict)
+            .open(temp_dir.path())
+    };
+    let mut store = open()?;
+    let value = "x".repeat(100);
+
+    for key_id in 0..30 {
+        store.set(format!("key{}", key_id), value.as_str())?;
+    }
+    // Reading a key keeps it from being evicted next, whichever point read is used.
+    assert!(store.get("key0")?.is_some());
+    assert!(store.get_into("key1", &mut String::new())?);
+    assert!(store.get_with_location("key2")?.is_some());
+    for key_id in 30..60 {
+        store.set(format!("key{}", key_id), value.as_str())?;
+    }
+
+    assert!(store.compaction_estimate().live_bytes <= 4096);
+    assert!(store.get("key0")?.is_some());
+    assert!(store.get("key1")?.is_some());
+    assert!(store.get("key2")?.is_some());
+    for key_id in 3..20 {
+        assert_eq!(store.get(format!("key{}", key_id))?, None);
+    }
+    for key_id in 40..60 {
+        assert!(store.get(format!("key{}", key_id))?.is_some());
+    }
+
+    // A single write larger than the limit is still rejected.
+    assert_eq!(
+        store
+            .set("huge", "x".repeat(8192))
+            .map_err(|err| err.kind()),
+        Err(ErrorKind::QuotaExceeded)
+    );
+    assert!(store.get("key59")?.is_some());
+    drop(store);
+
+    // Reopening orders keys by when they were last written, which reading doesn't change.
+    let mut store = open()?;
+    store.set("key60", value.as_str())?;
+    assert_eq!(store.get("key0")?, None);
+    assert!(store.get("key59")?.is_some());
+    assert!(store.get("key60")?.is_some());
+
+    Ok(())
+}
+
+// Threads sharing a single snapshot should all read the same generations concurrently.
+#[test]
+fn concurrent_snapshot_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    let snapshot = store.read_snapshot()?;
+    assert_eq!(snapshot.len(), 100);
+
+    // Later writes aren't visible through the snapshot.
+    store.set("key0", "changed")?;
+    store.remove("key1")?;
+
+    std::thread::scope(|scope| {
+        let readers: Vec<_> = (0..8)
+            .map(|thread_id| {
+                let snapshot = &snapshot;
+                scope.spawn(move || -> Result<()> {
+                    for iter in 0..500 {
+                        let key_id = (thread_id * 37 + iter * 13) % 100;
+                        assert_eq!(
+                            snapshot.get(&format!("key{}", key_id))?,
+                            Some(format!("value{}", key_id))
+                        );
+                    }
+                    assert_eq!(snapshot.get("missing")?, None);
+                    Ok(())
+                })
+            })
+            .collect();
+
+        readers
+            .into_iter()
+            .try_for_each(|reader| reader.join().expect("reader thread panicked"))
+    })?;
+
+    assert_eq!(store.get("key0")?, Some("changed".to_owned()));
+    assert_eq!(store.read_snapshot()?.get("key1")?, None);
+
+    Ok(())
+}
+
+// The log should be flushed on its own once the configured number of writes is buffered.
+#[test]
+fn max_unflushed_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder()
+        .max_unflushed_writes(3)
+        .open(temp_dir.path())?;
+    store.set("key0", "value0")?;
+    store.flush()?;
+
+    let flushed_keys = || -> Result<Vec<String>> {
+        let mut reopened = KvStore::open_existing(temp_dir.path())?;
+        let mut keys = Vec::new();
+        for key in ["key0", "key1", "key2", "key3"] {
+            if reopened.get(key)?.is_some() {
+                keys.push(key.to_owned());
+            }
+        }
+        Ok(keys)
+    };
+
+    store.set("key1", "value1")?;
+    store.set("key2", "value2")?;
+    assert_eq!(flushed_keys()?, ["key0"]);
+
+    store.remove("key0")?;
+    assert_eq!(flushed_keys()?, ["key1", "key2"]);
+
+    store.set("key3", "value3")?;
+    assert_eq!(flushed_keys()?, ["key1", "key2"]);
+
+    Ok(())
+}
+
+// Replaying a record that can't be deserialized should report where the record is.
+#[test]
+fn replay_failed() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "value1")?;
+    store.set("key2", "value2")?;
+    store.set("key3", "value3")?;
+    store.sync()?;
+    let offset = store.dump_generation(1)?[1].offset;
+    drop(store);
+
+    // Garbles the payload of the second synced record, right past its header.
+    let log_path = temp_dir.path().join("1.log");
+    let mut contents = std::fs::read(&log_path)?;
+    contents[offset + 12] = b'x';
+    std::fs::write(&log_path, contents)?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::ReplayFailed {
+            gen,
+            offset: failed_at,
+            ..
+        }) => {
+            assert_eq!(gen, 1);
+            assert_eq!(failed_at, offset);
+        }
+        other => panic!("expected a replay failure, got {:?}", other.map(|_| ())),
+    }
+
+    Ok(())
+}
+
+// Looking up a borrowed key shouldn't allocate anything but the value returned.
+#[test]
+fn get_borrowed_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "value1")?;
+
+    // Flushes the write and sizes the read buffer.
+    let key = String::from("key1");
+    assert_eq!(store.get(&key)?, Some("value1".to_owned()));
+
+    let (value, allocations) = count_allocations(|| store.get("key1"));
+    assert_eq!(value?, Some("value1".to_owned()));
+    assert_eq!(allocations, 1);
+
+    let (value, allocations) = count_allocations(|| store.get("missing"));
+    assert_eq!(value?, None);
+    assert_eq!(allocations, 0);
+
+    let mut buf = String::with_capacity(16);
+    let (found, allocations) = count_allocations(|| store.get_into("key1", &mut buf));
+    assert!(found?);
+    assert_eq!(buf, "value1");
+    assert_eq!(allocations, 0);
+
+    Ok(())
+}
+
+// Stale records should be exactly the overwritten and removing records.
+#[test]
+fn stale_records() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(store.stale_records()?.is_empty());
+
+    store.set("key1", "value1")?;
+    store.set("key2", "value1")?;
+    store.set("key1", "value2")?;
+    store.set("key3", "value1")?;
+    store.remove("key3")?;
+    store.set("key1", "value3")?;
+
+    let offsets: Vec<usize> = store
+        .dump_generation(1)?
+        .iter()
+        .map(|record| record.offset)
+        .collect();
+    assert_eq!(
+        store.stale_records()?,
+        [
+            (1, offsets[0], "key1".to_owned()),
+            (1, offsets[2], "key1".to_owned()),
+            (1, offsets[3], "key3".to_owned()),
+            (1, offsets[4], "key3".to_owned()),
+        ]
+    );
+
+    store.clean_stale_data()?;
+    assert!(store.stale_records()?.is_empty());
+
+    Ok(())
+}
+
+// Two logfiles parsing to the same generation should fail to open rather than one of
+// them being read at random.
+#[test]
+fn ambiguous_generation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "value1")?;
+    drop(store);
+
+    std::fs::copy(
+        temp_dir.path().join("1.log"),
+        temp_dir.path().join("01.log"),
+    )?;
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::AmbiguousGeneration(1))
+    ));
+
+    std::fs::remove_file(temp_dir.path().join("01.log"))?;
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Older versions within the history window should survive compaction and reopening.
+#[test]
+fn version_history() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = |key_order| {
+        KvStore::builder()
+            .version_history(2)
+            .compact_in_key_order(key_order)
+            .open(temp_dir.path())
+    };
+    let mut store = open(false)?;
+
+    for version in 1..=4 {
+        store.set("key1", format!("value{}", version))?;
+        store.set("key2", format!("value{}", version))?;
+    }
+    store.set("key3", "value1")?;
+
+    let assert_versions = |store: &mut KvStore| -> Result<()> {
+        assert_eq!(store.get_version("key1", 0)?, Some("value4".to_owned()));
+        assert_eq!(store.get_version("key1", 1)?, Some("value3".to_owned()));
+        assert_eq!(store.get_version("key1", 2)?, None);
+        assert_eq!(store.get_version("key2", 1)?, Some("value3".to_owned()));
+        assert_eq!(store.get_version("key3", 0)?, Some("value1".to_owned()));
+        assert_eq!(store.get_version("key3", 1)?, None);
+        Ok(())
+    };
+    assert_versions(&mut store)?;
+
+    // Only the two oldest versions of both keys are stale.
+    let reclaimable = store.compaction_estimate().reclaimable_bytes;
+    assert!(reclaimable > 0);
+    drop(store);
+    let mut store = open(false)?;
+    assert_eq!(store.compaction_estimate().reclaimable_bytes, reclaimable);
+    assert_versions(&mut store)?;
+
+    assert_eq!(store.clean_stale_data()?, reclaimable);
+    assert_eq!(store.compaction_estimate().reclaimable_bytes, 0);
+    assert_versions(&mut store)?;
+    drop(store);
+
+    let mut store = open(true)?;
+    assert_eq!(store.compaction_estimate().reclaimable_bytes, 0);
+    assert_versions(&mut store)?;
+    store.clean_stale_data()?;
+    assert_versions(&mut store)?;
+
+    store.remove("key1")?;
+    assert_eq!(store.get_version("key1", 1)?, None);
+    store.set("key1", "value5")?;
+    assert_eq!(store.get_version("key1", 1)?, None);
+    drop(store);
+
+    let mut store = open(false)?;
+    assert_eq!(store.get_version("key1", 0)?, Some("value5".to_owned()));
+    assert_eq!(store.get_version("key1", 1)?, None);
+    assert_eq!(store.get_version("key2", 1)?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Setting a field should create and update fields of the stored JSON object in place.
+#[test]
+fn set_field() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set_field("user:1", "name", serde_json::json!("Alice"))?;
+    assert_eq!(store.get("user:1")?, Some(r#"{"name":"Alice"}"#.to_owned()));
+
+    store.set_field("user:1", "address.city", serde_json::json!("Paris"))?;
+    store.set_field("user:1", "address.zip", serde_json::json!(75001))?;
+    store.set_field("user:1", "address.city", serde_json::json!("Lyon"))?;
+    assert_eq!(
+        store.get_json::<_, serde_json::Value>("user:1")?,
+        Some(serde_json::json!({
+            "name": "Alice",
+            "address": { "city": "Lyon", "zip": 75001 },
+        }))
+    );
+
+    // Neither a stored value nor a field along the path may be something else.
+    store.set("counter", "1")?;
+    store.set("text", "not json")?;
+    for (key, field_path) in [
+        ("counter", "name"),
+        ("text", "name"),
+        ("user:1", "name.first"),
+    ] {
+        assert!(matches!(
+            store.set_field(key, field_path, serde_json::json!(true)),
+            Err(KvsError::NotAnObject(failed)) if failed == key
+        ));
+    }
+    assert_eq!(store.get("counter")?, Some("1".to_owned()));
+    assert_eq!(
+        store.get_json::<_, serde_json::Value>("user:1")?.unwrap()["name"],
+        "Alice"
+    );
+
+    Ok(())
+}
+
+// Prewarming should read every live record exactly once and leave the store usable.
+#[test]
+fn prewarm() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for iter in 0..3 {
+        for key_id in 0..50 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    store.remove("key0")?;
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    let bytes_read = store.io_stats().bytes_read;
+    store.prewarm()?;
+    assert_eq!(
+        store.io_stats().bytes_read - bytes_read,
+        store.compaction_estimate().live_bytes as u64
+    );
+
+    assert_eq!(store.get("key0")?, None);
+    assert_eq!(store.get("key49")?, Some("value2".to_owned()));
+    store.set("key50", "value0")?;
+    assert_eq!(store.get("key50")?, Some("value0".to_owned()));
+
+    Ok(())
+}
+
+// An ephemeral store should work like any other and leave nothing behind once dropped.
+#[test]
+fn ephemeral() -> Result<()> {
+    let mut store = KvStore::ephemeral()?;
+    let path = store.path().to_path_buf();
+    assert!(path.is_dir());
+
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "value")?;
+    }
+    store.remove("key0")?;
+    store.clean_stale_data()?;
+    assert_eq!(store.get("key0")?, None);
+    assert_eq!(store.get("key99")?, Some("value".to_owned()));
+
+    drop(store);
+    assert!(!path.exists());
+
+    let store = KvStore::builder().version_history(2).ephemeral()?;
+    let path = store.path().to_path_buf();
+    drop(store);
+    assert!(!path.exists());
+
+    Ok(())
+}